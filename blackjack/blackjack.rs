@@ -33,24 +33,25 @@ fn it_works() {
 */
 #![allow(unused)]
 
+mod counting;
+mod rules;
+
+use counting::{CountingSystem, Counter, HiLo};
+use rules::Rules;
+use std::sync::Arc;
 use std::vec::Vec;
 use chrono::Utc;
 use rand::{Rng, RngExt};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 enum Suit {
+    #[default]
     Heart = 1,
     Spade = 2,
     Club = 3,
     Diamond = 4,
 }
 
-impl Default for Suit {
-    fn default() -> Self {
-        Suit::Heart
-    }
-}
-
 trait Card {
     fn get_suit(&self) -> &Suit;
 
@@ -114,6 +115,10 @@ impl Deck {
 struct Shoe {
     cards: Vec<Vec<BlackjackCard>>,
     number_of_decks: usize,
+    // Cards dealt since the shuffle; once `cut_card` of them are out the shoe is due a shuffle
+    dealt: usize,
+    cut_card: usize,
+    counter: Counter,
 }
 
 impl Shoe {
@@ -127,9 +132,46 @@ impl Shoe {
         Shoe {
             cards,
             number_of_decks,
+            dealt: 0,
+            cut_card: number_of_decks * 52,
+            counter: Counter::new(Arc::new(HiLo), number_of_decks),
         }
     }
 
+    // Places the cut card after the given share of the shoe
+    fn set_cut_card(&mut self, penetration: f32) {
+        self.cut_card = (penetration * (self.number_of_decks * 52) as f32) as usize;
+    }
+
+    // The count starts over with the new system
+    fn set_counting_system(&mut self, system: Arc<dyn CountingSystem>) {
+        self.counter = Counter::new(system, self.number_of_decks);
+    }
+
+    fn cut_card_reached(&self) -> bool {
+        self.dealt >= self.cut_card
+    }
+
+    // Gathers all cards, shuffles them and starts a new count
+    fn reshuffle(&mut self) {
+        self.create_shoe();
+        self.shuffle();
+        self.dealt = 0;
+        self.counter.reset();
+    }
+
+    fn remaining(&self) -> usize {
+        self.cards.iter().map(|deck| deck.len()).sum()
+    }
+
+    fn running_count(&self) -> i32 {
+        self.counter.running_count()
+    }
+
+    fn true_count(&self) -> f32 {
+        self.counter.true_count(self.remaining())
+    }
+
     fn create_shoe(&mut self) {
         self.cards.clear();
         for _ in 0..self.number_of_decks {
@@ -145,7 +187,8 @@ impl Shoe {
             let j = rng.random_range(i..card_count);
             let (deck_i, card_i) = self.get_deck_and_card_index(i);
             let (deck_j, card_j) = self.get_deck_and_card_index(j);
-            self.cards[deck_i][card_i] = std::mem::replace(&mut self.cards[deck_j][card_j], Default::default());
+            let card = std::mem::take(&mut self.cards[deck_i][card_i]);
+            self.cards[deck_i][card_i] = std::mem::replace(&mut self.cards[deck_j][card_j], card);
         }
     }
 
@@ -159,13 +202,17 @@ impl Shoe {
         (deck_index, card_index)
     }
 
+    // Every card dealt is counted. An empty shoe is reshuffled mid-round, which the cut card
+    // normally prevents.
     fn deal_card(&mut self) -> BlackjackCard {
-        if self.cards.is_empty() || self.cards.iter().all(|deck| deck.is_empty()) {
-            self.create_shoe();
-            self.shuffle();
+        if self.remaining() == 0 {
+            self.reshuffle();
         }
         let (deck_index, card_index) = self.get_deck_and_card_index(0);
-        self.cards[deck_index].remove(card_index)
+        let card = self.cards[deck_index].remove(card_index);
+        self.dealt += 1;
+        self.counter.see(card.game_value);
+        card
     }
 }
 
@@ -173,10 +220,16 @@ struct Person;
 
 trait BasePlayer {
     fn get_hands(&self) -> &Vec<Hand>;
+    fn get_hands_mut(&mut self) -> &mut Vec<Hand>;
     fn add_hand(&mut self, hand: Hand);
     fn remove_hand(&mut self, hand: &Hand);
+    fn get_balance(&self) -> f32;
+    // Adds the amount to the balance, or takes it away when negative
+    fn update_balance(&mut self, amount: f32);
+
+    // Best score of the first hand, 0 when busted
     fn get_total_score(&self) -> i32 {
-        0
+        self.get_hands().first().map_or(0, |hand| hand.resolve_score())
     }
     
     fn reset_password(&self) -> bool {
@@ -184,11 +237,14 @@ trait BasePlayer {
         true
     }
 
-    fn place_bet(&mut self, bet: f32) {
-        
+    // Takes the stake off the balance
+    fn place_bet(&mut self, bet: f32) -> Result<(), String> {
+        if bet > self.get_balance() {
+            return Err(format!("Bet {} is more than the balance {}", bet, self.get_balance()));
+        }
+        self.update_balance(-bet);
+        Ok(())
     }
-
-
 }
 
 struct Player {
@@ -198,7 +254,6 @@ struct Player {
     status: String,
     person: Person,
     hands: Vec<Hand>,
-    bet: f32,
     total_cash: f32,
 }
 
@@ -211,7 +266,6 @@ impl Player {
             status,
             person,
             hands: Vec::new(),
-            bet: 0.0,
             total_cash: 0.0
         }
     }
@@ -222,6 +276,10 @@ impl BasePlayer for Player {
         &self.hands
     }
 
+    fn get_hands_mut(&mut self) -> &mut Vec<Hand> {
+        &mut self.hands
+    }
+
     fn add_hand(&mut self, hand: Hand) {
         self.hands.push(hand);
     }
@@ -231,11 +289,20 @@ impl BasePlayer for Player {
             self.hands.remove(index);
         }
     }
+
+    fn get_balance(&self) -> f32 {
+        self.balance
+    }
+
+    fn update_balance(&mut self, amount: f32) {
+        self.balance += amount;
+    }
 }
 
 struct Dealer {
     id: String,
     password: String,
+    // The house bank: collects lost bets and pays the winnings
     balance: f32,
     status: String,
     person: Person,
@@ -261,6 +328,10 @@ impl BasePlayer for Dealer {
         &self.hands
     }
 
+    fn get_hands_mut(&mut self) -> &mut Vec<Hand> {
+        &mut self.hands
+    }
+
     fn add_hand(&mut self, hand: Hand) {
         self.hands.push(hand);
     }
@@ -270,11 +341,25 @@ impl BasePlayer for Dealer {
             self.hands.remove(index);
         }
     }
+
+    fn get_balance(&self) -> f32 {
+        self.balance
+    }
+
+    fn update_balance(&mut self, amount: f32) {
+        self.balance += amount;
+    }
 }
 
 #[derive(Debug, Clone)]
 struct Hand {
     cards: Vec<BlackjackCard>,
+    // Stake riding on this hand
+    bet: f32,
+    // Came from a split, so 21 with two cards is not a natural
+    split: bool,
+    doubled: bool,
+    surrendered: bool,
 }
 
 impl PartialEq for Hand {
@@ -289,6 +374,10 @@ impl Hand {
     fn new(blackjack_card1: BlackjackCard, blackjack_card2: BlackjackCard) -> Self {
         Hand {
             cards: vec![blackjack_card1, blackjack_card2],
+            bet: 0.0,
+            split: false,
+            doubled: false,
+            surrendered: false,
         }
     }
 
@@ -298,8 +387,8 @@ impl Hand {
         for card in &self.cards {
             let mut new_totals = Vec::new();
             for score in &totals {
-                new_totals.push(card.get_face_value() + score);
-                if card.get_face_value() == 1 {
+                new_totals.push(card.game_value + score);
+                if card.game_value == 1 {
                     new_totals.push(11 + score);
                 }
             }
@@ -327,6 +416,36 @@ impl Hand {
         }
         best_score
     }
+
+    fn is_busted(&self) -> bool {
+        self.resolve_score() == 0
+    }
+
+    // 21 with the first two cards of an unsplit hand
+    fn is_natural(&self) -> bool {
+        !self.split && self.cards.len() == 2 && self.resolve_score() == 21
+    }
+
+    // An ace counts as 11 in the best score
+    fn is_soft(&self) -> bool {
+        let hard: i32 = self.cards.iter().map(|card| card.game_value).sum();
+        self.cards.iter().any(|card| card.game_value == 1) && hard + 10 <= 21
+    }
+
+    // Two cards of the same rank
+    fn is_pair(&self) -> bool {
+        self.cards.len() == 2 && self.cards[0].face_value == self.cards[1].face_value
+    }
+
+    // Split aces get one card each
+    fn is_split_aces(&self) -> bool {
+        self.split && self.cards[0].game_value == 1
+    }
+
+    // No more cards can be drawn to the hand
+    fn is_finished(&self) -> bool {
+        self.doubled || self.surrendered || self.is_split_aces() || self.resolve_score() == 21 || self.is_busted()
+    }
 }
 
 
@@ -337,87 +456,510 @@ fn get_bet_from_ui() -> f32 {
 
 fn get_user_action(hand: &Hand) -> String {
     // Implementation for getting user action
-    String::new()
+    String::from("stand")
 }
 
 struct Game {
     player: Player,
     dealer: Dealer,
-    max_num_of_decks: usize,
+    rules: Rules,
     shoe: Shoe,
+    // Table limits for a single bet
+    min_bet: f32,
+    max_bet: f32,
+    // Side bet against a dealer natural this round
+    insurance: f32,
+    // The dealer has checked the hole card for a natural this round
+    peeked: bool,
+    // The player's net result once the round is settled
+    result: Option<f32>,
 }
 
 impl Game {
-    fn new(player: Player, dealer: Dealer) -> Self {
+    fn new(player: Player, dealer: Dealer, rules: Rules, min_bet: f32, max_bet: f32) -> Self {
+        let mut shoe = Shoe::new(rules.decks);
+        shoe.set_cut_card(rules.penetration);
+        shoe.shuffle();
         Game {
             player,
             dealer,
-            max_num_of_decks: 3,
-            shoe: Shoe::new(3),
+            rules,
+            shoe,
+            min_bet,
+            max_bet,
+            insurance: 0.0,
+            peeked: false,
+            result: None,
         }
     }
 
-    fn play_action(&mut self, action: &str, hand: &mut Hand) {
+    fn play_action(&mut self, action: &str, hand_index: usize) -> Result<(), String> {
         match action {
-            "hit" => self.hit(hand),
-            "split" => self.split(hand),
-            "stand pat" => {} // do nothing
-            "stand" => self.stand(),
-            _ => println!("Invalid move"),
+            "hit" => return self.hit(hand_index),
+            "split" => return self.split(hand_index),
+            "double" => return self.double(hand_index),
+            "surrender" => return self.surrender(hand_index),
+            "insurance" => return self.insure(),
+            "stand pat" | "stand" => {} // the hand is done
+            _ => return Err(format!("Invalid move {}", action)),
         }
+        Ok(())
     }
 
-    fn hit(&mut self, hand: &mut Hand) {
-        hand.add_card(self.shoe.deal_card());
+    fn hit(&mut self, hand_index: usize) -> Result<(), String> {
+        self.check_open()?;
+        if self.hand(hand_index)?.is_finished() {
+            return Err("No more cards can be drawn to this hand".to_string());
+        }
+        let card = self.shoe.deal_card();
+        self.player.get_hands_mut()[hand_index].add_card(card);
+        Ok(())
     }
 
-    fn stand(&mut self) {
-        let dealer_score = self.dealer.get_total_score();
-        let player_score = self.player.get_total_score();
-        let hands = self.player.get_hands();
-        for hand in hands {
-            let best_score = hand.resolve_score();
-            if player_score == 21 {
-                // blackjack, pay 3:2 of the bet
-            } else if player_score > dealer_score {
-                // pay player equal to the bet
-            } else if player_score < dealer_score {
-                // collect the bet from the player
+    fn hand(&self, hand_index: usize) -> Result<&Hand, String> {
+        self.player.get_hands().get(hand_index).ok_or_else(|| format!("There is no hand {}", hand_index))
+    }
+
+    // The dealer checks the hole card once insurance has been decided, i.e. on the player's
+    // first other action. A natural settles the round straight away.
+    fn peek(&mut self) {
+        if self.peeked {
+            return;
+        }
+        self.peeked = true;
+        if self.dealer.get_hands()[0].is_natural() {
+            self.settle();
+        }
+    }
+
+    // Peeks first, so no more is staked or drawn against a dealer natural
+    fn check_open(&mut self) -> Result<(), String> {
+        self.peek();
+        if self.result.is_some() {
+            return Err("The round is settled".to_string());
+        }
+        Ok(())
+    }
+
+    // Takes the bet off the player's balance and deals the first two cards to both sides.
+    // Bets outside the table limits or above the balance are rejected before any card is dealt.
+    fn deal(&mut self, bet: f32) -> Result<(), String> {
+        if bet < self.min_bet || bet > self.max_bet {
+            return Err(format!("Bet {} is outside the table limits {}-{}", bet, self.min_bet, self.max_bet));
+        }
+        self.player.place_bet(bet)?;
+        self.player.get_hands_mut().clear();
+        self.dealer.get_hands_mut().clear();
+        self.insurance = 0.0;
+        self.peeked = false;
+        self.result = None;
+        if self.shoe.cut_card_reached() {
+            self.shoe.reshuffle();
+        }
+
+        let mut hand = Hand::new(self.shoe.deal_card(), self.shoe.deal_card());
+        hand.bet = bet;
+        self.player.add_hand(hand);
+        self.dealer.add_hand(Hand::new(self.shoe.deal_card(), self.shoe.deal_card()));
+        Ok(())
+    }
+
+    // The dealer draws to 17 unless every player hand is already busted or surrendered
+    fn play_dealer(&mut self) {
+        let settled = |hand: &Hand| hand.is_busted() || hand.surrendered;
+        if self.result.is_some() || self.player.get_hands().iter().all(settled) || self.dealer.get_hands()[0].is_natural() {
+            return;
+        }
+        while !self.dealer.get_hands()[0].is_busted()
+            && self.rules.dealer_hits(self.dealer.get_total_score(), self.dealer.get_hands()[0].is_soft())
+        {
+            let card = self.shoe.deal_card();
+            self.dealer.get_hands_mut()[0].add_card(card);
+        }
+    }
+
+    // Pays out every player hand against the dealer's and returns the player's net result.
+    // A natural pays 3:2, a win 1:1, a push returns the stake, a surrender half of it and
+    // insurance 2:1. A round is paid out only once, later calls return the same result.
+    fn settle(&mut self) -> f32 {
+        if let Some(net) = self.result {
+            return net;
+        }
+        let dealer_hand = self.dealer.get_hands()[0].clone();
+        let dealer_score = dealer_hand.resolve_score();
+        let mut net = 0.0;
+        if self.insurance > 0.0 {
+            let payout = if dealer_hand.is_natural() { self.insurance * 3.0 } else { 0.0 };
+            self.player.update_balance(payout);
+            self.dealer.update_balance(self.insurance - payout);
+            net += payout - self.insurance;
+            self.insurance = 0.0;
+        }
+        for hand in self.player.get_hands().clone() {
+            let player_score = hand.resolve_score();
+            let payout = if hand.surrendered {
+                hand.bet / 2.0
+            } else if hand.is_busted() {
+                0.0
+            } else if hand.is_natural() && !dealer_hand.is_natural() {
+                hand.bet * (1.0 + self.rules.blackjack_payout as f32)
+            } else if dealer_hand.is_natural() && !hand.is_natural() {
+                0.0
+            } else if dealer_hand.is_busted() || player_score > dealer_score {
+                hand.bet * 2.0
+            } else if player_score == dealer_score {
+                hand.bet
             } else {
-                // tie, bet goes back to player
-            }
+                0.0
+            };
+            self.player.update_balance(payout);
+            self.dealer.update_balance(hand.bet - payout);
+            net += payout - hand.bet;
         }
+        self.result = Some(net);
+        net
     }
 
-    fn split(&mut self, hand: &mut Hand) {
+    fn stand(&mut self) -> f32 {
+        self.play_dealer();
+        self.settle()
+    }
+
+    // Splits a pair into two hands with a card each, betting the same again on the second
+    fn split(&mut self, hand_index: usize) -> Result<(), String> {
+        self.check_open()?;
+        let hand = self.hand(hand_index)?.clone();
+        if !hand.is_pair() || hand.is_finished() {
+            return Err("Only a pair can be split".to_string());
+        }
+        if self.player.get_hands().len() >= self.rules.max_hands() {
+            return Err(format!("No more than {} hands can be played", self.rules.max_hands()));
+        }
+        self.player.place_bet(hand.bet)?;
         let cards = hand.get_cards().clone();
-        self.player.add_hand(Hand::new(cards[0].clone(), self.shoe.deal_card()));
-        self.player.add_hand(Hand::new(cards[1].clone(), self.shoe.deal_card()));
-        self.player.remove_hand(hand);
+        let mut first = Hand::new(cards[0].clone(), self.shoe.deal_card());
+        let mut second = Hand::new(cards[1].clone(), self.shoe.deal_card());
+        for new_hand in [&mut first, &mut second] {
+            new_hand.bet = hand.bet;
+            new_hand.split = true;
+        }
+        let hands = self.player.get_hands_mut();
+        hands[hand_index] = first;
+        hands.insert(hand_index + 1, second);
+        Ok(())
+    }
+
+    // Doubles the bet on the first two cards for exactly one more card
+    fn double(&mut self, hand_index: usize) -> Result<(), String> {
+        self.check_open()?;
+        let hand = self.hand(hand_index)?;
+        if hand.get_cards().len() != 2 || hand.is_finished() {
+            return Err("Only the first two cards can be doubled on".to_string());
+        }
+        if hand.split && !self.rules.double_after_split {
+            return Err("No doubling after a split".to_string());
+        }
+        let bet = hand.bet;
+        self.player.place_bet(bet)?;
+        let card = self.shoe.deal_card();
+        let hand = &mut self.player.get_hands_mut()[hand_index];
+        hand.bet += bet;
+        hand.doubled = true;
+        hand.add_card(card);
+        Ok(())
+    }
+
+    // Late surrender: gives up the initial hand for half the bet, once the dealer is known
+    // not to hold a natural
+    fn surrender(&mut self, hand_index: usize) -> Result<(), String> {
+        if !self.rules.surrender {
+            return Err("Surrender is not allowed".to_string());
+        }
+        self.check_open()?;
+        let hand = self.hand(hand_index)?;
+        if self.player.get_hands().len() != 1 || hand.get_cards().len() != 2 || hand.split {
+            return Err("Only the initial two-card hand can be surrendered".to_string());
+        }
+        self.player.get_hands_mut()[hand_index].surrendered = true;
+        Ok(())
     }
 
-    fn start(&mut self) {
-        self.player.place_bet(get_bet_from_ui());
-        self.player.add_hand(Hand::new(self.shoe.deal_card(), self.shoe.deal_card()));
+    // Insurance for half the bet when the dealer's up card is an ace, before any other action
+    fn insure(&mut self) -> Result<(), String> {
+        let hands = self.player.get_hands();
+        if self.dealer.get_hands()[0].get_cards()[0].game_value != 1 {
+            return Err("Insurance is only offered against an ace".to_string());
+        }
+        if self.peeked || self.insurance > 0.0 || hands.len() != 1 || hands[0].get_cards().len() != 2 {
+            return Err("Insurance is only offered before the first action".to_string());
+        }
+        let insurance = hands[0].bet / 2.0;
+        self.player.place_bet(insurance)?;
+        self.insurance = insurance;
+        Ok(())
+    }
 
-        self.dealer.add_hand(Hand::new(self.shoe.deal_card(), self.shoe.deal_card()));
+    fn start(&mut self) {
+        if let Err(err) = self.deal(get_bet_from_ui()) {
+            println!("{}", err);
+            return;
+        }
 
-        loop {
-            let hands = self.player.get_hands().clone();
-            for mut hand in hands {
-                let action = get_user_action(&hand);
-                self.play_action(&action, &mut hand);
-                if action == "stand" {
-                    break;
-                }
+        let mut hand_index = 0;
+        while self.result.is_none() && hand_index < self.player.get_hands().len() {
+            let hand = &self.player.get_hands()[hand_index];
+            if hand.is_finished() {
+                hand_index += 1;
+                continue;
+            }
+            let action = get_user_action(hand);
+            if let Err(err) = self.play_action(&action, hand_index) {
+                println!("{}", err);
+            } else if action == "stand" || action == "stand pat" {
+                hand_index += 1;
             }
         }
+        let net = self.stand();
+        println!("Round result: {}, balance {}", net, self.player.get_balance());
     }
 }
 
 fn main() {
-    let player = Player::new("".to_string(), "".to_string(), 0.0, "".to_string(), Person);
-    let dealer = Dealer::new("".to_string(), "".to_string(), 0.0, "".to_string(), Person);
-    let mut game = Game::new(player, dealer);
+    let player = Player::new("".to_string(), "".to_string(), 100.0, "".to_string(), Person);
+    let dealer = Dealer::new("".to_string(), "".to_string(), 10_000.0, "".to_string(), Person);
+    let mut game = Game::new(player, dealer, Rules::default(), 5.0, 500.0);
     game.start();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(face_value: i32) -> BlackjackCard {
+        BlackjackCard::new(Suit::Spade, face_value)
+    }
+
+    // A game whose shoe deals the given face values in order: two player cards, two dealer
+    // cards, then the draws
+    fn game_with(face_values: &[i32]) -> Game {
+        let player = Player::new("p".to_string(), "".to_string(), 100.0, "".to_string(), Person);
+        let dealer = Dealer::new("d".to_string(), "".to_string(), 1000.0, "".to_string(), Person);
+        let mut game = Game::new(player, dealer, Rules::default(), 5.0, 50.0);
+        // without a cut card in a one-deck shoe the stacked cards are never reshuffled
+        game.shoe = Shoe::new(1);
+        game.shoe.cards = vec![face_values.iter().map(|value| card(*value)).collect()];
+        game
+    }
+
+    #[test]
+    fn test_bets_are_checked_and_debited_at_deal() {
+        let mut game = game_with(&[10, 9, 10, 7]);
+        assert!(game.deal(1.0).is_err());
+        assert!(game.deal(60.0).is_err());
+        game.player.balance = 20.0;
+        assert!(game.deal(30.0).is_err());
+        assert!(game.player.get_hands().is_empty());
+        assert!(game.deal(20.0).is_ok());
+        assert_eq!(game.player.get_balance(), 0.0);
+        assert_eq!(game.player.get_hands()[0].bet, 20.0);
+    }
+
+    #[test]
+    fn test_settlement_pays_natural_win_and_push() {
+        // natural against 17 pays 3:2
+        let mut game = game_with(&[1, 13, 10, 7]);
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 15.0);
+        assert_eq!((game.player.get_balance(), game.dealer.get_balance()), (115.0, 985.0));
+
+        // 19 against 17 pays 1:1
+        let mut game = game_with(&[10, 9, 10, 7]);
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 10.0);
+        assert_eq!(game.player.get_balance(), 110.0);
+
+        // 18 against a dealer drawing 12 + 6 pushes
+        let mut game = game_with(&[10, 8, 10, 2, 6]);
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 0.0);
+        assert_eq!((game.player.get_balance(), game.dealer.get_balance()), (100.0, 1000.0));
+
+        // busting loses even when the dealer busts too
+        let mut game = game_with(&[10, 6, 10, 6, 8, 10]);
+        game.deal(10.0).unwrap();
+        game.hit(0).unwrap();
+        assert_eq!(game.stand(), -10.0);
+        assert_eq!((game.player.get_balance(), game.dealer.get_balance()), (90.0, 1010.0));
+    }
+
+    #[test]
+    fn test_split_pairs_settle_independently() {
+        // 8 8 against 10 7: the hands become 8 3 and 8 10, then the first doubles to 8 3 10
+        let mut game = game_with(&[8, 8, 10, 7, 3, 10, 10]);
+        game.deal(10.0).unwrap();
+        game.split(0).unwrap();
+        assert_eq!(game.player.get_hands().len(), 2);
+        assert_eq!(game.player.get_balance(), 80.0);
+        game.double(0).unwrap();
+        assert_eq!(game.player.get_hands()[0].resolve_score(), 21);
+        assert!(game.double(0).is_err());
+        // 21 after doubling wins 20, 18 wins 10
+        assert_eq!(game.stand(), 30.0);
+        assert_eq!(game.player.get_balance(), 130.0);
+
+        let mut game = game_with(&[8, 9, 10, 7]);
+        game.deal(10.0).unwrap();
+        assert!(game.split(0).is_err());
+
+        // aces split into a ten are 21, not a natural
+        let mut game = game_with(&[1, 1, 10, 8, 10, 1, 10, 10]);
+        game.deal(10.0).unwrap();
+        game.rules.max_splits = 2;
+        game.split(0).unwrap();
+        assert!(!game.player.get_hands()[0].is_natural());
+        // split aces get one card each: no hitting and no splitting again
+        assert!(game.split(1).is_err());
+        assert!(game.hit(1).is_err());
+        assert_eq!(game.player.get_hands().len(), 2);
+        // 21 and 12 against 18
+        assert_eq!(game.stand(), 0.0);
+    }
+
+    #[test]
+    fn test_surrender_and_insurance() {
+        let mut game = game_with(&[10, 6, 10, 10]);
+        game.deal(10.0).unwrap();
+        game.surrender(0).unwrap();
+        assert_eq!(game.stand(), -5.0);
+        assert_eq!((game.player.get_balance(), game.dealer.get_balance()), (95.0, 1005.0));
+
+        // insurance pays 2:1 against a natural, which settles the round before surrender
+        let mut game = game_with(&[10, 6, 1, 10]);
+        game.deal(10.0).unwrap();
+        game.insure().unwrap();
+        assert!(game.insure().is_err());
+        assert!(game.surrender(0).is_err());
+        assert_eq!(game.stand(), 0.0);
+        assert_eq!(game.player.get_balance(), 100.0);
+
+        // insurance is lost when the dealer has no natural
+        let mut game = game_with(&[10, 9, 1, 6, 10]);
+        game.deal(10.0).unwrap();
+        game.insure().unwrap();
+        // dealer draws 17
+        assert_eq!(game.stand(), 5.0);
+
+        let mut game = game_with(&[10, 9, 10, 1]);
+        game.deal(10.0).unwrap();
+        assert!(game.insure().is_err());
+    }
+
+    #[test]
+    fn test_finished_hands_take_no_cards() {
+        // doubled, busted and 21
+        let mut game = game_with(&[6, 5, 10, 7, 9, 10, 10]);
+        game.deal(10.0).unwrap();
+        game.double(0).unwrap();
+        assert!(game.hit(0).is_err());
+
+        let mut game = game_with(&[10, 6, 10, 7, 9]);
+        game.deal(10.0).unwrap();
+        game.hit(0).unwrap();
+        assert!(game.hit(0).is_err());
+
+        let mut game = game_with(&[10, 6, 10, 7, 5]);
+        game.deal(10.0).unwrap();
+        game.hit(0).unwrap();
+        assert_eq!(game.player.get_hands()[0].resolve_score(), 21);
+        assert!(game.hit(0).is_err());
+
+        let mut game = game_with(&[10, 6, 10, 10]);
+        game.deal(10.0).unwrap();
+        game.surrender(0).unwrap();
+        assert!(game.hit(0).is_err());
+        // bad hand numbers are errors, not panics
+        assert!(game.surrender(1).is_err());
+        assert!(game.hit(1).is_err());
+        assert!(game.split(1).is_err());
+        assert!(game.double(1).is_err());
+    }
+
+    #[test]
+    fn test_dealer_natural_ends_the_round_before_any_action() {
+        // 11 against a dealer blackjack cannot be doubled or hit, only the bet is lost
+        let mut game = game_with(&[6, 5, 1, 10, 10]);
+        game.deal(10.0).unwrap();
+        assert!(game.double(0).is_err());
+        assert_eq!(game.player.get_balance(), 90.0);
+        assert_eq!(game.result, Some(-10.0));
+        assert!(game.hit(0).is_err());
+        assert!(game.insure().is_err());
+        assert_eq!(game.stand(), -10.0);
+        assert_eq!((game.player.get_balance(), game.dealer.get_balance()), (90.0, 1010.0));
+
+        let mut game = game_with(&[8, 8, 10, 1]);
+        game.deal(10.0).unwrap();
+        assert!(game.split(0).is_err());
+        assert_eq!(game.player.get_hands().len(), 1);
+        assert_eq!(game.stand(), -10.0);
+    }
+
+    #[test]
+    fn test_table_rules() {
+        // H17 draws to soft 17, S17 stands
+        let mut game = game_with(&[10, 8, 1, 6, 1]);
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 10.0);
+        let mut game = game_with(&[10, 8, 1, 6, 1]);
+        game.rules.dealer_hits_soft_17 = true;
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 0.0);
+
+        // 6:5 natural
+        let mut game = game_with(&[1, 10, 10, 7]);
+        game.rules.blackjack_payout = Rules::SIX_TO_FIVE;
+        game.deal(10.0).unwrap();
+        assert_eq!(game.stand(), 12.0);
+
+        let mut game = game_with(&[8, 8, 10, 7, 3, 2]);
+        game.rules.double_after_split = false;
+        game.rules.surrender = false;
+        game.deal(10.0).unwrap();
+        assert!(game.surrender(0).is_err());
+        game.split(0).unwrap();
+        assert!(game.double(0).is_err());
+    }
+
+    #[test]
+    fn test_shoe_cut_card_and_count() {
+        let mut shoe = Shoe::new(2);
+        shoe.set_cut_card(0.5);
+        // a fresh deck comes ace first: 2 to 6 count +1, 7 to 9 nothing, tens and aces -1
+        for (cards, expected) in [(4, -4), (20, 16), (12, 16), (15, 1)] {
+            for _ in 0..cards {
+                shoe.deal_card();
+            }
+            assert_eq!(shoe.running_count(), expected);
+        }
+        assert!(!shoe.cut_card_reached());
+        shoe.deal_card();
+        assert!(shoe.cut_card_reached());
+        // half the shoe is left, a balanced count over a whole deck
+        assert_eq!(shoe.running_count(), 0);
+        assert_eq!(shoe.remaining(), 52);
+
+        // KO starts two decks at -4
+        shoe.set_counting_system(Arc::new(counting::KnockOut));
+        assert_eq!(shoe.running_count(), -4);
+        shoe.deal_card();
+        assert_eq!((shoe.running_count(), shoe.true_count()), (-5, -5.0 * 52.0 / 51.0));
+        shoe.reshuffle();
+        assert_eq!((shoe.running_count(), shoe.remaining(), shoe.cut_card_reached()), (-4, 104, false));
+
+        // the game reshuffles at the cut card before dealing
+        let mut game = game_with(&[10, 9, 10, 7]);
+        game.shoe.set_cut_card(0.0);
+        game.deal(10.0).unwrap();
+        assert_eq!(game.shoe.remaining(), 48);
+    }
+}
@@ -0,0 +1,133 @@
+// Card counting shared by the blackjack binaries. A counting system tags every card value, the
+// running count adds up the tags of the cards dealt since the shuffle and the true count divides
+// it by the decks left in the shoe. Unbalanced systems start the running count off zero and bet
+// by it directly instead.
+
+use std::sync::Arc;
+
+// Cards are passed by blackjack value, 1 for an ace up to 10 for tens and faces
+pub trait CountingSystem: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn tag(&self, value: i32) -> i32;
+
+    // Whether the tags of a full deck add up to zero
+    fn balanced(&self) -> bool {
+        true
+    }
+
+    // Running count right after the shuffle
+    fn initial_running_count(&self, decks: usize) -> i32 {
+        0
+    }
+}
+
+pub struct HiLo;
+
+impl CountingSystem for HiLo {
+    fn name(&self) -> &str {
+        "Hi-Lo"
+    }
+
+    fn tag(&self, value: i32) -> i32 {
+        match value {
+            2..=6 => 1,
+            7..=9 => 0,
+            _ => -1,
+        }
+    }
+}
+
+// Leaves the ace and the two uncounted
+pub struct HiOptI;
+
+impl CountingSystem for HiOptI {
+    fn name(&self) -> &str {
+        "Hi-Opt I"
+    }
+
+    fn tag(&self, value: i32) -> i32 {
+        match value {
+            3..=6 => 1,
+            10 => -1,
+            _ => 0,
+        }
+    }
+}
+
+// Unbalanced: a full deck counts +4, so the running count starts at 4 - 4 per deck and is used
+// without conversion
+pub struct KnockOut;
+
+impl CountingSystem for KnockOut {
+    fn name(&self) -> &str {
+        "KO"
+    }
+
+    fn balanced(&self) -> bool {
+        false
+    }
+
+    fn initial_running_count(&self, decks: usize) -> i32 {
+        4 - 4 * decks as i32
+    }
+
+    fn tag(&self, value: i32) -> i32 {
+        match value {
+            2..=7 => 1,
+            8 | 9 => 0,
+            _ => -1,
+        }
+    }
+}
+
+// System named on the command line
+pub fn system_by_name(name: &str) -> Option<Arc<dyn CountingSystem>> {
+    match name.to_ascii_lowercase().as_str() {
+        "hi-lo" | "hilo" => Some(Arc::new(HiLo)),
+        "hi-opt-i" | "hiopt1" => Some(Arc::new(HiOptI)),
+        "ko" => Some(Arc::new(KnockOut)),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct Counter {
+    system: Arc<dyn CountingSystem>,
+    decks: usize,
+    running: i32,
+}
+
+impl Counter {
+    pub fn new(system: Arc<dyn CountingSystem>, decks: usize) -> Self {
+        let running = system.initial_running_count(decks);
+        Counter { system, decks, running }
+    }
+
+    pub fn system(&self) -> &dyn CountingSystem {
+        self.system.as_ref()
+    }
+
+    pub fn see(&mut self, value: i32) {
+        self.running += self.system.tag(value);
+    }
+
+    // After a shuffle
+    pub fn reset(&mut self) {
+        self.running = self.system.initial_running_count(self.decks);
+    }
+
+    pub fn running_count(&self) -> i32 {
+        self.running
+    }
+
+    // Running count per deck still in the shoe
+    pub fn true_count(&self, cards_remaining: usize) -> f32 {
+        self.running as f32 * 52.0 / cards_remaining.max(1) as f32
+    }
+
+    // What bets are sized by: the true count, or the running count of an unbalanced system
+    pub fn betting_count(&self, cards_remaining: usize) -> f32 {
+        if self.system.balanced() { self.true_count(cards_remaining) } else { self.running as f32 }
+    }
+}
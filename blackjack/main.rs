@@ -23,6 +23,13 @@
 // https://www.educative.io/courses/grokking-the-low-level-design-interview-using-ood-principles/B8E5kYVjonW
 //
 #![allow(unused)]
+mod counting;
+mod rules;
+mod simulator;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use rules::Rules;
+use simulator::{BasicStrategy, CountingBettor, simulate};
 use std::io;
 use std::{thread, time};
 use rand::seq::SliceRandom;
@@ -92,9 +99,24 @@ impl Deck {
                 cards.push(Card::new(suit.clone(), rank.clone()))
             }
         }
-        cards.push(Card::new(Suit::Clovers, Rank::Ace));
         Self { cards }
     }
+
+    // A shoe of several 52-card decks
+    pub fn with_decks(decks: usize) -> Self {
+        let mut cards = Vec::new();
+        for _ in 0..decks {
+            for suit in Suit::iter() {
+                for rank in Rank::iter() {
+                    cards.push(Card::new(suit.clone(), rank))
+                }
+            }
+        }
+        Self { cards }
+    }
+    pub fn remaining(&self) -> usize {
+        self.cards.len()
+    }
     pub fn get_card(&mut self) -> Card {
         let card = self.cards.pop();
         match card {
@@ -181,6 +203,8 @@ pub enum Reply {
     Hit,
     Stand,
     Split,
+    Double,
+    Surrender,
     AskUI,
 }
 
@@ -252,25 +276,27 @@ pub fn deal_players(deck: &mut Deck, dealer: &mut dyn Person, player: &mut dyn P
     player.deal_card(deck.get_card());
 }
 
-pub fn get_score(player: &dyn Person) -> Score {
-    let hand = &player.get_hand();
-    let mut result: u8 = 0;
-    for card in hand.iter() {
-        match card.rank {
-            Rank::Knight => result += 10,
-            Rank::Queen => result += 10,
-            Rank::King => result += 10,
-            Rank::Ace => result += 1,
-            x => result += x as u8,
-        }
-    }
-    for card in hand.iter() {
-        if let Rank::Ace = card.rank {
-            if result + 10 <= 21 {
-                result += 10;
-            }
-        }
+// Points of a card, an ace counting 1
+pub fn card_value(card: &Card) -> u8 {
+    match card.rank {
+        Rank::Knight => 10,
+        Rank::Queen => 10,
+        Rank::King => 10,
+        Rank::Ace => 1,
+        x => x as u8,
     }
+}
+
+// Best total of the cards and whether an ace counts as 11 in it
+pub fn hand_value(hand: &[Card]) -> (u8, bool) {
+    let hard: u8 = hand.iter().map(card_value).sum();
+    let soft = hand.iter().any(|card| card.rank == Rank::Ace) && hard + 10 <= 21;
+    (if soft { hard + 10 } else { hard }, soft)
+}
+
+pub fn get_score(player: &dyn Person) -> Score {
+    let hand = player.get_hand();
+    let (result, _) = hand_value(hand);
     if result > 21 {
         return Score::Busted;
     }
@@ -280,6 +306,11 @@ pub fn get_score(player: &dyn Person) -> Score {
     Score::Points(result)
 }
 
+// An ace still counts as 11 in the score
+pub fn is_soft(player: &dyn Person) -> bool {
+    hand_value(player.get_hand()).1
+}
+
 #[derive(PartialEq, PartialOrd, Debug)]
 pub enum Score {
     Busted,
@@ -318,6 +349,27 @@ pub fn get_winner<'a>(
     }
 }
 
+// Table rules from the command line, defaulting to `Rules::default()`
+fn rules_from_args(args: &ArgMatches) -> Result<Rules, String> {
+    let defaults = Rules::default();
+    let rules = Rules {
+        decks: args.get_one::<usize>("decks").copied().unwrap_or(defaults.decks),
+        dealer_hits_soft_17: args.get_flag("h17"),
+        blackjack_payout: if args.get_flag("six-to-five") { Rules::SIX_TO_FIVE } else { Rules::THREE_TO_TWO },
+        double_after_split: !args.get_flag("no-das"),
+        max_splits: args.get_one::<usize>("max-splits").copied().unwrap_or(defaults.max_splits),
+        surrender: !args.get_flag("no-surrender"),
+        penetration: args.get_one::<f32>("penetration").copied().unwrap_or(defaults.penetration),
+    };
+    if rules.decks == 0 {
+        return Err("The shoe needs at least one deck".to_string());
+    }
+    if !(rules.penetration > 0.0 && rules.penetration < 1.0) {
+        return Err(format!("Penetration {} must be between 0 and 1", rules.penetration));
+    }
+    Ok(rules)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -379,11 +431,100 @@ mod tests {
         let score = get_score(&player);
         assert_eq!(Score::Points(12), score);
     }
+
+    #[test]
+    fn dealer_draws_to_soft_17_only_under_h17() {
+        let mut dealer = Dealer::new("Dealer");
+        dealer.deal_card(Card::new(Suit::Hearts, Rank::Ace));
+        dealer.deal_card(Card::new(Suit::Hearts, Rank::Six));
+        assert!(is_soft(&dealer));
+        assert!(!Rules::default().dealer_hits(17, is_soft(&dealer)));
+        let h17 = Rules { dealer_hits_soft_17: true, ..Rules::default() };
+        assert!(h17.dealer_hits(17, is_soft(&dealer)));
+
+        dealer.deal_card(Card::new(Suit::Tiles, Rank::King));
+        assert!(!is_soft(&dealer));
+        assert!(!h17.dealer_hits(17, is_soft(&dealer)));
+        assert_eq!(Deck::with_decks(6).cards.len(), 312);
+    }
+
+    #[test]
+    fn deck_has_52_cards() {
+        let deck = Deck::new();
+        assert_eq!(deck.cards.len(), 52);
+        assert_eq!(deck.cards.iter().filter(|card| card.rank == Rank::Ace).count(), 4);
+    }
 }
 
 fn main() {
+    let args = Command::new("blackjack")
+        .about("Blackjack against the dealer, or a simulation of basic strategy")
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .value_name("ROUNDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Play this many rounds with basic strategy and report the results"),
+        )
+        .arg(Arg::new("decks").long("decks").value_parser(clap::value_parser!(usize)).help("Decks in the shoe [default: 6]"))
+        .arg(Arg::new("h17").long("h17").action(ArgAction::SetTrue).help("The dealer hits soft 17"))
+        .arg(Arg::new("six-to-five").long("six-to-five").action(ArgAction::SetTrue).help("Naturals pay 6:5 instead of 3:2"))
+        .arg(Arg::new("no-das").long("no-das").action(ArgAction::SetTrue).help("No doubling after a split"))
+        .arg(Arg::new("max-splits").long("max-splits").value_parser(clap::value_parser!(usize)).help("Splits per round [default: 3]"))
+        .arg(Arg::new("no-surrender").long("no-surrender").action(ArgAction::SetTrue).help("No late surrender"))
+        .arg(
+            Arg::new("penetration")
+                .long("penetration")
+                .value_parser(clap::value_parser!(f32))
+                .help("Share of the shoe dealt before reshuffling [default: 0.75]"),
+        )
+        .arg(
+            Arg::new("count")
+                .long("count")
+                .value_name("SYSTEM")
+                .help("Also simulate a counter spreading bets by the true count: hi-lo, hi-opt-i or ko"),
+        )
+        .arg(
+            Arg::new("spread")
+                .long("spread")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("8")
+                .help("Largest bet of the counter in units"),
+        )
+        .get_matches();
+    let rules = match rules_from_args(&args) {
+        Ok(rules) => rules,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+
+    if let Some(rounds) = args.get_one::<u64>("simulate") {
+        let name = args.get_one::<String>("count").map_or("hi-lo", String::as_str);
+        let Some(system) = counting::system_by_name(name) else {
+            println!("Unknown counting system {}", name);
+            return;
+        };
+        println!("{:?}", rules);
+        let flat = simulate(rules, &BasicStrategy::new(rules), system.clone(), *rounds);
+        println!("{}\n", flat);
+        let count = if system.balanced() { "true count" } else { "running count" };
+        println!("Player edge by {} {}:", system.name(), count);
+        for (count, share, edge) in flat.edge_by_count() {
+            println!("{:+3}: {:5.2}% of rounds, {:+.2}%", count, share, edge);
+        }
+        if args.contains_id("count") {
+            let spread = *args.get_one::<f64>("spread").unwrap();
+            let counted = simulate(rules, &CountingBettor::new(rules, spread), system, *rounds);
+            println!("\nCounting with a 1-{} spread:\n{}", spread, counted);
+            println!("Counting shifts the house edge by {:+.2}%", counted.house_edge() - flat.house_edge());
+        }
+        return;
+    }
+
     loop {
-        let mut deck = Deck::new(); // Create a new deck
+        let mut deck = Deck::with_decks(rules.decks); // Create a new shoe
         deck.shuffle(); // Shuffle it.
 
         // Create some players.
@@ -448,7 +589,9 @@ fn main() {
         // Dealer's turn.
         if get_score(&player) != Score::Busted && get_score(&player) != Score::Blackjack {
             let mut dealer_score = get_score(&dealer);
-            while dealer_score < Score::Points(17) && dealer_score != Score::Busted {
+            while let Score::Points(points) = dealer_score
+                && rules.dealer_hits(points as i32, is_soft(&dealer))
+            {
                 let card = deck.get_card();
                 announce_dealing(&card, &dealer.name);
                 dealer.deal_card(card);
@@ -0,0 +1,48 @@
+// House rules of a blackjack table, shared by the blackjack binaries. The defaults are a common
+// six-deck shoe: the dealer stands on soft 17, naturals pay 3:2, doubling after a split and late
+// surrender are allowed and the cut card sits three quarters into the shoe.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rules {
+    // Decks shuffled together into the shoe
+    pub decks: usize,
+    // H17 when true, S17 when false
+    pub dealer_hits_soft_17: bool,
+    // Paid per unit bet on a natural
+    pub blackjack_payout: f64,
+    pub double_after_split: bool,
+    // Splits per round, so a player holds at most `max_splits + 1` hands
+    pub max_splits: usize,
+    // Late surrender, after the dealer checked for a natural
+    pub surrender: bool,
+    // Share of the shoe dealt before the cut card comes out and the shoe is reshuffled
+    pub penetration: f32,
+}
+
+impl Rules {
+    pub const THREE_TO_TWO: f64 = 1.5;
+    pub const SIX_TO_FIVE: f64 = 1.2;
+
+    // Whether the dealer draws to the total; `soft` when an ace counts as 11 in it
+    pub fn dealer_hits(&self, total: i32, soft: bool) -> bool {
+        total < 17 || (total == 17 && soft && self.dealer_hits_soft_17)
+    }
+
+    pub fn max_hands(&self) -> usize {
+        self.max_splits + 1
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            decks: 6,
+            dealer_hits_soft_17: false,
+            blackjack_payout: Self::THREE_TO_TWO,
+            double_after_split: true,
+            max_splits: 3,
+            surrender: true,
+            penetration: 0.75,
+        }
+    }
+}
@@ -0,0 +1,523 @@
+// Headless blackjack for measuring rule variants: a strategy plays millions of rounds against the
+// dealer, spread over threads with rayon, and the results are added up into win, loss and push
+// rates, the expected value of a round and the house edge. The table keeps a count of the cards,
+// so strategies can size their bets by it and the results are broken down by count: the true
+// count, or the running count for an unbalanced system.
+
+use crate::counting::{CountingSystem, Counter};
+use crate::rules::Rules;
+use crate::{card_value, hand_value, Card, Deck, Rank, Reply};
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::Arc;
+
+// Rounds each parallel task plays on its own shoe
+const ROUNDS_PER_TASK: u64 = 10_000;
+
+// Rounds are grouped by count from -5 to +5, counts beyond that join the outer groups
+const COUNT_RANGE: i32 = 5;
+const COUNT_GROUPS: usize = 2 * COUNT_RANGE as usize + 1;
+
+// Actions open to the player on top of hitting and standing
+pub struct Options {
+    pub can_double: bool,
+    pub can_split: bool,
+    pub can_surrender: bool,
+}
+
+// Decides the player's actions. Hit, Stand, Double, Split and Surrender are understood; an
+// action that is not open is played as a hit.
+pub trait Strategy: Sync {
+    fn decide(&self, hand: &[Card], dealer_up: &Card, options: &Options) -> Reply;
+
+    // Initial bet of a round in units, given the betting count before the cards are dealt
+    fn bet(&self, count: f32) -> f64 {
+        1.0
+    }
+}
+
+// Basic strategy for multi-deck games, following the dealer's soft 17 rule, double after split
+// and surrender
+pub struct BasicStrategy {
+    rules: Rules,
+}
+
+impl BasicStrategy {
+    pub fn new(rules: Rules) -> Self {
+        BasicStrategy { rules }
+    }
+
+    // Dealer up cards count 2 to 11
+    fn split(&self, value: u8, up: u8) -> bool {
+        let das = self.rules.double_after_split;
+        match value {
+            1 | 8 => true,
+            9 => !matches!(up, 7 | 10 | 11),
+            7 => up <= 7,
+            6 => up <= 6 && (das || up >= 3),
+            4 => das && (5..=6).contains(&up),
+            2 | 3 => up <= 7 && (das || up >= 4),
+            _ => false,
+        }
+    }
+
+    fn surrender(&self, total: u8, up: u8) -> bool {
+        let h17 = self.rules.dealer_hits_soft_17;
+        match total {
+            16 => up >= 9,
+            15 => up == 10 || (h17 && up == 11),
+            17 => h17 && up == 11,
+            _ => false,
+        }
+    }
+
+    fn hard(&self, total: u8, up: u8) -> Reply {
+        match total {
+            17.. => Reply::Stand,
+            13..=16 if up <= 6 => Reply::Stand,
+            12 if (4..=6).contains(&up) => Reply::Stand,
+            11 if up <= 10 || self.rules.dealer_hits_soft_17 => Reply::Double,
+            10 if up <= 9 => Reply::Double,
+            9 if (3..=6).contains(&up) => Reply::Double,
+            _ => Reply::Hit,
+        }
+    }
+
+    fn soft(&self, total: u8, up: u8) -> Reply {
+        let h17 = self.rules.dealer_hits_soft_17;
+        match total {
+            20.. => Reply::Stand,
+            19 if h17 && up == 6 => Reply::Double,
+            19 => Reply::Stand,
+            18 if (3..=6).contains(&up) || (h17 && up == 2) => Reply::Double,
+            18 if up <= 8 => Reply::Stand,
+            17 if (3..=6).contains(&up) => Reply::Double,
+            15 | 16 if (4..=6).contains(&up) => Reply::Double,
+            13 | 14 if (5..=6).contains(&up) => Reply::Double,
+            _ => Reply::Hit,
+        }
+    }
+}
+
+impl Strategy for BasicStrategy {
+    fn decide(&self, hand: &[Card], dealer_up: &Card, options: &Options) -> Reply {
+        let up = match dealer_up.rank {
+            Rank::Ace => 11,
+            _ => card_value(dealer_up),
+        };
+        let (total, soft) = hand_value(hand);
+        let pair = hand.len() == 2 && hand[0].rank == hand[1].rank;
+        if options.can_split && pair && self.split(card_value(&hand[0]), up) {
+            return Reply::Split;
+        }
+        if options.can_surrender && !soft && self.surrender(total, up) {
+            return Reply::Surrender;
+        }
+        match if soft { self.soft(total, up) } else { self.hard(total, up) } {
+            // soft 18 and 19 stand when doubling is not allowed
+            Reply::Double if !options.can_double && total >= 18 => Reply::Stand,
+            Reply::Double if !options.can_double => Reply::Hit,
+            reply => reply,
+        }
+    }
+}
+
+// Basic strategy that bets one unit per point of the betting count, from 1 up to `spread` units
+pub struct CountingBettor {
+    basic: BasicStrategy,
+    spread: f64,
+}
+
+impl CountingBettor {
+    pub fn new(rules: Rules, spread: f64) -> Self {
+        CountingBettor {
+            basic: BasicStrategy::new(rules),
+            spread,
+        }
+    }
+}
+
+impl Strategy for CountingBettor {
+    fn decide(&self, hand: &[Card], dealer_up: &Card, options: &Options) -> Reply {
+        self.basic.decide(hand, dealer_up, options)
+    }
+
+    fn bet(&self, count: f32) -> f64 {
+        (count.floor() as f64).clamp(1.0, self.spread)
+    }
+}
+
+// Totals of a simulation. Outcomes are counted per hand played, so a split adds a hand; money is
+// in betting units.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub rounds: u64,
+    pub hands: u64,
+    pub wins: u64,
+    pub losses: u64,
+    pub pushes: u64,
+    // Bets at the start of the rounds, and everything bet including splits and doubles
+    pub initial_bets: f64,
+    pub wagered: f64,
+    pub net: f64,
+    // Rounds and net result per unit bet, by betting count at the start of the round
+    pub by_count: [(u64, f64); COUNT_GROUPS],
+}
+
+impl Stats {
+    fn merge(self, other: Stats) -> Stats {
+        Stats {
+            rounds: self.rounds + other.rounds,
+            hands: self.hands + other.hands,
+            wins: self.wins + other.wins,
+            losses: self.losses + other.losses,
+            pushes: self.pushes + other.pushes,
+            initial_bets: self.initial_bets + other.initial_bets,
+            wagered: self.wagered + other.wagered,
+            net: self.net + other.net,
+            by_count: std::array::from_fn(|group| {
+                let ((rounds, net), (other_rounds, other_net)) = (self.by_count[group], other.by_count[group]);
+                (rounds + other_rounds, net + other_net)
+            }),
+        }
+    }
+
+    fn record(&mut self, bet: f64, net: f64) {
+        self.hands += 1;
+        self.wagered += bet;
+        self.net += net;
+        if net > 0.0 {
+            self.wins += 1;
+        } else if net < 0.0 {
+            self.losses += 1;
+        } else {
+            self.pushes += 1;
+        }
+    }
+
+    // Expected result of a round, splits and doubles included, per unit of initial bet
+    pub fn expected_value(&self) -> f64 {
+        self.net / self.initial_bets
+    }
+
+    // What the house keeps of the initial bets, in percent
+    pub fn house_edge(&self) -> f64 {
+        -100.0 * self.expected_value()
+    }
+
+    // Share of the rounds and the player's edge in percent for each betting count
+    pub fn edge_by_count(&self) -> impl Iterator<Item = (i32, f64, f64)> + '_ {
+        self.by_count.iter().enumerate().filter(|(_, (rounds, _))| *rounds > 0).map(|(group, (rounds, net))| {
+            let share = 100.0 * *rounds as f64 / self.rounds as f64;
+            (group as i32 - COUNT_RANGE, share, 100.0 * net / *rounds as f64)
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = |count: u64| 100.0 * count as f64 / self.hands as f64;
+        writeln!(f, "Rounds: {}, hands: {}", self.rounds, self.hands)?;
+        writeln!(f, "Win {:.2}% / Loss {:.2}% / Push {:.2}%", rate(self.wins), rate(self.losses), rate(self.pushes))?;
+        writeln!(f, "Average initial bet: {:.2} units", self.initial_bets / self.rounds as f64)?;
+        writeln!(f, "EV per round: {:+.4} initial bets", self.expected_value())?;
+        write!(f, "House edge: {:.2}% of the initial bet, {:.2}% of the total wagered", self.house_edge(), -100.0 * self.net / self.wagered)
+    }
+}
+
+struct SimHand {
+    cards: Vec<Card>,
+    bet: f64,
+    split: bool,
+    surrendered: bool,
+    // Stood, doubled, surrendered or a split ace
+    done: bool,
+}
+
+// A dealer with a shoe, dealing rounds to one player, and the count of the cards dealt
+struct Table {
+    rules: Rules,
+    shoe: Deck,
+    counter: Counter,
+}
+
+impl Table {
+    fn new(rules: Rules, system: Arc<dyn CountingSystem>) -> Self {
+        let mut shoe = Deck::with_decks(rules.decks);
+        shoe.shuffle();
+        Table { rules, shoe, counter: Counter::new(system, rules.decks) }
+    }
+
+    // A shoe that runs out mid-round is reshuffled rather than left empty
+    fn draw(&mut self) -> Card {
+        if self.shoe.remaining() == 0 {
+            self.reshuffle();
+        }
+        let card = self.shoe.get_card();
+        self.counter.see(card_value(&card) as i32);
+        card
+    }
+
+    fn reshuffle_at_cut_card(&mut self) {
+        let dealt = 1.0 - self.shoe.remaining() as f32 / (self.rules.decks * 52) as f32;
+        if dealt >= self.rules.penetration {
+            self.reshuffle();
+        }
+    }
+
+    fn reshuffle(&mut self) {
+        self.shoe = Deck::with_decks(self.rules.decks);
+        self.shoe.shuffle();
+        self.counter.reset();
+    }
+
+    fn play_round(&mut self, strategy: &dyn Strategy) -> Stats {
+        self.reshuffle_at_cut_card();
+        let count = self.counter.betting_count(self.shoe.remaining());
+        let bet = strategy.bet(count);
+        let mut stats = Stats { rounds: 1, initial_bets: bet, ..Stats::default() };
+        let group = (count.round() as i32).clamp(-COUNT_RANGE, COUNT_RANGE) + COUNT_RANGE;
+        let round = self.deal_round(strategy, bet, &mut stats);
+        stats.by_count[group as usize] = (1, round / bet);
+        stats
+    }
+
+    // Plays the hands of a round and returns the player's net result
+    fn deal_round(&mut self, strategy: &dyn Strategy, bet: f64, stats: &mut Stats) -> f64 {
+        let (first, up) = (self.draw(), self.draw());
+        let player = vec![first, self.draw()];
+        let dealer = vec![up.clone(), self.draw()];
+
+        let natural = |cards: &[Card]| hand_value(cards).0 == 21;
+        if natural(&dealer) || natural(&player) {
+            let net = match (natural(&player), natural(&dealer)) {
+                (true, true) => 0.0,
+                (true, false) => bet * self.rules.blackjack_payout,
+                _ => -bet,
+            };
+            stats.record(bet, net);
+            return net;
+        }
+
+        let mut hands = vec![SimHand { cards: player, bet, split: false, surrendered: false, done: false }];
+        let mut index = 0;
+        while index < hands.len() {
+            let hand = &hands[index];
+            if hand.done || hand_value(&hand.cards).0 >= 21 {
+                index += 1;
+                continue;
+            }
+            let first_two = hand.cards.len() == 2;
+            let options = Options {
+                can_double: first_two && (!hand.split || self.rules.double_after_split),
+                can_split: first_two && hand.cards[0].rank == hand.cards[1].rank && hands.len() < self.rules.max_hands(),
+                can_surrender: first_two && self.rules.surrender && hands.len() == 1 && !hand.split,
+            };
+            match strategy.decide(&hand.cards, &up, &options) {
+                Reply::Stand | Reply::AskUI => hands[index].done = true,
+                Reply::Double if options.can_double => {
+                    let card = self.draw();
+                    let hand = &mut hands[index];
+                    hand.bet *= 2.0;
+                    hand.cards.push(card);
+                    hand.done = true;
+                }
+                Reply::Split if options.can_split => {
+                    let second = hands[index].cards.pop().expect("A pair has two cards");
+                    // split aces get one card each
+                    let aces = second.rank == Rank::Ace;
+                    let (card, other_card) = (self.draw(), self.draw());
+                    let hand = &mut hands[index];
+                    hand.cards.push(card);
+                    hand.split = true;
+                    hand.done = aces;
+                    let bet = hand.bet;
+                    hands.insert(index + 1, SimHand { cards: vec![second, other_card], bet, split: true, surrendered: false, done: aces });
+                }
+                Reply::Surrender if options.can_surrender => {
+                    hands[index].surrendered = true;
+                    hands[index].done = true;
+                }
+                _ => {
+                    let card = self.draw();
+                    hands[index].cards.push(card);
+                }
+            }
+        }
+
+        let mut dealer = dealer;
+        if hands.iter().any(|hand| !hand.surrendered && hand_value(&hand.cards).0 <= 21) {
+            loop {
+                let (total, soft) = hand_value(&dealer);
+                if !self.rules.dealer_hits(total as i32, soft) {
+                    break;
+                }
+                dealer.push(self.draw());
+            }
+        }
+        let dealer_total = hand_value(&dealer).0;
+        let mut round = 0.0;
+        for hand in &hands {
+            let total = hand_value(&hand.cards).0;
+            let net = if hand.surrendered {
+                -hand.bet / 2.0
+            } else if total > 21 {
+                -hand.bet
+            } else if dealer_total > 21 || total > dealer_total {
+                hand.bet
+            } else if total == dealer_total {
+                0.0
+            } else {
+                -hand.bet
+            };
+            stats.record(hand.bet, net);
+            round += net;
+        }
+        round
+    }
+}
+
+// Plays the rounds in parallel, each task on a freshly shuffled shoe, counting with the system
+pub fn simulate(rules: Rules, strategy: &dyn Strategy, system: Arc<dyn CountingSystem>, rounds: u64) -> Stats {
+    let tasks = rounds.div_ceil(ROUNDS_PER_TASK);
+    (0..tasks)
+        .into_par_iter()
+        .map(|task| {
+            let mut table = Table::new(rules, system.clone());
+            let task_rounds = ROUNDS_PER_TASK.min(rounds - task * ROUNDS_PER_TASK);
+            (0..task_rounds).fold(Stats::default(), |stats, _| stats.merge(table.play_round(strategy)))
+        })
+        .reduce(Stats::default, Stats::merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Suit;
+    use crate::counting::{HiLo, KnockOut};
+
+    fn card(rank: Rank) -> Card {
+        Card::new(Suit::Hearts, rank)
+    }
+
+    // A table dealing the ranks in order: player, dealer up card, player, dealer hole card, draws
+    fn stacked_table(rules: Rules, ranks: &[Rank]) -> Table {
+        let mut shoe = Deck::with_decks(rules.decks);
+        shoe.cards.extend(ranks.iter().rev().map(|rank| card(*rank)));
+        Table { rules, shoe, counter: Counter::new(Arc::new(HiLo), rules.decks) }
+    }
+
+    #[test]
+    fn test_shoe_running_out_mid_round_is_reshuffled() {
+        // a one-deck shoe with the cut card at the very end deals until it is empty
+        let rules = Rules { decks: 1, penetration: 1.0, ..Rules::default() };
+        let mut table = Table::new(rules, Arc::new(HiLo));
+        let stats = (0..2_000).fold(Stats::default(), |stats, _| stats.merge(table.play_round(&BasicStrategy::new(rules))));
+        assert_eq!(stats.rounds, 2_000);
+    }
+
+    fn decide(rules: Rules, hand: &[Rank], up: Rank, options: Options) -> Reply {
+        let hand: Vec<Card> = hand.iter().map(|rank| card(*rank)).collect();
+        BasicStrategy::new(rules).decide(&hand, &card(up), &options)
+    }
+
+    const ALL: Options = Options { can_double: true, can_split: true, can_surrender: true };
+    const NO_DOUBLE: Options = Options { can_double: false, can_split: false, can_surrender: false };
+
+    #[test]
+    fn test_basic_strategy() {
+        let s17 = Rules::default();
+        let h17 = Rules { dealer_hits_soft_17: true, ..s17 };
+        assert!(matches!(decide(s17, &[Rank::Eight, Rank::Eight], Rank::Ten, ALL), Reply::Split));
+        assert!(matches!(decide(s17, &[Rank::King, Rank::Queen], Rank::Six, ALL), Reply::Stand));
+        assert!(matches!(decide(s17, &[Rank::Nine, Rank::Seven], Rank::Ten, ALL), Reply::Surrender));
+        assert!(matches!(decide(s17, &[Rank::Nine, Rank::Seven], Rank::Ten, NO_DOUBLE), Reply::Hit));
+        assert!(matches!(decide(s17, &[Rank::Six, Rank::Five], Rank::Ace, ALL), Reply::Hit));
+        assert!(matches!(decide(h17, &[Rank::Six, Rank::Five], Rank::Ace, ALL), Reply::Double));
+        assert!(matches!(decide(s17, &[Rank::Ace, Rank::Seven], Rank::Four, ALL), Reply::Double));
+        assert!(matches!(decide(s17, &[Rank::Ace, Rank::Seven], Rank::Four, NO_DOUBLE), Reply::Stand));
+        assert!(matches!(decide(s17, &[Rank::Ace, Rank::Seven], Rank::Nine, ALL), Reply::Hit));
+        let no_das = Rules { double_after_split: false, ..s17 };
+        assert!(matches!(decide(s17, &[Rank::Two, Rank::Two], Rank::Two, ALL), Reply::Split));
+        assert!(matches!(decide(no_das, &[Rank::Two, Rank::Two], Rank::Two, ALL), Reply::Hit));
+    }
+
+    #[test]
+    fn test_round_settlement() {
+        let rules = Rules::default();
+        let strategy = BasicStrategy::new(rules);
+
+        // natural against 17
+        let stats = stacked_table(rules, &[Rank::Ace, Rank::Ten, Rank::King, Rank::Seven]).play_round(&strategy);
+        assert_eq!((stats.wins, stats.net), (1, 1.5));
+
+        // 16 against a ten surrenders
+        let stats = stacked_table(rules, &[Rank::Ten, Rank::Ten, Rank::Six, Rank::Eight]).play_round(&strategy);
+        assert_eq!((stats.losses, stats.net), (1, -0.5));
+
+        // 8 8 against 7 splits into 8 3 doubled to 21 and 8 10, dealer 7 10
+        let stats = stacked_table(rules, &[Rank::Eight, Rank::Seven, Rank::Eight, Rank::Ten, Rank::Three, Rank::Ten, Rank::Ten])
+            .play_round(&strategy);
+        assert_eq!((stats.hands, stats.wins, stats.wagered, stats.net), (2, 2, 3.0, 3.0));
+    }
+
+    #[test]
+    fn test_six_to_five_pays_exactly() {
+        let rules = Rules { blackjack_payout: Rules::SIX_TO_FIVE, ..Rules::default() };
+        let stats = stacked_table(rules, &[Rank::Ace, Rank::Ten, Rank::King, Rank::Seven]).play_round(&BasicStrategy::new(rules));
+        assert_eq!(stats.net, 1.2);
+    }
+
+    #[test]
+    fn test_simulation_edge_is_plausible() {
+        let rules = Rules::default();
+        let stats = simulate(rules, &BasicStrategy::new(rules), Arc::new(HiLo), 50_000);
+        assert_eq!(stats.rounds, 50_000);
+        assert_eq!(stats.wins + stats.losses + stats.pushes, stats.hands);
+        assert!((-2.0..3.0).contains(&stats.house_edge()), "{}", stats);
+    }
+
+    #[test]
+    fn test_counting_sizes_bets() {
+        let rules = Rules::default();
+        let bettor = CountingBettor::new(rules, 8.0);
+        assert_eq!((bettor.bet(-2.5), bettor.bet(2.9), bettor.bet(12.0)), (1.0, 2.0, 8.0));
+
+        // five low cards seen before the round: Hi-Lo +5 with 56 cards left is a true count of 4.6
+        let mut table = stacked_table(Rules { decks: 1, ..rules }, &[Rank::Ace, Rank::Ten, Rank::King, Rank::Seven]);
+        for _ in 0..5 {
+            table.counter.see(2);
+        }
+        let stats = table.play_round(&bettor);
+        assert_eq!((stats.initial_bets, stats.net), (4.0, 6.0));
+        assert_eq!(stats.by_count[(5 + COUNT_RANGE) as usize], (1, 1.5));
+        assert_eq!(table.counter.running_count(), 2);
+
+        let stats = simulate(rules, &bettor, Arc::new(HiLo), 20_000);
+        assert!(stats.initial_bets > stats.rounds as f64);
+        assert_eq!(stats.by_count.iter().map(|(rounds, _)| rounds).sum::<u64>(), stats.rounds);
+    }
+
+    #[test]
+    fn test_knock_out_bets_by_running_count() {
+        // six decks start KO at -20, and the bettor ramps up from the running count
+        let rules = Rules::default();
+        let bettor = CountingBettor::new(rules, 8.0);
+        let mut table = Table::new(rules, Arc::new(KnockOut));
+        assert_eq!(table.counter.running_count(), -20);
+        assert_eq!(bettor.bet(table.counter.betting_count(table.shoe.remaining())), 1.0);
+        for _ in 0..24 {
+            table.counter.see(2);
+        }
+        // +4 with almost six decks left, a true count below one
+        assert_eq!(table.counter.betting_count(table.shoe.remaining()), 4.0);
+        assert_eq!(bettor.bet(table.counter.betting_count(table.shoe.remaining())), 4.0);
+        table.reshuffle();
+        assert_eq!(table.counter.running_count(), -20);
+
+        // the count does not drift up through the shoe, so most rounds are bet small
+        let stats = simulate(rules, &bettor, Arc::new(KnockOut), 20_000);
+        assert!(stats.initial_bets < 2.0 * stats.rounds as f64, "{}", stats);
+        assert!(stats.initial_bets > stats.rounds as f64);
+    }
+}
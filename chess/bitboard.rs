@@ -0,0 +1,383 @@
+// Bitboard board representation shared by the chess binaries. Every piece kind of every color
+// gets a u64 with bit `file + 8 * rank` set where such a piece stands, so a1 is bit 0 and h8 is
+// bit 63. Attacks of knights, kings and pawns come from precomputed tables, sliding pieces
+// follow precomputed rays up to the first blocker.
+
+use std::sync::OnceLock;
+
+pub const WHITE: usize = 0;
+pub const BLACK: usize = 1;
+
+pub const PAWN: usize = 0;
+pub const KNIGHT: usize = 1;
+pub const BISHOP: usize = 2;
+pub const ROOK: usize = 3;
+pub const QUEEN: usize = 4;
+pub const KING: usize = 5;
+
+// Promotion choices in the order moves are generated
+pub const PROMOTION_KINDS: [usize; 4] = [QUEEN, ROOK, BISHOP, KNIGHT];
+
+pub const WHITE_KING_SIDE: u8 = 1;
+pub const WHITE_QUEEN_SIDE: u8 = 2;
+pub const BLACK_KING_SIDE: u8 = 4;
+pub const BLACK_QUEEN_SIDE: u8 = 8;
+pub const ALL_CASTLING: u8 = WHITE_KING_SIDE | WHITE_QUEEN_SIDE | BLACK_KING_SIDE | BLACK_QUEEN_SIDE;
+
+// a1, c1, ..., b2, d2, ...
+pub const DARK_SQUARES: u64 = 0xAA55_AA55_AA55_AA55;
+
+pub fn square(file: u8, rank: u8) -> u8 {
+    rank * 8 + file
+}
+
+pub fn bit(square: u8) -> u64 {
+    1 << square
+}
+
+// Squares of the set bits, lowest first
+pub fn squares(mut bitboard: u64) -> impl Iterator<Item = u8> {
+    std::iter::from_fn(move || {
+        if bitboard == 0 {
+            return None;
+        }
+        let square = bitboard.trailing_zeros() as u8;
+        bitboard &= bitboard - 1;
+        Some(square)
+    })
+}
+
+// Castling rights that go away once a piece leaves or is captured on the square
+pub fn castling_rights_lost(square: u8) -> u8 {
+    match square {
+        4 => WHITE_KING_SIDE | WHITE_QUEEN_SIDE,
+        7 => WHITE_KING_SIDE,
+        0 => WHITE_QUEEN_SIDE,
+        60 => BLACK_KING_SIDE | BLACK_QUEEN_SIDE,
+        63 => BLACK_KING_SIDE,
+        56 => BLACK_QUEEN_SIDE,
+        _ => 0,
+    }
+}
+
+// Ray directions as (file, rank) steps. The first four lead to higher squares, the last four
+// to lower ones; even indices are rook directions, odd ones bishop directions.
+const DIRECTIONS: [(i8, i8); 8] = [(0, 1), (1, 1), (1, 0), (-1, 1), (0, -1), (-1, -1), (-1, 0), (1, -1)];
+
+struct AttackTables {
+    knight: [u64; 64],
+    king: [u64; 64],
+    // indexed by the color of the pawn
+    pawn: [[u64; 64]; 2],
+    rays: [[u64; 64]; 8],
+}
+
+impl AttackTables {
+    fn new() -> Self {
+        let mut tables = AttackTables {
+            knight: [0; 64],
+            king: [0; 64],
+            pawn: [[0; 64]; 2],
+            rays: [[0; 64]; 8],
+        };
+        let step = |square: u8, (file_step, rank_step): (i8, i8)| -> Option<u8> {
+            let file = (square % 8) as i8 + file_step;
+            let rank = (square / 8) as i8 + rank_step;
+            ((0..8).contains(&file) && (0..8).contains(&rank)).then_some((rank * 8 + file) as u8)
+        };
+        let targets = |square: u8, steps: &[(i8, i8)]| -> u64 {
+            steps.iter().filter_map(|&direction| step(square, direction)).fold(0, |bitboard, target| bitboard | bit(target))
+        };
+        for square in 0..64u8 {
+            let idx = square as usize;
+            tables.knight[idx] = targets(square, &[(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)]);
+            tables.king[idx] = targets(square, &DIRECTIONS);
+            tables.pawn[WHITE][idx] = targets(square, &[(-1, 1), (1, 1)]);
+            tables.pawn[BLACK][idx] = targets(square, &[(-1, -1), (1, -1)]);
+            for (direction, &direction_step) in DIRECTIONS.iter().enumerate() {
+                let mut current = square;
+                while let Some(next) = step(current, direction_step) {
+                    tables.rays[direction][idx] |= bit(next);
+                    current = next;
+                }
+            }
+        }
+        tables
+    }
+}
+
+fn tables() -> &'static AttackTables {
+    static TABLES: OnceLock<AttackTables> = OnceLock::new();
+    TABLES.get_or_init(AttackTables::new)
+}
+
+pub fn knight_attacks(square: u8) -> u64 {
+    tables().knight[square as usize]
+}
+
+pub fn king_attacks(square: u8) -> u64 {
+    tables().king[square as usize]
+}
+
+// Squares a pawn of the given color on `square` captures on
+pub fn pawn_attacks(color: usize, square: u8) -> u64 {
+    tables().pawn[color][square as usize]
+}
+
+// Squares along one ray up to and including the first occupied one
+fn ray_attacks(direction: usize, square: u8, occupied: u64) -> u64 {
+    let rays = &tables().rays[direction];
+    let ray = rays[square as usize];
+    let blockers = ray & occupied;
+    if blockers == 0 {
+        return ray;
+    }
+    let blocker = if direction < 4 { blockers.trailing_zeros() } else { 63 - blockers.leading_zeros() };
+    ray ^ rays[blocker as usize]
+}
+
+pub fn bishop_attacks(square: u8, occupied: u64) -> u64 {
+    [1, 3, 5, 7].iter().fold(0, |attacks, &direction| attacks | ray_attacks(direction, square, occupied))
+}
+
+pub fn rook_attacks(square: u8, occupied: u64) -> u64 {
+    [0, 2, 4, 6].iter().fold(0, |attacks, &direction| attacks | ray_attacks(direction, square, occupied))
+}
+
+pub fn queen_attacks(square: u8, occupied: u64) -> u64 {
+    bishop_attacks(square, occupied) | rook_attacks(square, occupied)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub from: u8,
+    pub to: u8,
+    // Kind a pawn reaching the last rank turns into
+    pub promotion: Option<usize>,
+}
+
+impl Move {
+    pub fn new(from: u8, to: u8) -> Self {
+        Move { from, to, promotion: None }
+    }
+}
+
+// A full chess position: placement, side to move, castling rights, en passant square and clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    // pieces[color][kind]
+    pub pieces: [[u64; 6]; 2],
+    pub side: usize,
+    pub castling: u8,
+    // Square skipped by a pawn that just advanced two squares
+    pub en_passant: Option<u8>,
+    // Plies since the last capture or pawn move
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            pieces: [[0; 6]; 2],
+            side: WHITE,
+            castling: 0,
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        }
+    }
+}
+
+impl Position {
+    pub fn occupancy(&self, color: usize) -> u64 {
+        self.pieces[color].iter().fold(0, |occupied, bitboard| occupied | bitboard)
+    }
+
+    pub fn occupied(&self) -> u64 {
+        self.occupancy(WHITE) | self.occupancy(BLACK)
+    }
+
+    // Color and kind of the piece on the square
+    pub fn piece_at(&self, square: u8) -> Option<(usize, usize)> {
+        let mask = bit(square);
+        (0..2).flat_map(|color| (0..6).map(move |kind| (color, kind)))
+            .find(|&(color, kind)| self.pieces[color][kind] & mask != 0)
+    }
+
+    pub fn put(&mut self, color: usize, kind: usize, square: u8) {
+        self.remove(square);
+        self.pieces[color][kind] |= bit(square);
+    }
+
+    pub fn remove(&mut self, square: u8) -> Option<(usize, usize)> {
+        let (color, kind) = self.piece_at(square)?;
+        self.pieces[color][kind] &= !bit(square);
+        Some((color, kind))
+    }
+
+    pub fn king_square(&self, color: usize) -> Option<u8> {
+        squares(self.pieces[color][KING]).next()
+    }
+
+    pub fn is_attacked(&self, square: u8, by: usize) -> bool {
+        let pieces = &self.pieces[by];
+        let occupied = self.occupied();
+        pawn_attacks(1 - by, square) & pieces[PAWN] != 0
+            || knight_attacks(square) & pieces[KNIGHT] != 0
+            || king_attacks(square) & pieces[KING] != 0
+            || bishop_attacks(square, occupied) & (pieces[BISHOP] | pieces[QUEEN]) != 0
+            || rook_attacks(square, occupied) & (pieces[ROOK] | pieces[QUEEN]) != 0
+    }
+
+    pub fn in_check(&self, color: usize) -> bool {
+        self.king_square(color).is_some_and(|square| self.is_attacked(square, 1 - color))
+    }
+
+    // Drops castling rights whose king or rook is not on its home square
+    pub fn drop_stale_castling(&mut self) {
+        for (right, color, rook) in [
+            (WHITE_KING_SIDE, WHITE, 7),
+            (WHITE_QUEEN_SIDE, WHITE, 0),
+            (BLACK_KING_SIDE, BLACK, 63),
+            (BLACK_QUEEN_SIDE, BLACK, 56),
+        ] {
+            let king = if color == WHITE { 4 } else { 60 };
+            if self.pieces[color][KING] & bit(king) == 0 || self.pieces[color][ROOK] & bit(rook) == 0 {
+                self.castling &= !right;
+            }
+        }
+    }
+
+    // Moves of the side to move that may still leave its own king in check
+    pub fn pseudo_legal_moves(&self) -> Vec<Move> {
+        let mut moves = Vec::with_capacity(48);
+        let us = self.side;
+        let own = self.occupancy(us);
+        let enemy = self.occupancy(1 - us);
+        let occupied = own | enemy;
+        let (push, start_rank, last_rank): (i16, u8, u8) = if us == WHITE { (8, 1, 7) } else { (-8, 6, 0) };
+
+        let add_pawn_move = |moves: &mut Vec<Move>, from: u8, to: u8| {
+            if to / 8 == last_rank {
+                moves.extend(PROMOTION_KINDS.iter().map(|&kind| Move { from, to, promotion: Some(kind) }));
+            } else {
+                moves.push(Move::new(from, to));
+            }
+        };
+        let en_passant = self.en_passant.map_or(0, bit);
+        for from in squares(self.pieces[us][PAWN]) {
+            let one = from as i16 + push;
+            if !(0..64).contains(&one) {
+                continue;
+            }
+            if occupied & bit(one as u8) == 0 {
+                add_pawn_move(&mut moves, from, one as u8);
+                let two = one + push;
+                if from / 8 == start_rank && occupied & bit(two as u8) == 0 {
+                    moves.push(Move::new(from, two as u8));
+                }
+            }
+            for to in squares(pawn_attacks(us, from) & (enemy | en_passant)) {
+                add_pawn_move(&mut moves, from, to);
+            }
+        }
+
+        for kind in [KNIGHT, BISHOP, ROOK, QUEEN, KING] {
+            for from in squares(self.pieces[us][kind]) {
+                let attacks = match kind {
+                    KNIGHT => knight_attacks(from),
+                    BISHOP => bishop_attacks(from, occupied),
+                    ROOK => rook_attacks(from, occupied),
+                    QUEEN => queen_attacks(from, occupied),
+                    _ => king_attacks(from),
+                };
+                moves.extend(squares(attacks & !own).map(|to| Move::new(from, to)));
+            }
+        }
+
+        // the king may not castle out of, through or into check
+        let (base, king_side, queen_side) =
+            if us == WHITE { (0, WHITE_KING_SIDE, WHITE_QUEEN_SIDE) } else { (56, BLACK_KING_SIDE, BLACK_QUEEN_SIDE) };
+        let king_home = self.pieces[us][KING] & bit(base + 4) != 0;
+        if king_home && self.castling & (king_side | queen_side) != 0 && !self.is_attacked(base + 4, 1 - us) {
+            let rooks = self.pieces[us][ROOK];
+            if self.castling & king_side != 0
+                && rooks & bit(base + 7) != 0
+                && occupied & (bit(base + 5) | bit(base + 6)) == 0
+                && !self.is_attacked(base + 5, 1 - us)
+                && !self.is_attacked(base + 6, 1 - us)
+            {
+                moves.push(Move::new(base + 4, base + 6));
+            }
+            if self.castling & queen_side != 0
+                && rooks & bit(base) != 0
+                && occupied & (bit(base + 1) | bit(base + 2) | bit(base + 3)) == 0
+                && !self.is_attacked(base + 3, 1 - us)
+                && !self.is_attacked(base + 2, 1 - us)
+            {
+                moves.push(Move::new(base + 4, base + 2));
+            }
+        }
+        moves
+    }
+
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let mut moves = self.pseudo_legal_moves();
+        moves.retain(|&candidate| {
+            let mut after = *self;
+            after.make_move(candidate);
+            !after.in_check(self.side)
+        });
+        moves
+    }
+
+    // Plays the move without checking it and returns the kind of the captured piece. Castling
+    // moves the rook too, a pawn moving diagonally onto an empty square takes en passant.
+    pub fn make_move(&mut self, candidate: Move) -> Option<usize> {
+        let Move { from, to, promotion } = candidate;
+        let (color, kind) = self.remove(from)?;
+        let mut captured = self.remove(to).map(|(_, kind)| kind);
+        if kind == PAWN && from % 8 != to % 8 && captured.is_none() {
+            captured = self.remove(square(to % 8, from / 8)).map(|(_, kind)| kind);
+        }
+        if kind == KING && (to % 8).abs_diff(from % 8) == 2 {
+            let (rook_from, rook_to) = if to > from { (to + 1, to - 1) } else { (to - 2, to + 1) };
+            if let Some((rook_color, rook_kind)) = self.remove(rook_from) {
+                self.put(rook_color, rook_kind, rook_to);
+            }
+        }
+        let placed = match promotion {
+            Some(promotion) if kind == PAWN => promotion,
+            _ => kind,
+        };
+        self.pieces[color][placed] |= bit(to);
+
+        self.castling &= !(castling_rights_lost(from) | castling_rights_lost(to));
+        self.en_passant = (kind == PAWN && from.abs_diff(to) == 16).then_some((from + to) / 2);
+        self.halfmove_clock = if kind == PAWN || captured.is_some() { 0 } else { self.halfmove_clock + 1 };
+        if color == BLACK {
+            self.fullmove_number += 1;
+        }
+        self.side = 1 - color;
+        captured
+    }
+
+    // Number of leaf positions reachable in exactly `depth` plies
+    pub fn perft(&self, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let moves = self.legal_moves();
+        if depth == 1 {
+            return moves.len() as u64;
+        }
+        moves.iter()
+            .map(|&candidate| {
+                let mut after = *self;
+                after.make_move(candidate);
+                after.perft(depth - 1)
+            })
+            .sum()
+    }
+}
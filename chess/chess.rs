@@ -1,12 +1,12 @@
 #![allow(unused)]
 
-use std::collections::HashMap;
+mod bitboard;
+
+use bitboard::Position;
+use std::fmt;
 use std::io;
-use std::io::Result;
-use std::num;
-use std::ops::Range;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum PieceType {
     Pawn,
     Rook,
@@ -17,15 +17,53 @@ pub enum PieceType {
     Empty,
 }
 
+impl PieceType {
+    // Promotion choice as typed after a move, e.g. the `q` in `move 4 6 4 7 q`
+    pub fn from_promotion_str(string: &str) -> Option<PieceType> {
+        match string {
+            "q" | "Q" => Some(PieceType::Queen),
+            "r" | "R" => Some(PieceType::Rook),
+            "b" | "B" => Some(PieceType::Bishop),
+            "n" | "N" => Some(PieceType::Knight),
+            _ => None,
+        }
+    }
+
+    fn kind(self) -> Option<usize> {
+        match self {
+            PieceType::Pawn => Some(bitboard::PAWN),
+            PieceType::Knight => Some(bitboard::KNIGHT),
+            PieceType::Bishop => Some(bitboard::BISHOP),
+            PieceType::Rook => Some(bitboard::ROOK),
+            PieceType::Queen => Some(bitboard::QUEEN),
+            PieceType::King => Some(bitboard::KING),
+            PieceType::Empty => None,
+        }
+    }
+
+    fn from_kind(kind: usize) -> PieceType {
+        match kind {
+            bitboard::PAWN => PieceType::Pawn,
+            bitboard::KNIGHT => PieceType::Knight,
+            bitboard::BISHOP => PieceType::Bishop,
+            bitboard::ROOK => PieceType::Rook,
+            bitboard::QUEEN => PieceType::Queen,
+            _ => PieceType::King,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Piece {
     piece_type: PieceType,
     player: bool,
+    // Kings and rooks that moved can no longer castle
+    moved: bool,
 }
 
 impl Piece {
     pub fn new(piece_type: PieceType, player: bool) -> Self {
-        Piece { piece_type, player }
+        Piece { piece_type, player, moved: false }
     }
 
     pub fn piece_type(&self) -> PieceType {
@@ -34,71 +72,92 @@ impl Piece {
     pub fn player(&self) -> bool {
         self.player
     }
+}
 
-    pub fn to_string(self) -> String {
-        return if !self.player {
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = if !self.player {
             match self.piece_type {
-                PieceType::Pawn => "1P".to_string(),
-                PieceType::Rook => "1R".to_string(),
-                PieceType::Knight => "1N".to_string(),
-                PieceType::Bishop => "1B".to_string(),
-                PieceType::Queen => "1Q".to_string(),
-                PieceType::King => "1K".to_string(),
-                PieceType::Empty => " ".to_string(),
+                PieceType::Pawn => "1P",
+                PieceType::Rook => "1R",
+                PieceType::Knight => "1N",
+                PieceType::Bishop => "1B",
+                PieceType::Queen => "1Q",
+                PieceType::King => "1K",
+                PieceType::Empty => " ",
             }
         } else {
             match self.piece_type {
-                PieceType::Pawn => "2P︎".to_string(),
-                PieceType::Rook => "2R".to_string(),
-                PieceType::Knight => "2N".to_string(),
-                PieceType::Bishop => "2B".to_string(),
-                PieceType::Queen => "2Q".to_string(),
-                PieceType::King => "2K".to_string(),
-                PieceType::Empty => " ".to_string(),
+                PieceType::Pawn => "2P︎",
+                PieceType::Rook => "2R",
+                PieceType::Knight => "2N",
+                PieceType::Bishop => "2B",
+                PieceType::Queen => "2Q",
+                PieceType::King => "2K",
+                PieceType::Empty => " ",
             }
         };
+        write!(f, "{}", symbol)
     }
 }
 
+// A move as played, kept in the board's history
+#[derive(Copy, Clone, Debug)]
+pub struct MoveRecord {
+    from: (u8, u8),
+    to: (u8, u8),
+    piece_type: PieceType,
+    captured: Option<PieceType>,
+    promotion: Option<PieceType>,
+}
+
+#[derive(Clone, Default)]
 pub struct BoardState {
-    board: HashMap<u8, Piece>,
+    // Bitboards of the pieces; player 0 plays the white side, towards higher rows
+    position: Position,
+    // Squares holding a piece that has moved
+    moved: u64,
+    // Square skipped by a pawn that just advanced two squares
+    en_passant: Option<(u8, u8)>,
+    history: Vec<MoveRecord>,
 }
 
 impl BoardState {
     pub fn new() -> Self {
-        BoardState {
-            board: HashMap::new(),
-        }
+        Self::default()
     }
 
     pub fn reset(&mut self) {
-        self.board.clear();
-
-        self.board.insert(0, Piece::new(PieceType::Rook, false));
-        self.board.insert(1, Piece::new(PieceType::Knight, false));
-        self.board.insert(2, Piece::new(PieceType::Bishop, false));
-        self.board.insert(3, Piece::new(PieceType::King, false));
-        self.board.insert(4, Piece::new(PieceType::Queen, false));
-        self.board.insert(5, Piece::new(PieceType::Bishop, false));
-        self.board.insert(6, Piece::new(PieceType::Knight, false));
-        self.board.insert(7, Piece::new(PieceType::Rook, false));
+        self.position = Position::default();
+        self.moved = 0;
+        self.en_passant = None;
+        self.history.clear();
+
+        self.put(0, Piece::new(PieceType::Rook, false));
+        self.put(1, Piece::new(PieceType::Knight, false));
+        self.put(2, Piece::new(PieceType::Bishop, false));
+        self.put(3, Piece::new(PieceType::King, false));
+        self.put(4, Piece::new(PieceType::Queen, false));
+        self.put(5, Piece::new(PieceType::Bishop, false));
+        self.put(6, Piece::new(PieceType::Knight, false));
+        self.put(7, Piece::new(PieceType::Rook, false));
 
         for i in 8..16 {
-            self.board.insert(i, Piece::new(PieceType::Pawn, false));
+            self.put(i, Piece::new(PieceType::Pawn, false));
         }
 
         for i in 48..56 {
-            self.board.insert(i, Piece::new(PieceType::Pawn, true));
+            self.put(i, Piece::new(PieceType::Pawn, true));
         }
 
-        self.board.insert(56, Piece::new(PieceType::Rook, true));
-        self.board.insert(57, Piece::new(PieceType::Knight, true));
-        self.board.insert(58, Piece::new(PieceType::Bishop, true));
-        self.board.insert(59, Piece::new(PieceType::King, true));
-        self.board.insert(60, Piece::new(PieceType::Queen, true));
-        self.board.insert(61, Piece::new(PieceType::Bishop, true));
-        self.board.insert(62, Piece::new(PieceType::Knight, true));
-        self.board.insert(63, Piece::new(PieceType::Rook, true));
+        self.put(56, Piece::new(PieceType::Rook, true));
+        self.put(57, Piece::new(PieceType::Knight, true));
+        self.put(58, Piece::new(PieceType::Bishop, true));
+        self.put(59, Piece::new(PieceType::King, true));
+        self.put(60, Piece::new(PieceType::Queen, true));
+        self.put(61, Piece::new(PieceType::Bishop, true));
+        self.put(62, Piece::new(PieceType::Knight, true));
+        self.put(63, Piece::new(PieceType::Rook, true));
     }
 
     pub fn draw_board(&self) {
@@ -108,8 +167,8 @@ impl BoardState {
         for y in 0u8..8 {
             print!("{} ", y);
             for x in 0u8..8 {
-                if let Some(icon) = self.board.get(&(x + (8 * y))) {
-                    print!("| {} ", icon.to_string());
+                if let Some(icon) = self.piece_at(x + (8 * y)) {
+                    print!("| {} ", icon);
                 } else {
                     print!("|    ");
                 }
@@ -118,397 +177,264 @@ impl BoardState {
             println!("  -----------------------------------------");
         }
     }
+
+    pub fn history(&self) -> &[MoveRecord] {
+        &self.history
+    }
+
+    fn piece_at(&self, square: u8) -> Option<Piece> {
+        let (color, kind) = self.position.piece_at(square)?;
+        Some(Piece {
+            piece_type: PieceType::from_kind(kind),
+            player: color == bitboard::BLACK,
+            moved: self.moved & bitboard::bit(square) != 0,
+        })
+    }
+
+    fn put(&mut self, square: u8, piece: Piece) {
+        let Some(kind) = piece.piece_type.kind() else {
+            return;
+        };
+        self.position.put(color(piece.player), kind, square);
+        if piece.moved {
+            self.moved |= bitboard::bit(square);
+        } else {
+            self.moved &= !bitboard::bit(square);
+        }
+    }
+
+    fn take(&mut self, square: u8) -> Option<Piece> {
+        let piece = self.piece_at(square)?;
+        self.position.remove(square);
+        self.moved &= !bitboard::bit(square);
+        Some(piece)
+    }
 }
 
 impl BoardState {
     // returns type of piece at (x,y) as a enumerated type of PieceType
     pub fn piece_type_at(&self, x: u8, y: u8) -> PieceType {
-        return if let Some(piece) = self.board.get(&(x + (8 * y))) {
+        if let Some(piece) = self.piece_at(x + (8 * y)) {
             piece.piece_type()
         } else {
             PieceType::Empty
-        };
+        }
     }
 
     // returns the player (0 or 1) who owns the piece at (x, y) or -1 if no piece at (x, y)
     pub fn piece_player_at(&self, x: u8, y: u8) -> i8 {
-        if let Some(piece) = self.board.get(&(x + (8 * y))) {
-            if piece.player() {
-                1
-            } else {
-                0
-            }
-        } else {
-            return -1;
+        match self.piece_at(x + (8 * y)) {
+            Some(piece) if piece.player() => 1,
+            Some(_) => 0,
+            None => -1,
         }
     }
 
-    // returns true if piece successfully moved from (x, y) to (x2, y2)
+    // returns true if piece successfully moved from (x, y) to (x2, y2); pawns reaching
+    // the last rank become queens
     pub fn move_piece(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if let Some(p) = self.board.get(&(x + (8 * y))) {
-            match p.piece_type() {
-                PieceType::Empty => false,
-                PieceType::Pawn => self.move_pawn(x, y, x2, y2),
-                PieceType::Rook => self.move_rook(x, y, x2, y2),
-                PieceType::Knight => self.move_knight(x, y, x2, y2),
-                PieceType::Bishop => self.move_bishop(x, y, x2, y2),
-                PieceType::King => self.move_king(x, y, x2, y2),
-                PieceType::Queen => self.move_queen(x, y, x2, y2),
-            }
-        } else {
-            false
-        };
+        self.move_piece_with_promotion(x, y, x2, y2, PieceType::Queen)
     }
 
-    fn move_pawn(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_pawn(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
-
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        };
+    pub fn move_piece_with_promotion(&mut self, x: u8, y: u8, x2: u8, y2: u8, promotion: PieceType) -> bool {
+        if !matches!(promotion, PieceType::Queen | PieceType::Rook | PieceType::Bishop | PieceType::Knight) {
+            return false;
+        }
+        if !self.is_valid_move(x, y, x2, y2) {
+            return false;
+        }
+        self.apply_move(x, y, x2, y2, promotion);
+        true
     }
 
-    fn move_rook(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_rook(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
-
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+    // Moves without validation, handling the side effects of castling, en passant and promotion
+    fn apply_move(&mut self, x: u8, y: u8, x2: u8, y2: u8, promotion: PieceType) {
+        let Some(mut piece) = self.take(x + (8 * y)) else {
+            return;
         };
-    }
-
-    fn move_knight(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_knight(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
+        let mut captured = self.take(x2 + (8 * y2)).map(|p| p.piece_type());
 
-                true
-            } else {
-                false
+        if piece.piece_type == PieceType::Pawn && x != x2 && captured.is_none() {
+            // en passant takes the pawn that passed the destination square
+            captured = self.take(x2 + (8 * y)).map(|p| p.piece_type());
+        }
+        if piece.piece_type == PieceType::King && abs_dif(x, x2) == 2 {
+            let (rook_x, rook_x2) = if x2 > x { (7, x2 - 1) } else { (0, x2 + 1) };
+            if let Some(mut rook) = self.take(rook_x + (8 * y)) {
+                rook.moved = true;
+                self.put(rook_x2 + (8 * y), rook);
             }
-        } else {
-            false
-        };
-    }
-
-    fn move_bishop(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_bishop(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
+        }
 
-                true
-            } else {
-                false
-            }
+        let mut promoted = None;
+        if piece.piece_type == PieceType::Pawn && (y2 == 0 || y2 == 7) {
+            piece.piece_type = promotion;
+            promoted = Some(promotion);
+        }
+        self.en_passant = if piece.piece_type == PieceType::Pawn && abs_dif(y, y2) == 2 {
+            Some((x, y.min(y2) + 1))
         } else {
-            false
+            None
         };
+        self.history.push(MoveRecord {
+            from: (x, y),
+            to: (x2, y2),
+            piece_type: if promoted.is_some() { PieceType::Pawn } else { piece.piece_type },
+            captured,
+            promotion: promoted,
+        });
+        piece.moved = true;
+        self.put(x2 + (8 * y2), piece);
     }
 
-    fn move_king(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_king(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
-
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+    pub fn is_valid_move(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
+        // if movement outside of board bounds or onto own piece
+        if x > 7 || y > 7 || x2 > 7 || y2 > 7 {
+            return false;
+        }
+        let Some(p) = self.piece_at(x + (8 * y)) else {
+            return false;
+        };
+        if self.piece_player_at(x2, y2) == self.piece_player_at(x, y) {
+            return false;
+        }
+        let valid = match p.piece_type() {
+            PieceType::Empty => false,
+            PieceType::Pawn => self.is_valid_move_pawn(x, y, x2, y2),
+            PieceType::Rook => self.is_valid_move_rook(x, y, x2, y2),
+            PieceType::Knight => self.is_valid_move_knight(x, y, x2, y2),
+            PieceType::Bishop => self.is_valid_move_bishop(x, y, x2, y2),
+            PieceType::King => self.is_valid_move_king(x, y, x2, y2),
+            PieceType::Queen => self.is_valid_move_queen(x, y, x2, y2),
         };
+        // a move may not leave the own king in check
+        valid && {
+            let mut after = self.clone();
+            after.apply_move(x, y, x2, y2, PieceType::Queen);
+            !after.is_in_check(p.player())
+        }
     }
 
-    fn move_queen(&mut self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if self.is_valid_move_queen(x, y, x2, y2) {
-            if let Some(temp) = self.board.remove(&(x + (8 * y))) {
-                self.board.remove(&(x2 + (8 * y2)));
-                self.board.insert((x2 + (8 * y2)), temp);
+    pub fn is_in_check(&self, player: bool) -> bool {
+        self.position.in_check(color(player))
+    }
 
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        };
+    pub fn is_square_attacked(&self, x: u8, y: u8, by_player: bool) -> bool {
+        self.position.is_attacked(x + (8 * y), color(by_player))
     }
 
-    pub fn is_valid_move(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        return if let Some(p) = self.board.get(&(x + (8 * y))) {
-            match p.piece_type() {
-                PieceType::Empty => false,
-                PieceType::Pawn => self.is_valid_move_pawn(x, y, x2, y2),
-                PieceType::Rook => self.is_valid_move_rook(x, y, x2, y2),
-                PieceType::Knight => self.is_valid_move_knight(x, y, x2, y2),
-                PieceType::Bishop => self.is_valid_move_bishop(x, y, x2, y2),
-                PieceType::King => self.is_valid_move_king(x, y, x2, y2),
-                PieceType::Queen => self.is_valid_move_queen(x, y, x2, y2),
+    // true if every square strictly between origin and destination on a straight or
+    // diagonal line is empty
+    fn is_path_clear(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
+        if (x, y) == (x2, y2) {
+            return false;
+        }
+        let (dx, dy) = ((x2 as i8 - x as i8).signum(), (y2 as i8 - y as i8).signum());
+        let (mut cx, mut cy) = (x as i8 + dx, y as i8 + dy);
+        while (cx, cy) != (x2 as i8, y2 as i8) {
+            if self.piece_type_at(cx as u8, cy as u8) != PieceType::Empty {
+                return false;
             }
-        } else {
-            false
-        };
+            cx += dx;
+            cy += dy;
+        }
+        true
     }
 
     fn is_valid_move_pawn(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
         // can move forward 1 or 2 if at starting location and NOT blocked by piece
-        // can move diagonally forward 1 ONLY IF blocked by piece of enemy player
+        // can move diagonally forward 1 ONLY IF taking an enemy piece, possibly en passant
+        let Some(p) = self.piece_at(x + (8 * y)) else {
+            return false;
+        };
+        let Some(one_ahead) = forward(p.player(), y) else {
+            return false;
+        };
+        let start_row = if p.player() { 6 } else { 1 };
 
-        if let Some(p) = self.board.get(&(x + (8 * y))) {
-            if x2 < 0 || x2 > 7 || y2 < 0 || y2 > 7 {
+        if x == x2 {
+            if self.piece_type_at(x, one_ahead) != PieceType::Empty {
                 return false;
-            } // if movement outside of board bounds
-
-            if p.player() == false
-            // player 0
-            {
-                // if move forward
-                if x == x2 {
-                    // if moving forward 1 piece
-                    if y2 == y + 1 {
-                        return self.piece_type_at(x, y + 1) == PieceType::Empty;
-                    }
-                    // if moving forward 2 piece
-                    else if y2 == y + 2 && y == 1 {
-                        self.piece_type_at(x, y + 1) == PieceType::Empty
-                            && self.piece_type_at(x, y + 2) == PieceType::Empty
-                    }
-                    // if not moving forward 1 or 2 spaces
-                    else {
-                        false
-                    }
-                }
-                // if attacking diagonally
-                else if abs_dif(x, x2) == 1 && y + 1 == y2 {
-                    return self.piece_player_at(x2, y2) == 1;
-                } else {
-                    false
-                }
-            } else
-            // player 1
-            {
-                // if move forward
-                if x == x2 {
-                    // if moving forward 1 piece
-                    if y2 == y - 1 {
-                        self.piece_type_at(x, y - 1) == PieceType::Empty
-                    }
-                    // if moving forward 2 piece
-                    else if y2 == y - 2 && y == 6 {
-                        self.piece_type_at(x, y - 1) == PieceType::Empty
-                            && self.piece_type_at(x, y - 2) == PieceType::Empty
-                    }
-                    // if not moving forward 1 or 2 spaces
-                    else {
-                        false
-                    }
-                }
-                // if attacking diagonally
-                else if abs_dif(x, x2) == 1 && y - 1 == y2 {
-                    return self.piece_player_at(x2, y2) == 1;
-                } else {
-                    false
-                }
             }
+            y2 == one_ahead
+                || (y == start_row
+                    && forward(p.player(), one_ahead) == Some(y2)
+                    && self.piece_type_at(x, y2) == PieceType::Empty)
+        } else if abs_dif(x, x2) == 1 && y2 == one_ahead {
+            let enemy = if p.player() { 0 } else { 1 };
+            // en passant only takes an enemy pawn that just passed the destination square
+            self.piece_player_at(x2, y2) == enemy
+                || (self.en_passant == Some((x2, y2))
+                    && self.piece_player_at(x2, y) == enemy
+                    && self.piece_type_at(x2, y) == PieceType::Pawn)
         } else {
             false
         }
     }
 
     fn is_valid_move_rook(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        // can move 4 ways, optionally move into piece owned by enemy player
-
-        if x2 < 0 || x2 > 7 || y2 < 0 || y2 > 7 {
-            return false;
-        } // if movement outside of board bounds
-
-        return if x == x2
-        // if vertically aligned
-        {
-            for i in y + 1..y2 {
-                // if non-empty space encountered before destination, then invalid move
-                if self.piece_type_at(x, i) != PieceType::Empty {
-                    return false;
-                }
-            }
-
-            // if path to destination is empty AND destination is empty OR enemy, then move is valid
-            if self.piece_player_at(x2, y2) != self.piece_player_at(x, y) {
-                true
-            } else {
-                false
-            }
-        } else if y == y2
-        // if horizontally aligned
-        {
-            for i in x + 1..x2 {
-                // if non-empty space encountered before destination, then invalid move
-                if self.piece_type_at(y, i) != PieceType::Empty {
-                    return false;
-                }
-            }
-
-            // if path to destination is empty AND destination is empty OR enemy, then move is valid
-            return if self.piece_player_at(x2, y2) != self.piece_player_at(x, y) {
-                true
-            } else {
-                false
-            };
-        } else {
-            false
-        }; // if not aligned, false
-
-        // if x = x2 then moving along y axis
-        //      all spaces from between y and y2 are empty, and (x2, y2) is empty or enemy
-        //          true
-        //      else
-        //          false
-        // if y = y2 then moving along x axis
-        //      all spaces from between x and x2 are empty, and (x2, y2) is empty or enemy
-        //          true
-        //      else
-        //          false
-        // if does not align with x or y axis, invalid
+        // can move 4 ways until contact is made, optionally move into piece owned by enemy player
+        (x == x2 || y == y2) && self.is_path_clear(x, y, x2, y2)
     }
 
     fn is_valid_move_knight(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        if x2 < 0 || x2 > 7 || y2 < 0 || y2 > 7 {
-            return false;
-        } // if movement outside of board bounds
-
-        // can move in L pattern in any direction, jumping over pieces, can only take enemy player piece
-
-        // if L pattern away
-        return if (abs_dif(x, x2) == 1 && abs_dif(y, y2) == 2)
-            || (abs_dif(x, x2) == 2 && abs_dif(y, y2) == 1)
-        {
-            if self.piece_player_at(x2, y2) != self.piece_player_at(x, y) {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        };
-
-        // if (x2, y2) is L jump away, and destination is empty or enemy
-        //      true
-        // else
-        //      false
+        // can move in L pattern in any direction, jumping over pieces
+        is_knight_jump(x, y, x2, y2)
     }
 
     fn is_valid_move_bishop(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        if x2 < 0 || x2 > 7 || y2 < 0 || y2 > 7 {
-            return false;
-        }
-        // if movement outside of board bounds
-        else if x == x2 && y == y2 {
-            return false;
-        } // if destination == origin
-
-        if abs_dif(x, x2) == abs_dif(y, y2) {
-            // if every space between origin and destination is empty
-
-            let mut temp_x: Range<u8>;
-            let mut temp_y: Range<u8>;
-
-            if x2 < x {
-                temp_x = (x - 1)..x2;
-            } else {
-                temp_x = (x + 1)..x2;
-            }
-
-            if y2 < y {
-                temp_y = (y - 1)..y2;
-            } else {
-                temp_y = (y + 1)..y2;
-            }
-
-            for (X, Y) in temp_x.zip(temp_y)
-            // check every square between origin and destination
-            {
-                if self.piece_type_at(X, Y) != PieceType::Empty {
-                    return false;
-                }
-            }
-
-            return if self.piece_player_at(x2, y2) != self.piece_player_at(x, y) {
-                true
-            } else {
-                false
-            };
-
-            // AND if destination is empty or enemy
-        }
-
-        // can move 4 ways diagonally, until contact with piece is made, can only take enemy player piece
-
-        false
+        // can move 4 ways diagonally, until contact with piece is made
+        abs_dif(x, x2) == abs_dif(y, y2) && self.is_path_clear(x, y, x2, y2)
     }
 
     fn is_valid_move_king(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
-        // can move 8 ways, one space, can only take enemy player piece
+        // can move 8 ways, one space, or castle by moving two spaces towards a rook
+        if abs_dif(x, x2) <= 1 && abs_dif(y, y2) <= 1 {
+            return true;
+        }
+        y == y2 && abs_dif(x, x2) == 2 && self.can_castle(x, y, x2)
+    }
 
-        if x2 < 0 || x2 > 7 || y2 < 0 || y2 > 7 {
+    // Neither king nor rook has moved, the squares between them are empty and the king is
+    // not in check, does not pass through an attacked square and does not land on one
+    fn can_castle(&self, x: u8, y: u8, x2: u8) -> bool {
+        let Some(king) = self.piece_at(x + (8 * y)) else {
             return false;
-        } // if movement outside of board bounds
-
-        return if abs_dif(x, x2) <= 1 && abs_dif(y, y2) <= 1
-        // if within 1 square
-        {
-            if self.piece_player_at(x2, y2) != self.piece_player_at(x, y)
-            // if destination is empty or enemy piece
-            {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
         };
-
-        // if (x2, y2) is within 1 square, and is empty or enemy
-        //      true
-        // else
-        //      false
+        let rook_x = if x2 > x { 7 } else { 0 };
+        let rook_ready = matches!(
+            self.piece_at(rook_x + (8 * y)),
+            Some(rook) if rook.piece_type == PieceType::Rook && rook.player == king.player && !rook.moved
+        );
+        let passed = if x2 > x { x + 1 } else { x - 1 };
+        !king.moved
+            && rook_ready
+            && self.is_path_clear(x, y, rook_x, y)
+            && [x, passed, x2].iter().all(|&cx| !self.is_square_attacked(cx, y, !king.player))
     }
 
     fn is_valid_move_queen(&self, x: u8, y: u8, x2: u8, y2: u8) -> bool {
         // can move 8 ways, until contact is made, can only take enemy player piece
+        self.is_valid_move_bishop(x, y, x2, y2) || self.is_valid_move_rook(x, y, x2, y2)
+    }
+}
 
-        return if self.is_valid_move_bishop(x, y, x2, y2) || self.is_valid_move_rook(x, y, x2, y2) {
-            true
-        } else {
-            false
-        };
+// Bitboard color of a player
+fn color(player: bool) -> usize {
+    if player { bitboard::BLACK } else { bitboard::WHITE }
+}
 
-        // if (x2, y2) is along horizontal/vertical
-        //      reuse Rook logic
-        // if (x2, y2) is along diagonal
-        //      reuse bishop logic
-        // else
-        //      false
-    }
+// The row in front of a pawn of the given player standing on row y
+fn forward(player: bool, y: u8) -> Option<u8> {
+    if player { y.checked_sub(1) } else { Some(y + 1).filter(|&y| y <= 7) }
+}
+
+fn is_knight_jump(x: u8, y: u8, x2: u8, y2: u8) -> bool {
+    (abs_dif(x, x2) == 1 && abs_dif(y, y2) == 2) || (abs_dif(x, x2) == 2 && abs_dif(y, y2) == 1)
 }
 
 fn abs_dif(x: u8, y: u8) -> u8 {
-    return (x as i16 - y as i16).abs() as u8;
+    x.abs_diff(y)
 }
 
 fn main() {
@@ -517,7 +443,7 @@ fn main() {
     board.reset();
     board.draw_board();
 
-    let help_message = "move <origin X> <origin Y> <destination X> <destination Y>\n\
+    let help_message = "move <origin X> <origin Y> <destination X> <destination Y> [q|r|b|n]\n\
         valid_move <origin X> <origin Y> <destination X> <destination Y>\n\
         draw\n\
         reset\n\
@@ -541,7 +467,7 @@ fn main() {
 
         let vector: Vec<&str> = input.split(' ').collect();
 
-        match vector.get(0) {
+        match vector.first() {
             Some(&"move") => {
                 call_move(&mut board, &vector);
             }
@@ -568,74 +494,172 @@ fn main() {
             }
         }
     }
-
-    return;
 }
 
-fn call_move(bs: &mut BoardState, s: &Vec<&str>) -> bool {
-    if s.len() != 5 {
-        for i in s {
-            println!("{}", i);
-        }
-    }
-
+// parses the four coordinates following the command name
+fn parse_coordinates(s: &[&str]) -> Option<[u8; 4]> {
     let mut array: [u8; 4] = [0; 4];
-
     for i in 1..5 {
-        if let Some(slice) = s.get(i) {
-            if let Ok(val) = slice.parse::<u8>() {
-                array[i - 1] = val;
-            } else {
-                println!("usage: move <origin X> <origin Y> <destination X> <destination Y>");
+        array[i - 1] = s.get(i)?.parse::<u8>().ok()?;
+    }
+    Some(array)
+}
+
+fn call_move(bs: &mut BoardState, s: &[&str]) -> bool {
+    let usage = "usage: move <origin X> <origin Y> <destination X> <destination Y> [q|r|b|n]";
+    let Some(array) = parse_coordinates(s) else {
+        println!("{}", usage);
+        return false;
+    };
+    let promotion = match s.get(5) {
+        None => PieceType::Queen,
+        Some(choice) => match PieceType::from_promotion_str(choice) {
+            Some(promotion) => promotion,
+            None => {
+                println!("{}", usage);
                 return false;
             }
-        } else {
-            println!("usage: move <origin X> <origin Y> <destination X> <destination Y>");
-            return false;
-        } // if failed to parse term, return false
-    }
+        },
+    };
 
-    return if bs.move_piece(array[0], array[1], array[2], array[3]) {
+    if bs.move_piece_with_promotion(array[0], array[1], array[2], array[3], promotion) {
         println!("successfully moved");
         true
     } else {
         println!("invalid move");
         false
+    }
+}
+
+fn call_valid_move(bs: &mut BoardState, s: &[&str]) -> bool {
+    let Some(array) = parse_coordinates(s) else {
+        println!("usage: is_valid_move <origin X> <origin Y> <destination X> <destination Y>");
+        return false;
     };
+
+    if bs.is_valid_move(array[0], array[1], array[2], array[3]) {
+        println!("valid move");
+        true
+    } else {
+        println!("invalid move");
+        false
+    }
 }
 
-fn call_valid_move(bs: &mut BoardState, s: &Vec<&str>) -> bool {
-    if s.len() != 5 {
-        for i in s {
-            println!("{}", i);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board_with(pieces: &[(PieceType, bool, u8, u8)]) -> BoardState {
+        let mut board = BoardState::new();
+        for &(piece_type, player, x, y) in pieces {
+            board.put(x + 8 * y, Piece::new(piece_type, player));
         }
+        board
     }
 
-    let mut array: [u8; 4] = [0; 4];
+    #[test]
+    fn test_sliding_pieces_are_blocked_both_ways() {
+        let mut board = BoardState::new();
+        board.reset();
+        assert!(!board.is_valid_move(0, 0, 0, 3));
+        assert!(!board.is_valid_move(2, 0, 4, 2));
+        assert!(board.move_piece(0, 1, 0, 3));
+        assert!(board.move_piece(0, 0, 0, 2));
+        assert!(board.move_piece(0, 2, 7, 2));
+        // back along the rank, not through the pawns
+        assert!(board.is_valid_move(7, 2, 1, 2));
+        assert!(!board.is_valid_move(7, 2, 7, 0));
+    }
 
-    for i in 1..5 {
-        if let Some(slice) = s.get(i) {
-            if let Ok(val) = slice.parse::<u8>()
-            // parse str to u8
-            {
-                array[i - 1] = val;
-            } else {
-                println!(
-                    "usage: is_valid_move <origin X> <origin Y> <destination X> <destination Y>"
-                );
-                return false;
-            }
-        } else {
-            println!("usage: is_valid_move <origin X> <origin Y> <destination X> <destination Y>");
-            return false;
-        }
+    #[test]
+    fn test_castling() {
+        let mut board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Rook, false, 0, 0),
+            (PieceType::Rook, false, 7, 0),
+            (PieceType::King, true, 3, 7),
+            (PieceType::Rook, true, 4, 5),
+        ]);
+        // the rook on 4 attacks the square the king would cross towards 7
+        assert!(!board.is_valid_move(3, 0, 5, 0));
+        assert!(board.move_piece(3, 0, 1, 0));
+        assert_eq!(board.piece_type_at(2, 0), PieceType::Rook);
+        assert_eq!(board.piece_type_at(0, 0), PieceType::Empty);
+
+        let mut board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Rook, false, 0, 0),
+            (PieceType::King, true, 3, 7),
+        ]);
+        board.move_piece(0, 0, 0, 1);
+        board.move_piece(0, 1, 0, 0);
+        assert!(!board.is_valid_move(3, 0, 1, 0));
     }
 
-    return if bs.is_valid_move(array[0], array[1], array[2], array[3]) {
-        println!("valid move");
-        true
-    } else {
-        println!("invalid move");
-        false
-    };
+    #[test]
+    fn test_en_passant() {
+        let mut board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Pawn, false, 4, 4),
+            (PieceType::Pawn, true, 5, 6),
+            (PieceType::Pawn, true, 3, 6),
+            (PieceType::King, true, 0, 7),
+        ]);
+        assert!(board.move_piece(5, 6, 5, 4));
+        assert!(board.move_piece(4, 4, 5, 5));
+        assert_eq!(board.piece_type_at(5, 4), PieceType::Empty);
+        assert_eq!(board.history().last().unwrap().captured, Some(PieceType::Pawn));
+
+        // only right after the double step
+        let mut board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Pawn, false, 4, 4),
+            (PieceType::Pawn, true, 3, 6),
+            (PieceType::King, true, 0, 7),
+        ]);
+        assert!(board.move_piece(3, 6, 3, 4));
+        assert!(board.is_valid_move(4, 4, 3, 5));
+        assert!(board.move_piece(3, 0, 3, 1));
+        assert!(board.move_piece(0, 7, 0, 6));
+        assert!(!board.is_valid_move(4, 4, 3, 5));
+    }
+
+    #[test]
+    fn test_en_passant_needs_an_enemy_pawn() {
+        let mut board = BoardState::new();
+        board.reset();
+        assert!(board.move_piece(3, 1, 3, 3));
+        // the square behind white's own double step is not a capture for white
+        assert!(!board.is_valid_move(2, 1, 3, 2));
+        assert!(!board.is_valid_move(4, 1, 3, 2));
+    }
+
+    #[test]
+    fn test_promotion() {
+        let mut board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Pawn, false, 6, 6),
+            (PieceType::Pawn, false, 1, 6),
+            (PieceType::King, true, 3, 7),
+        ]);
+        assert!(board.move_piece(6, 6, 6, 7));
+        assert_eq!(board.piece_type_at(6, 7), PieceType::Queen);
+        assert!(!board.move_piece_with_promotion(1, 6, 1, 7, PieceType::King));
+        assert!(board.move_piece_with_promotion(1, 6, 1, 7, PieceType::Knight));
+        assert_eq!(board.piece_type_at(1, 7), PieceType::Knight);
+        assert_eq!(board.history()[1].promotion, Some(PieceType::Knight));
+    }
+
+    #[test]
+    fn test_pinned_piece_cannot_move() {
+        let board = board_with(&[
+            (PieceType::King, false, 3, 0),
+            (PieceType::Bishop, false, 3, 1),
+            (PieceType::Rook, true, 3, 7),
+            (PieceType::King, true, 0, 7),
+        ]);
+        assert!(!board.is_valid_move(3, 1, 4, 2));
+        assert!(board.is_valid_move(3, 0, 4, 0));
+    }
 }
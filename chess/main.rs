@@ -1,5 +1,15 @@
 #![allow(unused)]
 
+mod bitboard;
+
+use bitboard::{Move, Position};
+use clap::{Arg, ArgAction, Command};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 use strum_macros::Display;
 
 
@@ -31,7 +41,7 @@ impl PieceSymbol for Piece {
 }
 
 impl PieceType {
-    fn new(&self, position: ChessPosition, color: Color) -> Piece {
+    fn create(self, position: ChessPosition, color: Color) -> Piece {
         match self {
             PieceType::King => Piece::King(King::new(position, color)),
             PieceType::Queen => Piece::Queen(Queen::new(position, color)),
@@ -41,8 +51,76 @@ impl PieceType {
             PieceType::Pawn => Piece::Pawn(Pawn::new(position, color)),
         }
     }
+
+    // Promotion choice as typed after a move, e.g. the `q` in `e7 e8 q`
+    fn from_promotion_char(c: char) -> Option<PieceType> {
+        match c.to_ascii_lowercase() {
+            'q' => Some(PieceType::Queen),
+            'r' => Some(PieceType::Rook),
+            'b' => Some(PieceType::Bishop),
+            'n' => Some(PieceType::Knight),
+            _ => None,
+        }
+    }
+
+    // Letter used in FEN and SAN, lowercase
+    fn fen_char(self) -> char {
+        match self {
+            PieceType::King => 'k',
+            PieceType::Queen => 'q',
+            PieceType::Rook => 'r',
+            PieceType::Bishop => 'b',
+            PieceType::Knight => 'n',
+            PieceType::Pawn => 'p',
+        }
+    }
+
+    // Index of the kind in the bitboards
+    fn kind(self) -> usize {
+        match self {
+            PieceType::Pawn => bitboard::PAWN,
+            PieceType::Knight => bitboard::KNIGHT,
+            PieceType::Bishop => bitboard::BISHOP,
+            PieceType::Rook => bitboard::ROOK,
+            PieceType::Queen => bitboard::QUEEN,
+            PieceType::King => bitboard::KING,
+        }
+    }
+
+    fn from_kind(kind: usize) -> PieceType {
+        match kind {
+            bitboard::PAWN => PieceType::Pawn,
+            bitboard::KNIGHT => PieceType::Knight,
+            bitboard::BISHOP => PieceType::Bishop,
+            bitboard::ROOK => PieceType::Rook,
+            bitboard::QUEEN => PieceType::Queen,
+            _ => PieceType::King,
+        }
+    }
+
+    // Material worth in centipawns; the king is never traded
+    fn value(self) -> i32 {
+        match self {
+            PieceType::Pawn => 100,
+            PieceType::Knight => 320,
+            PieceType::Bishop => 330,
+            PieceType::Rook => 500,
+            PieceType::Queen => 900,
+            PieceType::King => 0,
+        }
+    }
+
+    fn from_fen_char(c: char) -> Option<PieceType> {
+        match c.to_ascii_lowercase() {
+            'k' => Some(PieceType::King),
+            'p' => Some(PieceType::Pawn),
+            other => PieceType::from_promotion_char(other),
+        }
+    }
 }
 
+const PROMOTION_CHOICES: [PieceType; 4] = [PieceType::Queen, PieceType::Rook, PieceType::Bishop, PieceType::Knight];
+
 const CHESS_BOARD_SIZE: usize = 8;
 
 const INITIAL_PIECE_SET_SINGLE: [(PieceType, i32, i32); 16] = [
@@ -118,12 +196,12 @@ enum Piece {
 impl PieceProperties for Piece {
     fn get_piece_color(&self) -> Color {
         match self {
-            Piece::King(king) => king.color.clone(),
-            Piece::Queen(queen) => queen.color.clone(),
-            Piece::Knight(knight) => knight.color.clone(),
-            Piece::Rook(rook) => rook.color.clone(),
-            Piece::Bishop(bishop) => bishop.color.clone(),
-            Piece::Pawn(pawn) => pawn.color.clone()
+            Piece::King(king) => king.color,
+            Piece::Queen(queen) => queen.color,
+            Piece::Knight(knight) => knight.color,
+            Piece::Rook(rook) => rook.color,
+            Piece::Bishop(bishop) => bishop.color,
+            Piece::Pawn(pawn) => pawn.color
         }
     }
 
@@ -139,6 +217,25 @@ impl PieceProperties for Piece {
     }
 }
 
+impl Piece {
+    // Uppercase for white, lowercase for black
+    fn fen_char(&self) -> char {
+        let c = self.get_piece_type().fen_char();
+        if self.get_piece_color() == Color::WHITE { c.to_ascii_uppercase() } else { c }
+    }
+
+    fn get_piece_type(&self) -> PieceType {
+        match self {
+            Piece::King(_) => PieceType::King,
+            Piece::Queen(_) => PieceType::Queen,
+            Piece::Knight(_) => PieceType::Knight,
+            Piece::Rook(_) => PieceType::Rook,
+            Piece::Bishop(_) => PieceType::Bishop,
+            Piece::Pawn(_) => PieceType::Pawn,
+        }
+    }
+}
+
 
 impl PieceMaker for Piece {
     fn get_threatened_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
@@ -149,7 +246,6 @@ impl PieceMaker for Piece {
             Piece::Pawn(pawn) => pawn.get_threatened_positions(board),
             Piece::Rook(rook) => rook.get_threatened_positions(board),
             Piece::Queen(queen) => queen.get_threatened_positions(board),
-            _ => unreachable!()
         }
     }
 
@@ -162,7 +258,6 @@ impl PieceMaker for Piece {
             Piece::Pawn(pawn) => pawn.get_movable_positions(board),
             Piece::Rook(rook) => rook.get_movable_positions(board),
             Piece::Queen(queen) => queen.get_movable_positions(board),
-            _ => unreachable!()
         }
     }
 
@@ -174,7 +269,6 @@ impl PieceMaker for Piece {
             Piece::Rook(rook) => rook.move_to(target_position),
             Piece::Bishop(bishop) => bishop.move_to(target_position),
             Piece::Pawn(pawn) => pawn.move_to(target_position),
-            _ => unreachable!()
         }
     }
 }
@@ -182,7 +276,8 @@ impl PieceMaker for Piece {
 #[derive(Debug, Clone, Copy)]
 struct King {
     position: ChessPosition,
-    color: Color
+    color: Color,
+    moved: bool,
 }
 
 impl King {
@@ -200,16 +295,46 @@ impl King {
     fn new(position: ChessPosition, color: Color) -> Self {
         King {
             position,
-            color
+            color,
+            moved: false,
         }
     }
 
+    // Squares the king can castle to: neither king nor rook has moved, nothing stands
+    // between them and the king is not in check and does not cross an attacked square
+    fn get_castling_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
+        let mut positions = Vec::new();
+        let home_rank = if self.color == Color::WHITE { 0 } else { board.size as i32 - 1 };
+        let enemy = opponent(self.color);
+        if self.moved
+            || self.position != ChessPosition::new(4, home_rank)
+            || board.is_attacked(self.position, enemy)
+        {
+            return positions;
+        }
+        for (rook_x, step) in [(board.size as i32 - 1, 1), (0, -1)] {
+            let rook_ready = matches!(
+                board.get_piece(ChessPosition::new(rook_x, home_rank)),
+                Some(Piece::Rook(rook)) if rook.color == self.color && !rook.moved
+            );
+            let mut between = (self.position.x_coord.min(rook_x) + 1)..self.position.x_coord.max(rook_x);
+            if !rook_ready || between.any(|x| board.get_piece(ChessPosition::new(x, home_rank)).is_some()) {
+                continue;
+            }
+            let passed = ChessPosition::new(self.position.x_coord + step, home_rank);
+            let target = ChessPosition::new(self.position.x_coord + 2 * step, home_rank);
+            if !board.is_attacked(passed, enemy) && !board.is_attacked(target, enemy) {
+                positions.push(target);
+            }
+        }
+        positions
+    }
 }
 
 impl PieceMaker for King {
     fn move_to(&mut self, target_position: ChessPosition) {
+        self.moved = true;
         self.position = target_position;
-        // self.board_handle.as_ref().unwrap().register_king_position(target_position, self.color);
     }
 
     fn get_threatened_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
@@ -229,7 +354,9 @@ impl PieceMaker for King {
     }
 
     fn get_movable_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
-        self.get_threatened_positions(board)
+        let mut positions = self.get_threatened_positions(board);
+        positions.extend(self.get_castling_positions(board));
+        positions
     }
 
 }
@@ -253,8 +380,6 @@ struct Pawn {
 }
 
 impl Pawn {
-    const SPOT_INCREMENTS_MOVE: [(i32, i32); 2] = [(0, 1), (0, 0)];
-    const SPOT_INCREMENTS_MOVE_FIRST: [(i32, i32); 2] = [(0, 1), (0, 2)];
     const SPOT_INCREMENTS_TAKE: [(i32, i32); 2] = [(-1, 1), (1, 1)];
 
     fn new(position: ChessPosition, color: Color) -> Self {
@@ -265,24 +390,27 @@ impl Pawn {
         }
     }
 
+    fn direction(&self) -> i32 {
+        if self.color == Color::WHITE { 1 } else { -1 }
+    }
+
+    // Pawns may advance two squares from the rank they start on
+    fn on_start_rank(&self, board: &ChessBoard) -> bool {
+        let start_rank = if self.color == Color::WHITE { 1 } else { board.size as i32 - 2 };
+        self.position.y_coord == start_rank
+    }
 }
 
 impl PieceMaker for Pawn {
     
     fn get_threatened_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
         let mut positions = Vec::new();
-        let increments = Pawn::SPOT_INCREMENTS_TAKE;
-        for increment in increments.iter() {
-            let dy = if self.color == Color::WHITE {
-                increment.1
-            } else {
-                -increment.1
-            };
+        for increment in Pawn::SPOT_INCREMENTS_TAKE.iter() {
             if let Some(position) = board.spot_search_threat(
                 self.position, 
                 &self.color, 
                 increment.0, 
-                dy,
+                increment.1 * self.direction(),
                 false, false
             ) {
                 positions.push(position);
@@ -293,41 +421,29 @@ impl PieceMaker for Pawn {
 
     fn get_movable_positions(&self, board: &ChessBoard) -> Vec<ChessPosition> {
         let mut positions = Vec::new();
-        let increments = if self.moved {
-            Pawn::SPOT_INCREMENTS_MOVE
-        } else {
-            Pawn::SPOT_INCREMENTS_MOVE_FIRST
-        };
-        for increment in increments.iter() {
-            let dy = if self.color == Color::WHITE {
-                increment.1
-            } else {
-                -increment.1
-            };
-            if let Some(position) = board.spot_search_threat(
-                self.position, 
-                &self.color, 
-                increment.0, 
-                dy, 
-                true, false ) {
+        let dy = self.direction();
+        if let Some(position) = board.spot_search_threat(self.position, &self.color, 0, dy, false, true) {
+            positions.push(position);
+            if self.on_start_rank(board)
+                && let Some(position) = board.spot_search_threat(self.position, &self.color, 0, 2 * dy, false, true)
+            {
                 positions.push(position);
             }
         }
 
-        let increments = Pawn::SPOT_INCREMENTS_TAKE;
-        for increment in increments.iter() {
-            let dy = if self.color == Color::WHITE {
-                increment.1
-            } else {
-                -increment.1
-            };
+        for increment in Pawn::SPOT_INCREMENTS_TAKE.iter() {
             if let Some(position) = board.spot_search_threat(
                 self.position, 
                 &self.color, 
                 increment.0, 
-                dy, false, true) {
+                increment.1 * dy, true, false) {
                 positions.push(position);
             }
+            // en passant onto the square the enemy pawn skipped
+            let target = ChessPosition::new(self.position.x_coord + increment.0, self.position.y_coord + dy);
+            if board.en_passant_target() == Some(target) {
+                positions.push(target);
+            }
         }
         positions
     }
@@ -466,6 +582,7 @@ impl PieceSymbol for Knight {
 struct Rook {
     position: ChessPosition,
     color: Color,
+    moved: bool,
 }
 
 impl Rook {
@@ -480,6 +597,7 @@ impl Rook {
         Rook {
             position,
             color,
+            moved: false,
         }
     }
 }
@@ -487,6 +605,7 @@ impl Rook {
 
 impl PieceMaker for Rook {
     fn move_to(&mut self, target_position: ChessPosition) {
+        self.moved = true;
         self.position = target_position;
     }
 
@@ -570,242 +689,1414 @@ impl PieceSymbol for Queen {
     }
 }
 
-struct Player;
+struct Player {
+    engine: Engine,
+    // Colors the engine plays, the rest are typed in
+    engine_colors: Vec<Color>,
+    time_control: Option<TimeControl>,
+}
 
 impl Player {
     fn play_chess(&self) {
         let render = Some(ConsoleRender);
         let mut game = ChessGame::new(render.as_ref());
+        game.set_engine(self.engine, &self.engine_colors);
+        if let Some(control) = self.time_control {
+            game.set_clock(control, Box::new(SystemClock::new()));
+        }
         game.run();
     }
 }
 
+// Piece-square tables from white's point of view, rank 8 first
+#[rustfmt::skip]
+const PAWN_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const KNIGHT_TABLE: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+
+#[rustfmt::skip]
+const BISHOP_TABLE: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const ROOK_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const QUEEN_TABLE: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const KING_TABLE: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+
+// Indexed by bitboard piece kind
+const PIECE_SQUARE_TABLES: [&[i32; 64]; 6] = [&PAWN_TABLE, &KNIGHT_TABLE, &BISHOP_TABLE, &ROOK_TABLE, &QUEEN_TABLE, &KING_TABLE];
 
+// Computer player: iterative-deepening alpha-beta over material and piece-square tables
 #[derive(Debug, Clone, Copy)]
-struct ChessPosition {
-    x_coord: i32,
-    y_coord: i32,
+struct Engine {
+    max_depth: u32,
+    // Thinking time per move; the first iteration always completes
+    time_limit: Option<Duration>,
 }
 
-impl ChessPosition {
-    fn new(x_coord: i32, y_coord: i32) -> Self {
-        ChessPosition {
-            x_coord,
-            y_coord,
+impl Engine {
+    const MATE_SCORE: i32 = 100_000;
+    const MAX_DEPTH: u32 = 64;
+
+    fn new(max_depth: u32, time_limit: Option<Duration>) -> Self {
+        Engine {
+            max_depth: max_depth.clamp(1, Self::MAX_DEPTH),
+            time_limit,
         }
     }
 
-    fn from_string(string: &str) -> Option<Self> {
-        if string.len() != 2 {
+    // Score of the position in centipawns, positive when white is better
+    fn evaluate(position: &Position) -> i32 {
+        let mut score = 0;
+        for (color, pieces) in position.pieces.iter().enumerate() {
+            for (kind, table) in PIECE_SQUARE_TABLES.iter().enumerate() {
+                for square in bitboard::squares(pieces[kind]) {
+                    let rank = if color == bitboard::WHITE { 7 - square / 8 } else { square / 8 };
+                    let value = PieceType::from_kind(kind).value() + table[(rank * 8 + square % 8) as usize];
+                    score += if color == bitboard::WHITE { value } else { -value };
+                }
+            }
+        }
+        score
+    }
+
+    // Captures of valuable pieces by cheap ones first, then promotions, then the rest
+    fn order_moves(position: &Position, moves: &mut [Move]) {
+        let value = |square: u8| position.piece_at(square).map(|(_, kind)| PieceType::from_kind(kind).value());
+        moves.sort_by_cached_key(|candidate| {
+            let attacker = value(candidate.from).unwrap_or(0);
+            let promotion = candidate.promotion.map_or(0, |kind| PieceType::from_kind(kind).value());
+            -(value(candidate.to).map_or(0, |victim| 10 * victim - attacker + 10_000) + promotion)
+        });
+    }
+
+    fn best_move(&self, board: &ChessBoard) -> Option<MoveCommand> {
+        self.search(board).map(|(command, _)| command)
+    }
+
+    // Best move and its score for the side to move, deepening one ply at a time until the
+    // depth or time limit is reached
+    fn search(&self, board: &ChessBoard) -> Option<(MoveCommand, i32)> {
+        let deadline = self.time_limit.map(|limit| Instant::now() + limit);
+        let position = board.position;
+        let mut moves = position.legal_moves();
+        Self::order_moves(&position, &mut moves);
+        let mut best: Option<(Move, i32)> = None;
+        for depth in 1..=self.max_depth {
+            // search the best move of the last iteration first
+            if let Some((candidate, _)) = best
+                && let Some(idx) = moves.iter().position(|other| *other == candidate)
+            {
+                moves[..=idx].rotate_right(1);
+            }
+            let iteration_deadline = if depth == 1 { None } else { deadline };
+            let Some(result) = Self::search_root(&position, &moves, depth, iteration_deadline) else {
+                break;
+            };
+            best = Some(result);
+            if result.1.abs() >= Self::MATE_SCORE - Self::MAX_DEPTH as i32 {
+                break;
+            }
+        }
+        best.map(|(candidate, score)| (MoveCommand::from(candidate), score))
+    }
+
+    fn search_root(position: &Position, moves: &[Move], depth: u32, deadline: Option<Instant>) -> Option<(Move, i32)> {
+        let mut best: Option<(Move, i32)> = None;
+        let mut alpha = -Self::MATE_SCORE - 1;
+        for &candidate in moves {
+            let mut child = *position;
+            child.make_move(candidate);
+            let score = -Self::alpha_beta(&child, depth - 1, 1, -Self::MATE_SCORE - 1, -alpha, deadline)?;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((candidate, score));
+                alpha = alpha.max(score);
+            }
+        }
+        best
+    }
+
+    // Negamax score for the side to move; None once the deadline has passed
+    fn alpha_beta(position: &Position, depth: u32, ply: u32, mut alpha: i32, beta: i32, deadline: Option<Instant>) -> Option<i32> {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
             return None;
         }
-        let chars: Vec<char> = string.chars().collect();
-        let x_coord = chars[0] as i32 - 'a' as i32;
-        let y_coord = chars[1] as i32 - '1' as i32;
-        Some(ChessPosition::new(x_coord, y_coord))
+        let side = position.side;
+        let mut moves = position.legal_moves();
+        if moves.is_empty() {
+            // mated sooner is worse
+            return Some(if position.in_check(side) { -Self::MATE_SCORE + ply as i32 } else { 0 });
+        }
+        if depth == 0 {
+            let score = Self::evaluate(position);
+            return Some(if side == bitboard::WHITE { score } else { -score });
+        }
+        Self::order_moves(position, &mut moves);
+        for &candidate in &moves {
+            let mut child = *position;
+            child.make_move(candidate);
+            let score = -Self::alpha_beta(&child, depth - 1, ply + 1, -beta, -alpha, deadline)?;
+            if score >= beta {
+                return Some(beta);
+            }
+            alpha = alpha.max(score);
+        }
+        Some(alpha)
     }
 }
+// Where the chess clocks read the time from
+trait ClockSource {
+    // Time since some fixed starting point
+    fn now(&self) -> Duration;
+}
 
-impl std::fmt::Display for ChessPosition {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let x_char = ('a' as u8 + self.x_coord as u8) as char;
-        write!(f, "{}{}", x_char, self.y_coord + 1)
+struct SystemClock {
+    started: Instant,
+}
+
+impl SystemClock {
+    fn new() -> Self {
+        SystemClock { started: Instant::now() }
     }
 }
 
-impl PartialEq for ChessPosition {
-    fn eq(&self, other: &Self) -> bool {
-        self.x_coord == other.x_coord && self.y_coord == other.y_coord
+impl ClockSource for SystemClock {
+    fn now(&self) -> Duration {
+        self.started.elapsed()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct MoveCommand {
-    src: ChessPosition,
-    dst: ChessPosition,
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TimeControl {
+    base: Duration,
+    // Added to the clock after every move
+    increment: Duration,
+    // Time at the start of every move that is not taken off the clock
+    delay: Duration,
 }
 
-impl MoveCommand {
-    fn new(src: ChessPosition, dst: ChessPosition) -> Self {
-        MoveCommand {
-            src,
-            dst,
+impl TimeControl {
+    fn new(base: Duration, increment: Duration) -> Self {
+        TimeControl {
+            base,
+            increment,
+            delay: Duration::ZERO,
         }
     }
 
-    fn from_string(string: &str) -> Option<Self> {
-        let tokens: Vec<&str> = string.split_whitespace().collect();
-        if tokens.len() != 2 {
-            return None;
-        }
-        let src = ChessPosition::from_string(tokens[0]);
-        let dst = ChessPosition::from_string(tokens[1]);
-        match (src, dst) {
-            (Some(src), Some(dst)) => Some(MoveCommand::new(src, dst)),
-            _ => None,
+    fn with_delay(base: Duration, delay: Duration) -> Self {
+        TimeControl {
+            base,
+            increment: Duration::ZERO,
+            delay,
         }
     }
 }
 
-#[derive(Clone)]
-struct ChessGameState {
-    pieces: Vec<Piece>,
-    board_size: i32,
+impl std::fmt::Display for TimeControl {
+    // PGN TimeControl tag, e.g. `300+5`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.base.as_secs())?;
+        if !self.increment.is_zero() {
+            write!(f, "+{}", self.increment.as_secs())?;
+        }
+        Ok(())
+    }
 }
 
-struct ChessGame<'a, R: InputRender> {
-    finished: bool,
-    board: ChessBoard,
-    renderer: Option<&'a R>,
-    status: &'static str,
+// A pair of chess clocks; only the clock of the side to move runs
+struct ChessClock {
+    control: TimeControl,
+    source: Box<dyn ClockSource>,
+    remaining: [Duration; 2],
+    running: Color,
+    // When the running clock was started
+    turn_started: Duration,
 }
 
-impl<'a, R: InputRender> ChessGame<'a, R> {
-    const STATUS_WHITE_MOVE: &'static str = "white_move";
-    const STATUS_BLACK_MOVE: &'static str = "black_move";
-    const STATUS_WHITE_VICTORY: &'static str = "white_victory";
-    const STATUS_BLACK_VICTORY: &'static str = "black_victory";
+impl ChessClock {
+    fn new(control: TimeControl, source: Box<dyn ClockSource>, running: Color) -> Self {
+        let turn_started = source.now();
+        ChessClock {
+            control,
+            source,
+            remaining: [control.base; 2],
+            running,
+            turn_started,
+        }
+    }
 
-    fn new(renderer: Option<&'a R>) -> Self {
-        ChessGame {
-            finished: false,
-            board: ChessBoard::new(8),
-            renderer,
-            status: Self::STATUS_WHITE_MOVE,
+    // Time the running side has used on this move, after the delay
+    fn used(&self) -> Duration {
+        (self.source.now() - self.turn_started).saturating_sub(self.control.delay)
+    }
+
+    fn remaining(&self, color: Color) -> Duration {
+        let remaining = self.remaining[color_index(color)];
+        if color == self.running { remaining.saturating_sub(self.used()) } else { remaining }
+    }
+
+    fn is_flagged(&self, color: Color) -> bool {
+        self.remaining(color).is_zero()
+    }
+
+    // The running side finished its move: charge the time, add the increment and start the
+    // opponent's clock. False when the time ran out before the move.
+    fn press(&mut self) -> bool {
+        // read the time once, so the check and the charge agree
+        let used = self.used();
+        let side = color_index(self.running);
+        if used >= self.remaining[side] {
+            return false;
         }
+        self.remaining[side] = self.remaining[side].saturating_sub(used) + self.control.increment;
+        self.running = opponent(self.running);
+        self.turn_started = self.source.now();
+        true
     }
+}
 
-    fn run(&mut self) {
-        if let Some(renderer) = self.renderer {
-            renderer.render(self.get_game_state());
+// Universal Chess Interface front-end, so the engine can be driven by a chess GUI
+struct UciSession {
+    board: ChessBoard,
+    // Used for `go` without depth, movetime or clocks
+    engine: Engine,
+}
+
+impl UciSession {
+    fn new(engine: Engine) -> Self {
+        UciSession {
+            board: ChessBoard::new(CHESS_BOARD_SIZE),
+            engine,
         }
-        while !self.finished {
-            let command = self.parse_command();
-            if command.is_none() && self.renderer.is_some() {
-                if let Some(renderer) = self.renderer {
-                    renderer.print_line("Invalid command, please re-enter.");
-                }
-                continue;
-            }
-            if !self.try_move(command.unwrap()) {
-                if let Some(renderer) = self.renderer {
-                    renderer.print_line("Invalid command, please re-enter.");
-                }
-                continue;
-            }
+    }
 
-            self.board.execute_move(command.as_ref().unwrap());
-            self.status = match self.status {
-                Self::STATUS_WHITE_MOVE => Self::STATUS_BLACK_MOVE,
-                Self::STATUS_BLACK_MOVE => Self::STATUS_WHITE_MOVE,
-                _ => self.status,
+    // Reads commands from stdin until `quit` or the end of input
+    fn run(&mut self) {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
             };
-            if let Some(renderer) = self.renderer {
-                renderer.render(self.get_game_state());
+            let Some(replies) = self.handle(&line) else {
+                break;
+            };
+            for reply in replies {
+                println!("{}", reply);
             }
         }
     }
 
-    fn try_move(&mut self, command: MoveCommand) -> bool {
-        let mut board_copy = self.board.clone();
-        let src_piece = board_copy.get_piece(command.src);
-        if src_piece.is_none() {
-            return false;
-        }
-        if let Some(src_piece) = src_piece {
-            match src_piece {
-                Piece::King(king) => {
-                    self.board.set_board_handle()
-                },
-                _ => unreachable!(),
+    // Replies to one command line; None once the GUI asks to quit.
+    // `stop`, `setoption` and unknown commands are ignored since searches run to completion.
+    fn handle(&mut self, line: &str) -> Option<Vec<String>> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let replies = match tokens.first() {
+            Some(&"uci") => vec!["id name chess_game".to_string(), "id author cracking_the_coding_interview".to_string(), "uciok".to_string()],
+            Some(&"isready") => vec!["readyok".to_string()],
+            Some(&"ucinewgame") => {
+                self.board = ChessBoard::new(CHESS_BOARD_SIZE);
+                Vec::new()
             }
-            if (self.status == Self::STATUS_WHITE_MOVE && src_piece.get_piece_color() == Color::BLACK)
-                || (self.status == Self::STATUS_BLACK_MOVE && src_piece.get_piece_color() == Color::WHITE)
-            {
-                return false;
+            Some(&"position") => {
+                self.set_position(&tokens[1..]);
+                Vec::new()
             }
+            Some(&"go") => self.go(&tokens[1..]),
+            Some(&"quit") => return None,
+            _ => Vec::new(),
+        };
+        Some(replies)
+    }
 
-            if !src_piece.get_movable_positions(&board_copy).contains(&command.dst)
-                && !src_piece.get_threatened_positions(&board_copy).contains(&command.dst)
-            {
-                return false;
-            }
+    // `startpos` or `fen <fields>`, optionally followed by `moves e2e4 e7e5 ...`.
+    // An invalid position is ignored and the moves stop at the first illegal one.
+    fn set_position(&mut self, tokens: &[&str]) {
+        let moves_at = tokens.iter().position(|token| *token == "moves").unwrap_or(tokens.len());
+        let board = match tokens.first() {
+            Some(&"startpos") => Some(ChessBoard::new(CHESS_BOARD_SIZE)),
+            Some(&"fen") => ChessBoard::from_fen(&tokens[1..moves_at].join(" ")),
+            _ => None,
         };
-        
-        board_copy.execute_move(&command);
-        for piece in &board_copy.pieces {
-            if self.status == Self::STATUS_WHITE_MOVE
-                && board_copy.white_king_position()
-                    .map_or(false, |pos| piece.get_threatened_positions(&board_copy).contains(&pos))
-            {
-                return false;
-            } else if self.status == Self::STATUS_BLACK_MOVE
-                && board_copy.black_king_position()
-                    .map_or(false, |pos| piece.get_threatened_positions(&board_copy).contains(&pos))
-            {
-                return false;
-            }
+        let Some(mut board) = board else {
+            return;
+        };
+        for token in tokens.iter().skip(moves_at + 1) {
+            let side = board.side_to_move();
+            let Some(command) = MoveCommand::from_uci(token).filter(|command| board.legal_moves(side).contains(command)) else {
+                break;
+            };
+            board.execute_move(&command);
         }
-        true
+        self.board = board;
     }
 
-    fn parse_command(&self) -> Option<MoveCommand> {
-        let mut input = String::new();
-        if let Ok(_) = std::io::stdin().read_line(&mut input) {
-            MoveCommand::from_string(&input)
-        } else {
-            None
+    // `go depth 5`, `go movetime 1000` or `go wtime .. btime .. winc .. binc ..`
+    fn go(&self, tokens: &[&str]) -> Vec<String> {
+        let value = |name: &str| {
+            let idx = tokens.iter().position(|token| *token == name)?;
+            tokens.get(idx + 1)?.parse::<u64>().ok()
+        };
+        let (time, increment) = match self.board.side_to_move() {
+            Color::WHITE => ("wtime", "winc"),
+            Color::BLACK => ("btime", "binc"),
+        };
+        // a slice of what is left on the clock when only the clocks are given
+        let clock_limit = value(time).map(|left| (left / 30 + value(increment).unwrap_or(0) / 2).min(left / 2));
+        let time_limit = value("movetime").or(clock_limit).map(Duration::from_millis);
+        let engine = match (value("depth"), time_limit) {
+            (None, None) => self.engine,
+            (depth, _) => Engine::new(depth.map_or(Engine::MAX_DEPTH, |depth| depth as u32), time_limit),
+        };
+        match engine.search(&self.board) {
+            Some((command, score)) => vec![format!("info score {}", Self::score(score)), format!("bestmove {}", command.to_uci())],
+            None => vec!["bestmove 0000".to_string()],
         }
     }
 
-    fn get_game_state(&self) -> ChessGameState {
-        ChessGameState {
-            pieces: self.board.pieces.clone(),
-            board_size: self.board.size as i32,
+    // Centipawns, or full moves to mate, negative when the side to move is getting mated
+    fn score(score: i32) -> String {
+        let plies = Engine::MATE_SCORE - score.abs();
+        if plies > Engine::MAX_DEPTH as i32 {
+            return format!("cp {}", score);
         }
+        let moves = (plies + 1) / 2;
+        format!("mate {}", if score > 0 { moves } else { -moves })
     }
 }
 
-trait InputRender {
-    fn render(&self, game_state: ChessGameState);
+// What the connection threads tell the thread that owns the game
+enum ServerEvent {
+    Joined(usize, Sender<String>),
+    Line(usize, String),
+    Left(usize),
+}
 
-    fn print_line(&self, string: &str);
+struct Client {
+    id: usize,
+    // Lines for the writer thread of the connection
+    sender: Sender<String>,
+    // None for spectators
+    color: Option<Color>,
 }
 
-struct ConsoleRender;
+// Seats the first two clients as white and black and lets everyone after them watch.
+// Moves are checked against the server's own game before being broadcast.
+struct ChessServer {
+    game: ChessGame<'static, ConsoleRender>,
+    clients: Vec<Client>,
+    started: bool,
+    // Color whose draw offer is standing
+    draw_offer: Option<Color>,
+    time_control: Option<TimeControl>,
+}
 
-impl InputRender for ConsoleRender {
-    fn render(&self, game: ChessGameState) {
-        for i in (0..game.board_size).rev() {
-            self._draw_board_line(i, &game.pieces, game.board_size);
+impl ChessServer {
+    const CLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn new(time_control: Option<TimeControl>) -> Self {
+        ChessServer {
+            game: ChessGame::new(None),
+            clients: Vec::new(),
+            started: false,
+            draw_offer: None,
+            time_control,
         }
-        self._draw_bottom_line(game.board_size);
     }
 
-    fn print_line(&self, string: &str) {
-        println!("{}", string);
+    fn handle(&mut self, event: ServerEvent) {
+        match event {
+            ServerEvent::Joined(id, sender) => self.join(id, sender),
+            ServerEvent::Line(id, line) => self.command(id, line.trim()),
+            ServerEvent::Left(id) => self.leave(id),
+        }
     }
-}
 
-impl ConsoleRender {
-    fn _draw_time_line(&self, countdown_white: i32, countdown_black: i32) {
-        println!("Time remaining: {}s W / B {}s", countdown_white, countdown_black);
+    fn join(&mut self, id: usize, sender: Sender<String>) {
+        let seated = |color| self.clients.iter().any(|client| client.color == Some(color));
+        let color = if self.started {
+            None
+        } else if !seated(Color::WHITE) {
+            Some(Color::WHITE)
+        } else if !seated(Color::BLACK) {
+            Some(Color::BLACK)
+        } else {
+            None
+        };
+        let role = match color {
+            Some(Color::WHITE) => "white",
+            Some(Color::BLACK) => "black",
+            None => "spectator",
+        };
+        let _ = sender.send(format!("welcome {}", role));
+        self.clients.push(Client { id, sender, color });
+
+        if self.started {
+            self.send_state(id);
+        } else if color == Some(Color::BLACK) {
+            self.started = true;
+            if let Some(control) = self.time_control {
+                self.game.set_clock(control, Box::new(SystemClock::new()));
+            }
+            self.broadcast("start");
+            self.broadcast_state();
+        }
     }
 
-    fn _draw_board_line(&self, line_number: i32, pieces: &Vec<Piece>, board_size: i32) {
-        let empty_square = " ";
-        let white_square_prefix = "\u{001b}[47m";
-        let black_square_prefix = "\u{001b}[40m";
-        let reset_suffix = "\u{001b}[0m";
-        let black_first_offset = line_number % 2;
+    fn command(&mut self, id: usize, line: &str) {
+        let Some(color) = self.clients.iter().find(|client| client.id == id).map(|client| client.color) else {
+            return;
+        };
+        match line {
+            "" => {}
+            "board" => self.send_state(id),
+            "fen" => self.send(id, &format!("fen {}", self.game.to_fen())),
+            "pgn" => self.send(id, &self.game.to_pgn()),
+            _ => match color {
+                None => self.send(id, "error spectators can only watch"),
+                Some(_) if !self.started => self.send(id, "error waiting for an opponent"),
+                Some(_) if self.game.finished => self.send(id, "error the game is over"),
+                Some(color) => self.player_command(id, color, line),
+            },
+        }
+    }
 
-        let legend = format!("{:<2} ", line_number + 1);
-        print!("{}", legend);
-        for i in 0..board_size {
-            let is_black = (i + black_first_offset) % 2;
-            let prefix = if is_black == 1 { black_square_prefix } else { white_square_prefix };
-            let mut contents = empty_square.to_owned();
-            let curr_position = ChessPosition::new(i, line_number);
+    // `resign`, `draw` to offer or accept a draw, or a move in coordinates or SAN
+    fn player_command(&mut self, id: usize, color: Color, line: &str) {
+        match line {
+            "resign" => {
+                self.game.forfeit(color, "resignation");
+                self.broadcast_result();
+            }
+            "draw" if self.draw_offer == Some(opponent(color)) => {
+                self.game.agree_draw();
+                self.broadcast_result();
+            }
+            "draw" => {
+                self.draw_offer = Some(color);
+                self.broadcast(&format!("draw offered by {}", if color == Color::WHITE { "white" } else { "black" }));
+            }
+            _ if self.game.side_to_move() != color => self.send(id, "error not your turn"),
+            _ => {
+                let played = MoveCommand::from_string(line, &self.game.board).is_some_and(|command| self.game.play_move(command));
+                if played {
+                    // moving declines the opponent's offer and withdraws one's own
+                    self.draw_offer = None;
+                    self.broadcast(&format!("move {}", self.game.moves_san.last().map_or("", String::as_str)));
+                    self.broadcast_state();
+                } else if self.game.finished {
+                    // the flag fell before the move
+                    self.broadcast_result();
+                } else {
+                    self.send(id, "error illegal move");
+                }
+            }
+        }
+    }
+
+    // Ends the game when the running clock runs out. The game thread calls this on a timer,
+    // so the flag also falls while the player to move stays silent.
+    fn check_clock(&mut self) {
+        if self.started && self.game.check_flag() {
+            self.broadcast_result();
+        }
+    }
+
+    // A player who disconnects during the game loses it
+    fn leave(&mut self, id: usize) {
+        let Some(idx) = self.clients.iter().position(|client| client.id == id) else {
+            return;
+        };
+        let client = self.clients.remove(idx);
+        if let Some(color) = client.color
+            && self.started
+            && self.game.forfeit(color, "abandonment")
+        {
+            self.broadcast_result();
+        }
+    }
+
+    fn state_lines(&self) -> Vec<String> {
+        let mut lines = self.game.get_game_state().to_lines();
+        lines.push(format!("fen {}", self.game.to_fen()));
+        if let Some(clock) = self.game.clock.as_ref() {
+            lines.push(format!("time {} {}", clock.remaining(Color::WHITE).as_secs(), clock.remaining(Color::BLACK).as_secs()));
+        }
+        if !self.game.finished {
+            lines.push(format!("turn {}", if self.game.side_to_move() == Color::WHITE { "white" } else { "black" }));
+        }
+        lines
+    }
+
+    fn send_state(&self, id: usize) {
+        for line in self.state_lines() {
+            self.send(id, &line);
+        }
+        if self.game.finished {
+            self.send(id, &self.result_line());
+        }
+    }
+
+    fn broadcast_state(&self) {
+        for line in self.state_lines() {
+            self.broadcast(&line);
+        }
+        if self.game.finished {
+            self.broadcast_result();
+        }
+    }
+
+    fn result_line(&self) -> String {
+        format!("result {} {}", self.game.result(), self.game.result_reason.unwrap_or(""))
+    }
+
+    fn broadcast_result(&self) {
+        self.broadcast(&self.result_line());
+    }
+
+    fn send(&self, id: usize, line: &str) {
+        if let Some(client) = self.clients.iter().find(|client| client.id == id) {
+            let _ = client.sender.send(line.to_string());
+        }
+    }
+
+    fn broadcast(&self, line: &str) {
+        for client in &self.clients {
+            let _ = client.sender.send(line.to_string());
+        }
+    }
+}
+
+// Accepts connections forever; every connection gets a reader and a writer thread and one
+// more thread owns the game and watches its clock
+fn serve(address: &str, time_control: Option<TimeControl>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("Listening on {}", listener.local_addr()?);
+    let (events, inbox) = mpsc::channel();
+    thread::spawn(move || {
+        let mut server = ChessServer::new(time_control);
+        loop {
+            match inbox.recv_timeout(ChessServer::CLOCK_CHECK_INTERVAL) {
+                Ok(event) => server.handle(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            server.check_clock();
+        }
+    });
+
+    for (id, stream) in listener.incoming().enumerate() {
+        let Ok(mut writer) = stream else {
+            continue;
+        };
+        let reader = writer.try_clone()?;
+        let (sender, outbox) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for line in outbox {
+                if writeln!(writer, "{}", line).is_err() {
+                    break;
+                }
+            }
+        });
+        let events = events.clone();
+        thread::spawn(move || {
+            if events.send(ServerEvent::Joined(id, sender)).is_err() {
+                return;
+            }
+            for line in BufReader::new(reader).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if events.send(ServerEvent::Line(id, line)).is_err() {
+                    return;
+                }
+            }
+            let _ = events.send(ServerEvent::Left(id));
+        });
+    }
+    Ok(())
+}
+
+// Prints what the server sends and forwards stdin to it
+fn connect(address: &str) -> std::io::Result<()> {
+    let mut writer = TcpStream::connect(address)?;
+    let reader = writer.try_clone()?;
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            println!("{}", line);
+        }
+        std::process::exit(0);
+    });
+    for line in std::io::stdin().lines() {
+        writeln!(writer, "{}", line?)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChessPosition {
+    x_coord: i32,
+    y_coord: i32,
+}
+
+impl ChessPosition {
+    fn new(x_coord: i32, y_coord: i32) -> Self {
+        ChessPosition {
+            x_coord,
+            y_coord,
+        }
+    }
+
+    fn from_string(string: &str) -> Option<Self> {
+        let chars: Vec<char> = string.chars().collect();
+        if chars.len() != 2 || !('a'..='h').contains(&chars[0]) || !('1'..='8').contains(&chars[1]) {
+            return None;
+        }
+        let x_coord = chars[0] as i32 - 'a' as i32;
+        let y_coord = chars[1] as i32 - '1' as i32;
+        Some(ChessPosition::new(x_coord, y_coord))
+    }
+}
+
+impl ChessPosition {
+    fn on_board(&self, size: usize) -> bool {
+        (0..size as i32).contains(&self.x_coord) && (0..size as i32).contains(&self.y_coord)
+    }
+
+    // Bitboard index of the square
+    fn square(&self) -> u8 {
+        bitboard::square(self.x_coord as u8, self.y_coord as u8)
+    }
+
+    fn from_square(square: u8) -> Self {
+        ChessPosition::new((square % 8) as i32, (square / 8) as i32)
+    }
+}
+
+impl std::fmt::Display for ChessPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let x_char = (b'a' + self.x_coord as u8) as char;
+        write!(f, "{}{}", x_char, self.y_coord + 1)
+    }
+}
+
+impl PartialEq for ChessPosition {
+    fn eq(&self, other: &Self) -> bool {
+        self.x_coord == other.x_coord && self.y_coord == other.y_coord
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MoveCommand {
+    src: ChessPosition,
+    dst: ChessPosition,
+    // Piece a pawn reaching the last rank turns into
+    promotion: Option<PieceType>,
+}
+
+impl MoveCommand {
+    fn new(src: ChessPosition, dst: ChessPosition) -> Self {
+        MoveCommand {
+            src,
+            dst,
+            promotion: None,
+        }
+    }
+
+    fn with_promotion(src: ChessPosition, dst: ChessPosition, promotion: PieceType) -> Self {
+        MoveCommand {
+            src,
+            dst,
+            promotion: Some(promotion),
+        }
+    }
+
+    // A move typed in coordinates or in Standard Algebraic Notation
+    fn from_string(string: &str, board: &ChessBoard) -> Option<Self> {
+        Self::from_coordinates(string).or_else(|| Self::from_san(string.trim(), board))
+    }
+
+    // `e2 e4`, or `e7 e8 q` to choose the promotion piece
+    fn from_coordinates(string: &str) -> Option<Self> {
+        let tokens: Vec<&str> = string.split_whitespace().collect();
+        if tokens.len() != 2 && tokens.len() != 3 {
+            return None;
+        }
+        let src = ChessPosition::from_string(tokens[0])?;
+        let dst = ChessPosition::from_string(tokens[1])?;
+        match tokens.get(2) {
+            None => Some(MoveCommand::new(src, dst)),
+            Some(token) => {
+                let mut chars = token.chars();
+                match (chars.next().and_then(PieceType::from_promotion_char), chars.next()) {
+                    (Some(promotion), None) => Some(MoveCommand::with_promotion(src, dst, promotion)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    // Long algebraic notation as spoken by UCI: `e2e4`, or `e7e8q` for a promotion
+    fn from_uci(string: &str) -> Option<Self> {
+        if !string.is_ascii() || (string.len() != 4 && string.len() != 5) {
+            return None;
+        }
+        let src = ChessPosition::from_string(&string[..2])?;
+        let dst = ChessPosition::from_string(&string[2..4])?;
+        match string[4..].chars().next() {
+            None => Some(MoveCommand::new(src, dst)),
+            Some(c) => Some(MoveCommand::with_promotion(src, dst, PieceType::from_promotion_char(c)?)),
+        }
+    }
+
+    fn to_uci(self) -> String {
+        let promotion = self.promotion.map(|piece_type| piece_type.fen_char().to_ascii_lowercase().to_string());
+        format!("{}{}{}", self.src, self.dst, promotion.unwrap_or_default())
+    }
+
+    // The legal move for the side to move written in SAN, e.g. `Nf3`, `exd5`, `Rad1`, `e8=Q` or `O-O`.
+    // A promotion without a piece becomes a queen.
+    fn from_san(san: &str, board: &ChessBoard) -> Option<Self> {
+        let san = san.trim_end_matches(['+', '#', '!', '?']);
+        if !san.is_ascii() {
+            return None;
+        }
+        let side = board.side_to_move();
+        let moves = board.legal_moves(side);
+        let castle_step = match san {
+            "O-O" | "0-0" => Some(2),
+            "O-O-O" | "0-0-0" => Some(-2),
+            _ => None,
+        };
+        if let Some(step) = castle_step {
+            let king = board.king_position(side)?;
+            let target = ChessPosition::new(king.x_coord + step, king.y_coord);
+            return moves.into_iter().find(|command| command.src == king && command.dst == target);
+        }
+
+        let (piece_type, mut rest) = match san.chars().next()? {
+            c if c.is_ascii_uppercase() => (PieceType::from_fen_char(c)?, &san[1..]),
+            _ => (PieceType::Pawn, san),
+        };
+        let mut promotion = None;
+        if let Some(last) = rest.chars().last()
+            && last.is_ascii_uppercase()
+        {
+            promotion = Some(PieceType::from_promotion_char(last)?);
+            rest = rest[..rest.len() - 1].trim_end_matches('=');
+        }
+        if rest.len() < 2 {
+            return None;
+        }
+        let dst = ChessPosition::from_string(&rest[rest.len() - 2..])?;
+        // file and/or rank of the moving piece, when the notation names them
+        let hints: Vec<char> = rest[..rest.len() - 2].chars().filter(|c| *c != 'x').collect();
+        let mut candidates = moves.into_iter().filter(|command| {
+            command.dst == dst
+                && (command.promotion == promotion || (promotion.is_none() && command.promotion == Some(PieceType::Queen)))
+                && board.get_piece(command.src).is_some_and(|piece| piece.get_piece_type() == piece_type)
+                && hints.iter().all(|hint| match hint {
+                    'a'..='h' => command.src.x_coord == *hint as i32 - 'a' as i32,
+                    '1'..='8' => command.src.y_coord == *hint as i32 - '1' as i32,
+                    _ => false,
+                })
+        });
+        let command = candidates.next()?;
+        candidates.next().is_none().then_some(command)
+    }
+
+    // The move in SAN, as played on the given board
+    fn to_san(self, board: &ChessBoard) -> String {
+        let piece = board.get_piece(self.src).expect("Invalid source position");
+        let piece_type = piece.get_piece_type();
+        let color = piece.get_piece_color();
+        let (src, dst) = (self.src, self.dst);
+        let mut san = String::new();
+        if piece_type == PieceType::King && (dst.x_coord - src.x_coord).abs() == 2 {
+            san.push_str(if dst.x_coord > src.x_coord { "O-O" } else { "O-O-O" });
+        } else {
+            let capture = board.get_piece(dst).is_some() || (piece_type == PieceType::Pawn && src.x_coord != dst.x_coord);
+            let square = src.to_string();
+            if piece_type == PieceType::Pawn {
+                if capture {
+                    san.push_str(&square[..1]);
+                }
+            } else {
+                san.push(piece_type.fen_char().to_ascii_uppercase());
+                // other pieces of the same kind that could go to the same square
+                let rivals: Vec<ChessPosition> = board.legal_moves(color).iter()
+                    .filter(|command| command.dst == dst && command.src != src)
+                    .filter(|command| board.get_piece(command.src).is_some_and(|other| other.get_piece_type() == piece_type))
+                    .map(|command| command.src)
+                    .collect();
+                if !rivals.is_empty() {
+                    if rivals.iter().all(|rival| rival.x_coord != src.x_coord) {
+                        san.push_str(&square[..1]);
+                    } else if rivals.iter().all(|rival| rival.y_coord != src.y_coord) {
+                        san.push_str(&square[1..]);
+                    } else {
+                        san.push_str(&square);
+                    }
+                }
+            }
+            if capture {
+                san.push('x');
+            }
+            san.push_str(&dst.to_string());
+            if let Some(promotion) = self.promotion {
+                san.push('=');
+                san.push(promotion.fen_char().to_ascii_uppercase());
+            }
+        }
+
+        let mut after = board.clone();
+        after.execute_move(&self);
+        let enemy = opponent(color);
+        if after.is_in_check(enemy) {
+            san.push(if after.legal_moves(enemy).is_empty() { '#' } else { '+' });
+        }
+        san
+    }
+}
+
+impl From<Move> for MoveCommand {
+    fn from(candidate: Move) -> Self {
+        MoveCommand {
+            src: ChessPosition::from_square(candidate.from),
+            dst: ChessPosition::from_square(candidate.to),
+            promotion: candidate.promotion.map(PieceType::from_kind),
+        }
+    }
+}
+
+impl From<MoveCommand> for Move {
+    fn from(command: MoveCommand) -> Self {
+        Move {
+            from: command.src.square(),
+            to: command.dst.square(),
+            promotion: command.promotion.map(PieceType::kind),
+        }
+    }
+}
+
+// A move as played, kept in the board's history
+#[derive(Debug, Clone, Copy)]
+struct MoveRecord {
+    command: MoveCommand,
+    piece_type: PieceType,
+    captured: Option<PieceType>,
+}
+
+#[derive(Clone)]
+struct ChessGameState {
+    pieces: Vec<Piece>,
+    board_size: i32,
+    // The whole position as text
+    fen: String,
+    // Time left for white and black, when playing on the clock
+    time_remaining: Option<[Duration; 2]>,
+}
+
+impl ChessGameState {
+    // The board as plain text, rank 8 first, with FEN letters for the pieces
+    fn to_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for y_coord in (0..self.board_size).rev() {
+            let mut line = format!("{:<2} ", y_coord + 1);
+            for x_coord in 0..self.board_size {
+                let square = ChessPosition::new(x_coord, y_coord);
+                let piece = self.pieces.iter().find(|piece| piece.get_piece_position() == square);
+                line.push(piece.map_or('.', Piece::fen_char));
+            }
+            lines.push(line);
+        }
+        lines.push(format!("   {}", (0..self.board_size as u8).map(|i| (b'a' + i) as char).collect::<String>()));
+        lines
+    }
+}
+
+struct ChessGame<'a, R: InputRender> {
+    finished: bool,
+    board: ChessBoard,
+    // Position the game started from, as FEN
+    start_fen: String,
+    // Moves played so far in SAN
+    moves_san: Vec<String>,
+    // PGN tag pairs other than Result, SetUp and FEN
+    headers: Vec<(String, String)>,
+    renderer: Option<&'a R>,
+    status: &'static str,
+    // Why the game ended
+    result_reason: Option<&'static str>,
+    // How often each position occurred, for threefold repetition
+    position_counts: HashMap<String, u32>,
+    engine: Option<Engine>,
+    // Colors whose moves the engine picks
+    engine_colors: Vec<Color>,
+    clock: Option<ChessClock>,
+}
+
+impl<'a, R: InputRender> ChessGame<'a, R> {
+    const STATUS_WHITE_MOVE: &'static str = "white_move";
+    const STATUS_BLACK_MOVE: &'static str = "black_move";
+    const STATUS_WHITE_VICTORY: &'static str = "white_victory";
+    const STATUS_BLACK_VICTORY: &'static str = "black_victory";
+    const STATUS_DRAW: &'static str = "draw";
+
+    fn side_to_move(&self) -> Color {
+        self.board.side_to_move()
+    }
+
+    fn new(renderer: Option<&'a R>) -> Self {
+        Self::with_board(renderer, ChessBoard::new(CHESS_BOARD_SIZE))
+    }
+
+    // Continue from the position described by the FEN record
+    fn from_fen(renderer: Option<&'a R>, fen: &str) -> Option<Self> {
+        ChessBoard::from_fen(fen).map(|board| Self::with_board(renderer, board))
+    }
+
+    fn with_board(renderer: Option<&'a R>, board: ChessBoard) -> Self {
+        let status = if board.side_to_move() == Color::WHITE { Self::STATUS_WHITE_MOVE } else { Self::STATUS_BLACK_MOVE };
+        let today = chrono::Local::now().format("%Y.%m.%d").to_string();
+        let headers = [("Event", "?"), ("Site", "?"), ("Date", today.as_str()), ("Round", "?"), ("White", "?"), ("Black", "?")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut game = ChessGame {
+            finished: false,
+            start_fen: board.to_fen(),
+            moves_san: vec![],
+            headers,
+            board,
+            renderer,
+            status,
+            result_reason: None,
+            position_counts: HashMap::new(),
+            engine: None,
+            engine_colors: vec![],
+            clock: None,
+        };
+        game.record_position();
+        game.update_result();
+        game
+    }
+
+    fn to_fen(&self) -> String {
+        self.board.to_fen()
+    }
+
+    // Load a game in Portable Game Notation and replay its moves through the renderer
+    fn from_pgn(renderer: Option<&'a R>, pgn: &str) -> Option<Self> {
+        let mut headers = Vec::new();
+        let mut movetext = String::new();
+        for line in pgn.lines() {
+            let line = line.trim();
+            match line.strip_prefix('[').and_then(|tag| tag.strip_suffix(']')) {
+                Some(tag) => {
+                    let (name, value) = tag.split_once(' ')?;
+                    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+                    headers.push((name.to_string(), value.replace("\\\"", "\"").replace("\\\\", "\\")));
+                }
+                None => {
+                    movetext.push_str(line);
+                    movetext.push('\n');
+                }
+            }
+        }
+        let fen = headers.iter().find(|(name, _)| name == "FEN").map(|(_, value)| value.clone());
+        let mut game = match fen {
+            Some(fen) => Self::from_fen(renderer, &fen)?,
+            None => Self::new(renderer),
+        };
+        game.headers = headers.into_iter().filter(|(name, _)| !["Result", "SetUp", "FEN"].contains(&name.as_str())).collect();
+
+        if let Some(renderer) = renderer {
+            renderer.render(game.get_game_state());
+        }
+        for san in pgn_moves(&movetext) {
+            let command = MoveCommand::from_san(&san, &game.board)?;
+            if !game.play_move(command) {
+                return None;
+            }
+            if let Some(renderer) = renderer {
+                renderer.render(game.get_game_state());
+            }
+        }
+        Some(game)
+    }
+
+    fn set_engine(&mut self, engine: Engine, colors: &[Color]) {
+        self.engine = Some(engine);
+        self.engine_colors = colors.to_vec();
+    }
+
+    // Let the engine choose and play the move for the side to move
+    fn play_engine_move(&mut self) -> Option<MoveCommand> {
+        let command = self.engine?.best_move(&self.board)?;
+        self.play_move(command).then_some(command)
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) {
+        match self.headers.iter_mut().find(|(header, _)| header == name) {
+            Some(header) => header.1 = value.to_string(),
+            None => self.headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn result(&self) -> &'static str {
+        match self.status {
+            Self::STATUS_WHITE_VICTORY => "1-0",
+            Self::STATUS_BLACK_VICTORY => "0-1",
+            Self::STATUS_DRAW => "1/2-1/2",
+            _ => "*",
+        }
+    }
+
+    // The game so far in Portable Game Notation
+    fn to_pgn(&self) -> String {
+        let mut pgn = String::new();
+        let mut headers = self.headers.clone();
+        headers.push(("Result".to_string(), self.result().to_string()));
+        let start = ChessBoard::from_fen(&self.start_fen).expect("Invalid start position");
+        if self.start_fen != ChessBoard::new(CHESS_BOARD_SIZE).to_fen() {
+            headers.push(("SetUp".to_string(), "1".to_string()));
+            headers.push(("FEN".to_string(), self.start_fen.clone()));
+        }
+        for (name, value) in headers {
+            pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        pgn.push('\n');
+
+        let mut tokens = Vec::new();
+        let (mut number, mut side) = (start.fullmove_number(), start.side_to_move());
+        for (i, san) in self.moves_san.iter().enumerate() {
+            if side == Color::WHITE {
+                tokens.push(format!("{}.", number));
+            } else if i == 0 {
+                tokens.push(format!("{}...", number));
+            }
+            tokens.push(san.clone());
+            if side == Color::BLACK {
+                number += 1;
+            }
+            side = opponent(side);
+        }
+        tokens.push(self.result().to_string());
+
+        // keep movetext lines under 80 characters
+        let mut line = String::new();
+        for token in tokens {
+            if !line.is_empty() && line.len() + token.len() >= 80 {
+                pgn.push_str(&line);
+                pgn.push('\n');
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        pgn.push_str(&line);
+        pgn.push('\n');
+        pgn
+    }
+
+    fn run(&mut self) {
+        if let Some(renderer) = self.renderer {
+            renderer.render(self.get_game_state());
+        }
+        while !self.finished {
+            if self.engine_colors.contains(&self.side_to_move()) {
+                self.play_engine_move();
+                if let Some(renderer) = self.renderer {
+                    renderer.print_line(&format!("Engine plays {}", self.moves_san.last().map_or("", String::as_str)));
+                    renderer.render(self.get_game_state());
+                }
+                continue;
+            }
+            let Some(input) = self.read_line() else {
+                // input closed
+                return;
+            };
+            if self.check_flag() {
+                break;
+            }
+            if input.trim() == "pgn" {
+                if let Some(renderer) = self.renderer {
+                    renderer.print_line(&self.to_pgn());
+                }
+                continue;
+            }
+            let played = MoveCommand::from_string(&input, &self.board).is_some_and(|command| self.play_move(command));
+            if !played {
+                if let Some(renderer) = self.renderer {
+                    renderer.print_line("Invalid command, please re-enter.");
+                }
+                continue;
+            }
+            if let Some(renderer) = self.renderer {
+                renderer.render(self.get_game_state());
+            }
+        }
+        self.report_result();
+    }
+
+    fn report_result(&self) {
+        if let (Some(renderer), Some(reason)) = (self.renderer, self.result_reason) {
+            renderer.report_result(self.status, reason);
+        }
+    }
+
+    // Play the command for the side to move if it is legal, then check whether the game is over
+    fn play_move(&mut self, command: MoveCommand) -> bool {
+        if self.finished || self.check_flag() {
+            return false;
+        }
+        let Some(command) = self.resolve_move(command) else {
+            return false;
+        };
+        if self.clock.as_mut().is_some_and(|clock| !clock.press()) {
+            // the flag fell while the move was being checked
+            self.flag_fall(self.side_to_move());
+            return false;
+        }
+        self.moves_san.push(command.to_san(&self.board));
+        self.board.execute_move(&command);
+        self.status = match self.status {
+            Self::STATUS_WHITE_MOVE => Self::STATUS_BLACK_MOVE,
+            Self::STATUS_BLACK_MOVE => Self::STATUS_WHITE_MOVE,
+            _ => self.status,
+        };
+        self.record_position();
+        self.update_result();
+        true
+    }
+
+    fn record_position(&mut self) {
+        let key = self.board.position_key(self.side_to_move());
+        *self.position_counts.entry(key).or_insert(0) += 1;
+    }
+
+    fn update_result(&mut self) {
+        let side = self.side_to_move();
+        let (status, reason) = if self.board.legal_moves(side).is_empty() {
+            if !self.board.is_in_check(side) {
+                (Self::STATUS_DRAW, "stalemate")
+            } else if side == Color::WHITE {
+                (Self::STATUS_BLACK_VICTORY, "checkmate")
+            } else {
+                (Self::STATUS_WHITE_VICTORY, "checkmate")
+            }
+        } else if self.position_counts.get(&self.board.position_key(side)).is_some_and(|count| *count >= 3) {
+            (Self::STATUS_DRAW, "threefold repetition")
+        } else if self.board.halfmove_clock() >= 100 {
+            (Self::STATUS_DRAW, "fifty-move rule")
+        } else if self.board.has_insufficient_material() {
+            (Self::STATUS_DRAW, "insufficient material")
+        } else {
+            return;
+        };
+        self.status = status;
+        self.result_reason = Some(reason);
+        self.finished = true;
+    }
+
+    fn set_clock(&mut self, control: TimeControl, source: Box<dyn ClockSource>) {
+        self.clock = Some(ChessClock::new(control, source, self.side_to_move()));
+        self.set_header("TimeControl", &control.to_string());
+    }
+
+    // Ends the game once the side to move ran out of time
+    fn check_flag(&mut self) -> bool {
+        let side = self.side_to_move();
+        if self.finished || !self.clock.as_ref().is_some_and(|clock| clock.is_flagged(side)) {
+            return false;
+        }
+        self.flag_fall(side);
+        true
+    }
+
+    // The side's flag fell: it loses, unless the opponent could never mate, then it is a draw
+    fn flag_fall(&mut self, side: Color) {
+        let (status, reason) = if !self.board.has_mating_material(opponent(side)) {
+            (Self::STATUS_DRAW, "timeout vs insufficient material")
+        } else if side == Color::WHITE {
+            (Self::STATUS_BLACK_VICTORY, "time forfeit")
+        } else {
+            (Self::STATUS_WHITE_VICTORY, "time forfeit")
+        };
+        self.status = status;
+        self.result_reason = Some(reason);
+        self.finished = true;
+    }
+
+    // The given color gives the game up, e.g. by resignation; false once the game is over
+    fn forfeit(&mut self, color: Color, reason: &'static str) -> bool {
+        if self.finished {
+            return false;
+        }
+        self.status = if color == Color::WHITE { Self::STATUS_BLACK_VICTORY } else { Self::STATUS_WHITE_VICTORY };
+        self.result_reason = Some(reason);
+        self.finished = true;
+        true
+    }
+
+    fn agree_draw(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        self.status = Self::STATUS_DRAW;
+        self.result_reason = Some("agreement");
+        self.finished = true;
+        true
+    }
+
+    fn try_move(&mut self, command: MoveCommand) -> bool {
+        self.resolve_move(command).is_some()
+    }
+
+    // The legal move the command stands for; promotions default to a queen
+    fn resolve_move(&self, command: MoveCommand) -> Option<MoveCommand> {
+        let moves = self.board.legal_moves(self.side_to_move());
+        if moves.contains(&command) {
+            return Some(command);
+        }
+        let queening = MoveCommand::with_promotion(command.src, command.dst, PieceType::Queen);
+        (command.promotion.is_none() && moves.contains(&queening)).then_some(queening)
+    }
+
+    // None once stdin is closed
+    fn read_line(&self) -> Option<String> {
+        let mut input = String::new();
+        match std::io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(input),
+        }
+    }
+
+    fn get_game_state(&self) -> ChessGameState {
+        ChessGameState {
+            pieces: self.board.pieces(),
+            board_size: self.board.size as i32,
+            fen: self.to_fen(),
+            time_remaining: self.clock.as_ref()
+                .map(|clock| [clock.remaining(Color::WHITE), clock.remaining(Color::BLACK)]),
+        }
+    }
+}
+
+trait InputRender {
+    fn render(&self, game_state: ChessGameState);
+
+    fn print_line(&self, string: &str);
+
+    fn report_result(&self, status: &str, reason: &str);
+}
+
+struct ConsoleRender;
+
+impl InputRender for ConsoleRender {
+    fn render(&self, game: ChessGameState) {
+        for i in (0..game.board_size).rev() {
+            self._draw_board_line(i, &game.pieces, game.board_size);
+        }
+        self._draw_bottom_line(game.board_size);
+        if let Some([white, black]) = game.time_remaining {
+            self._draw_time_line(white.as_secs() as i32, black.as_secs() as i32);
+        }
+    }
+
+    fn print_line(&self, string: &str) {
+        println!("{}", string);
+    }
+
+    fn report_result(&self, status: &str, reason: &str) {
+        let result = match status {
+            "white_victory" => "White wins",
+            "black_victory" => "Black wins",
+            _ => "Draw",
+        };
+        println!("{} by {}", result, reason);
+    }
+}
+
+impl ConsoleRender {
+    fn _draw_time_line(&self, countdown_white: i32, countdown_black: i32) {
+        println!("Time remaining: {}s W / B {}s", countdown_white, countdown_black);
+    }
+
+    fn _draw_board_line(&self, line_number: i32, pieces: &[Piece], board_size: i32) {
+        let empty_square = " ";
+        let white_square_prefix = "\u{001b}[47m";
+        let black_square_prefix = "\u{001b}[40m";
+        let reset_suffix = "\u{001b}[0m";
+        let black_first_offset = line_number % 2;
+
+        let legend = format!("{:<2} ", line_number + 1);
+        print!("{}", legend);
+        for i in 0..board_size {
+            let is_black = (i + black_first_offset) % 2;
+            let prefix = if is_black == 1 { black_square_prefix } else { white_square_prefix };
+            let mut contents = empty_square.to_owned();
+            let curr_position = ChessPosition::new(i, line_number);
             for piece in pieces {
                 if curr_position == piece.get_piece_position() {
                     contents = piece.symbol();
@@ -829,60 +2120,195 @@ impl ConsoleRender {
 
 #[derive(Debug, Clone)]
 struct ChessBoard {
+    // Bitboards only cover 8x8 boards
     size: usize,
-    pieces: Vec<Piece>,
-    white_king_position: Option<ChessPosition>,
-    black_king_position: Option<ChessPosition>,
+    position: Position,
+    history: Vec<MoveRecord>,
 }
 
 impl ChessBoard {
     fn new(size: usize) -> Self {
-        let mut board = ChessBoard {
-            size,
-            pieces: vec![],
-            white_king_position: None,
-            black_king_position: None,
-        };
+        let mut board = ChessBoard::empty(size);
         board.initialize_pieces(&INITIAL_PIECE_SET_SINGLE);
+        board.position.castling = bitboard::ALL_CASTLING;
         board
     }
 
+    fn empty(size: usize) -> Self {
+        assert_eq!(size, CHESS_BOARD_SIZE, "Only 8x8 boards are supported");
+        ChessBoard {
+            size,
+            position: Position::default(),
+            history: vec![],
+        }
+    }
+
+    fn side_to_move(&self) -> Color {
+        color_of(self.position.side)
+    }
+
+    fn en_passant_target(&self) -> Option<ChessPosition> {
+        self.position.en_passant.map(ChessPosition::from_square)
+    }
+
+    fn halfmove_clock(&self) -> u32 {
+        self.position.halfmove_clock
+    }
+
+    fn fullmove_number(&self) -> u32 {
+        self.position.fullmove_number
+    }
+
+    // Forsyth-Edwards Notation, e.g. `rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1`.
+    // The two clock fields may be left out.
+    fn from_fen(fen: &str) -> Option<Self> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() != 4 && fields.len() != 6 {
+            return None;
+        }
+        let size = CHESS_BOARD_SIZE as i32;
+        let mut board = ChessBoard::empty(CHESS_BOARD_SIZE);
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        if ranks.len() != CHESS_BOARD_SIZE {
+            return None;
+        }
+        for (row, rank) in ranks.iter().enumerate() {
+            let y_coord = size - 1 - row as i32;
+            let mut x_coord = 0;
+            for c in rank.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    x_coord += empty as i32;
+                    continue;
+                }
+                let piece_type = PieceType::from_fen_char(c)?;
+                if x_coord >= size {
+                    return None;
+                }
+                let color = if c.is_ascii_uppercase() { Color::WHITE } else { Color::BLACK };
+                board.add_piece(piece_type, ChessPosition::new(x_coord, y_coord), color);
+                x_coord += 1;
+            }
+            if x_coord != size {
+                return None;
+            }
+        }
+        let kings = board.position.pieces.map(|pieces| pieces[bitboard::KING].count_ones());
+        if kings != [1, 1] {
+            return None;
+        }
+        // pawns never stand on the first or the last rank
+        let back_ranks = 0xFF | (0xFF << 56);
+        if board.position.pieces.iter().any(|pieces| pieces[bitboard::PAWN] & back_ranks != 0) {
+            return None;
+        }
+
+        board.position.side = match fields[1] {
+            "w" => bitboard::WHITE,
+            "b" => bitboard::BLACK,
+            _ => return None,
+        };
+        if fields[2] != "-" {
+            for c in fields[2].chars() {
+                let (_, right) = CASTLING_LETTERS.iter().find(|(letter, _)| *letter == c)?;
+                board.position.castling |= right;
+            }
+        }
+        board.position.drop_stale_castling();
+        board.position.en_passant = match fields[3] {
+            "-" => None,
+            square => Some(board.en_passant_square(ChessPosition::from_string(square)?)?),
+        };
+        if fields.len() == 6 {
+            board.position.halfmove_clock = fields[4].parse().ok()?;
+            board.position.fullmove_number = fields[5].parse().ok()?;
+        }
+        Some(board)
+    }
+
+    // The square must be the one just passed by a pawn of the side that moved last, so it is
+    // empty on rank 3 or 6 with that pawn in front of it and its start square empty behind it
+    fn en_passant_square(&self, target: ChessPosition) -> Option<u8> {
+        let (mover, rank, step) = if self.position.side == bitboard::WHITE {
+            (bitboard::BLACK, 5, -1)
+        } else {
+            (bitboard::WHITE, 2, 1)
+        };
+        let pawn = ChessPosition::new(target.x_coord, rank + step);
+        let start = ChessPosition::new(target.x_coord, rank - step);
+        let occupied = self.position.occupied();
+        let valid = target.y_coord == rank
+            && self.position.piece_at(pawn.square()) == Some((mover, bitboard::PAWN))
+            && occupied & (bitboard::bit(target.square()) | bitboard::bit(start.square())) == 0;
+        valid.then(|| target.square())
+    }
+
+    fn to_fen(&self) -> String {
+        let size = self.size as i32;
+        let mut ranks = Vec::new();
+        for y_coord in (0..size).rev() {
+            let mut rank = String::new();
+            let mut empty = 0;
+            for x_coord in 0..size {
+                match self.get_piece(ChessPosition::new(x_coord, y_coord)) {
+                    Some(piece) => {
+                        if empty > 0 {
+                            rank.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        rank.push(piece.fen_char());
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                rank.push_str(&empty.to_string());
+            }
+            ranks.push(rank);
+        }
+        let side = if self.side_to_move() == Color::WHITE { "w" } else { "b" };
+        let en_passant = self.en_passant_target().map_or("-".to_owned(), |target| target.to_string());
+        format!(
+            "{} {} {} {} {} {}",
+            ranks.join("/"), side, self.castling_rights(), en_passant, self.halfmove_clock(), self.fullmove_number(),
+        )
+    }
+
+    // Castling rights in FEN form, `-` when neither side may castle
+    fn castling_rights(&self) -> String {
+        let rights: String = CASTLING_LETTERS.iter()
+            .filter(|(_, right)| self.position.castling & right != 0)
+            .map(|(letter, _)| *letter)
+            .collect();
+        if rights.is_empty() { "-".to_owned() } else { rights }
+    }
+
     fn initialize_pieces(&mut self, pieces_setup: &[(PieceType, i32, i32)]) {
         for &(piece_type, x, y) in pieces_setup {
-            let piece_white = piece_type.new( 
-                ChessPosition::new(x, y), 
-                Color::WHITE
-            );
-            if piece_type == PieceType::King {
-                self.set_board_handle();
-            }
-            self.pieces.push(piece_white);
-            
+            self.add_piece(piece_type, ChessPosition::new(x, y), Color::WHITE);
+
             // Put the block on the opposite side
-            let piece_black = piece_type.new(
-                ChessPosition::new(
-                    self.size as i32 - x - 1, self.size as i32 - y - 1
-                ),
-                Color::BLACK,
-            );
-            if piece_type == PieceType::King {
-                self.set_board_handle();
-            }
-            self.pieces.push(piece_black);
+            self.add_piece(piece_type, ChessPosition::new(x, self.size as i32 - y - 1), Color::BLACK);
         }
     }
 
+    fn add_piece(&mut self, piece_type: PieceType, position: ChessPosition, color: Color) {
+        self.position.put(color_index(color), piece_type.kind(), position.square());
+    }
+
+    // The piece on the square; kings and rooks that lost their castling rights count as moved
     fn get_piece(&self, position: ChessPosition) -> Option<Piece> {
-        self.pieces.clone().into_iter().find(|piece| {
-            // let x = if let Some(piece) = piece.downcast_ref::<Piece>() {
-            //     piece.position == position
-            // } else {
-            //     false
-            // }
-            // x
-            // piece.position == position
-            true
-        })
+        if !position.on_board(self.size) {
+            return None;
+        }
+        let square = position.square();
+        let (color, kind) = self.position.piece_at(square)?;
+        let mut piece = PieceType::from_kind(kind).create(position, color_of(color));
+        match &mut piece {
+            Piece::King(king) => king.moved = self.position.castling & bitboard::castling_rights_lost(square) == 0,
+            Piece::Rook(rook) => rook.moved = self.position.castling & bitboard::castling_rights_lost(square) == 0,
+            _ => {}
+        }
+        Some(piece)
     }
 
     fn beam_search_threat(
@@ -947,47 +2373,1092 @@ impl ChessBoard {
     }
 
     fn pieces(&self) -> Vec<Piece> {
-        self.pieces.clone()
+        bitboard::squares(self.position.occupied())
+            .filter_map(|square| self.get_piece(ChessPosition::from_square(square)))
+            .collect()
     }
 
     fn size(&self) -> usize {
         self.size
     }
 
+    fn king_position(&self, color: Color) -> Option<ChessPosition> {
+        self.position.king_square(color_index(color)).map(ChessPosition::from_square)
+    }
+
     fn white_king_position(&self) -> Option<ChessPosition> {
-        self.white_king_position
+        self.king_position(Color::WHITE)
     }
 
     fn black_king_position(&self) -> Option<ChessPosition> {
-        unimplemented!()
+        self.king_position(Color::BLACK)
+    }
+
+    fn remove_piece(&mut self, position: ChessPosition) -> Option<Piece> {
+        let piece = self.get_piece(position)?;
+        self.position.remove(position.square());
+        self.position.drop_stale_castling();
+        Some(piece)
+    }
+
+    // Puts the piece on another square without any rules
+    fn move_piece(&mut self, src: ChessPosition, dst: ChessPosition) {
+        let (color, kind) = self.position.remove(src.square()).expect("Invalid source position");
+        self.position.put(color, kind, dst.square());
+        self.position.castling &= !(bitboard::castling_rights_lost(src.square()) | bitboard::castling_rights_lost(dst.square()));
     }
 
     fn execute_move(&mut self, command: &MoveCommand) {
-        let mut source_piece = self.get_piece(command.src).expect("Invalid source position");
-        if let Some(idx) = self.pieces.iter().position(|piece| piece.get_piece_position() == command.dst) {
-            self.pieces.remove(idx);
+        let (_, kind) = self.position.piece_at(command.src.square()).expect("Invalid source position");
+        let captured = self.position.make_move((*command).into());
+        self.history.push(MoveRecord {
+            command: *command,
+            piece_type: PieceType::from_kind(kind),
+            captured: captured.map(PieceType::from_kind),
+        });
+    }
+
+    // Identifies a position for repetition: placement, side to move, castling rights and
+    // an en passant capture that is actually available
+    fn position_key(&self, side_to_move: Color) -> String {
+        let mut position = self.for_side(side_to_move);
+        let en_passant_available = position.en_passant.is_some_and(|target| {
+            position.legal_moves().iter()
+                .any(|candidate| candidate.to == target && position.pieces[position.side][bitboard::PAWN] & bitboard::bit(candidate.from) != 0)
+        });
+        if !en_passant_available {
+            position.en_passant = None;
         }
-        source_piece.move_to(command.dst);
+        format!("{:?}|{}|{}|{:?}", position.pieces, position.side, position.castling, position.en_passant)
+    }
+
+    // Anything beyond a lone king or a king and one minor piece could still mate
+    fn has_mating_material(&self, color: Color) -> bool {
+        let pieces = self.position.pieces[color_index(color)];
+        let minors = (pieces[bitboard::KNIGHT] | pieces[bitboard::BISHOP]).count_ones();
+        pieces[bitboard::PAWN] | pieces[bitboard::ROOK] | pieces[bitboard::QUEEN] != 0 || minors >= 2
     }
 
-    fn register_king_position(&mut self, position: ChessPosition, color: Color) {
-        match color {
-            Color::WHITE => self.white_king_position = Some(position),
-            Color::BLACK => self.black_king_position = Some(position),
-            _ => panic!("Unknown color of the king piece"),
+    // K v K, K and one minor piece v K, or kings with bishops all on one square color
+    fn has_insufficient_material(&self) -> bool {
+        let [white, black] = self.position.pieces;
+        let kind = |kind: usize| white[kind] | black[kind];
+        if kind(bitboard::PAWN) | kind(bitboard::ROOK) | kind(bitboard::QUEEN) != 0 {
+            return false;
         }
+        let minors = kind(bitboard::KNIGHT) | kind(bitboard::BISHOP);
+        minors.count_ones() <= 1
+            || (kind(bitboard::KNIGHT) == 0 && (minors & bitboard::DARK_SQUARES == 0 || minors & !bitboard::DARK_SQUARES == 0))
     }
 
-    fn set_board_handle(&mut self) {
-        // self.board_handle = Some(board);
-        // if let Some(handle) = self.board_handle.as_ref() {
-        //     handle.register_king_position(self.position, self.color);
-        // }
+    fn is_attacked(&self, position: ChessPosition, by_color: Color) -> bool {
+        self.position.is_attacked(position.square(), color_index(by_color))
+    }
+
+    fn is_in_check(&self, color: Color) -> bool {
+        self.position.in_check(color_index(color))
+    }
+
+    // The position with the given color to move
+    fn for_side(&self, color: Color) -> Position {
+        let mut position = self.position;
+        position.side = color_index(color);
+        position
+    }
+
+    // Every move the pieces of the given color can make, ignoring the safety of their king.
+    // Pawns reaching the last rank get one move per promotion choice.
+    fn pseudo_legal_moves(&self, color: Color) -> Vec<MoveCommand> {
+        self.for_side(color).pseudo_legal_moves().into_iter().map(MoveCommand::from).collect()
+    }
+
+    // Pseudo-legal moves that do not leave the own king in check
+    fn legal_moves(&self, color: Color) -> Vec<MoveCommand> {
+        self.for_side(color).legal_moves().into_iter().map(MoveCommand::from).collect()
+    }
+
+    // Number of leaf positions reachable in exactly `depth` plies
+    fn perft(&self, color: Color, depth: u32) -> u64 {
+        self.for_side(color).perft(depth)
+    }
+}
+
+// FEN castling letters and the rights they stand for
+const CASTLING_LETTERS: [(char, u8); 4] = [
+    ('K', bitboard::WHITE_KING_SIDE),
+    ('Q', bitboard::WHITE_QUEEN_SIDE),
+    ('k', bitboard::BLACK_KING_SIDE),
+    ('q', bitboard::BLACK_QUEEN_SIDE),
+];
+
+fn color_index(color: Color) -> usize {
+    match color {
+        Color::WHITE => bitboard::WHITE,
+        Color::BLACK => bitboard::BLACK,
+    }
+}
+
+fn color_of(index: usize) -> Color {
+    if index == bitboard::WHITE { Color::WHITE } else { Color::BLACK }
+}
+
+// SAN tokens of PGN movetext, without move numbers, comments, variations, annotations and the result
+fn pgn_moves(movetext: &str) -> Vec<String> {
+    let mut cleaned = String::new();
+    let mut variation_depth = 0;
+    let mut chars = movetext.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                chars.by_ref().find(|c| *c == '}');
+            }
+            ';' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '(' => variation_depth += 1,
+            ')' => variation_depth -= 1,
+            _ if variation_depth > 0 => continue,
+            _ => {
+                cleaned.push(c);
+                continue;
+            }
+        }
+        // whatever was skipped still separates the tokens around it
+        cleaned.push(' ');
+    }
+    cleaned.split_whitespace()
+        .map(|token| token.rsplit('.').next().unwrap_or(token))
+        .filter(|token| !token.is_empty() && !token.starts_with('$') && !["1-0", "0-1", "1/2-1/2", "*"].contains(token))
+        .map(|token| token.to_string())
+        .collect()
+}
+
+fn opponent(color: Color) -> Color {
+    match color {
+        Color::WHITE => Color::BLACK,
+        Color::BLACK => Color::WHITE,
     }
 }
 
 
 fn main() {
-    let player = Player;
+    let args = Command::new("chess_game")
+        .about("Chess in the terminal, against a person or the engine")
+        .arg(Arg::new("white-engine").long("white-engine").action(ArgAction::SetTrue).help("Let the engine play white"))
+        .arg(Arg::new("black-engine").long("black-engine").action(ArgAction::SetTrue).help("Let the engine play black"))
+        .arg(
+            Arg::new("depth")
+                .long("depth")
+                .value_parser(clap::value_parser!(u32))
+                .help("Search depth of the engine in plies [default: 3, or unlimited with --movetime]"),
+        )
+        .arg(
+            Arg::new("movetime")
+                .long("movetime")
+                .value_parser(clap::value_parser!(u64))
+                .help("Milliseconds the engine may think per move"),
+        )
+        .arg(
+            Arg::new("base")
+                .long("base")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seconds on each clock; games are untimed without it"),
+        )
+        .arg(
+            Arg::new("increment")
+                .long("increment")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0")
+                .help("Seconds added to the clock after every move"),
+        )
+        .arg(
+            Arg::new("delay")
+                .long("delay")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0")
+                .help("Seconds at the start of every move before the clock runs"),
+        )
+        .arg(
+            Arg::new("uci")
+                .long("uci")
+                .action(ArgAction::SetTrue)
+                .help("Speak the Universal Chess Interface on stdin/stdout instead of playing in the terminal"),
+        )
+        .arg(
+            Arg::new("serve")
+                .long("serve")
+                .value_name("ADDRESS")
+                .help("Host a game for two TCP clients, e.g. 127.0.0.1:7878; later clients watch"),
+        )
+        .arg(
+            Arg::new("connect")
+                .long("connect")
+                .value_name("ADDRESS")
+                .help("Join a game hosted with --serve"),
+        )
+        .get_matches();
+
+    let time_limit = args.get_one::<u64>("movetime").map(|millis| Duration::from_millis(*millis));
+    let default_depth = if time_limit.is_some() { Engine::MAX_DEPTH } else { 3 };
+    let depth = args.get_one::<u32>("depth").copied().unwrap_or(default_depth);
+    if args.get_flag("uci") {
+        UciSession::new(Engine::new(depth, time_limit)).run();
+        return;
+    }
+    let mut engine_colors = Vec::new();
+    if args.get_flag("white-engine") {
+        engine_colors.push(Color::WHITE);
+    }
+    if args.get_flag("black-engine") {
+        engine_colors.push(Color::BLACK);
+    }
+    let seconds = |name: &str| Duration::from_secs(args.get_one::<u64>(name).copied().unwrap_or(0));
+    let time_control = args.get_one::<u64>("base").map(|_| TimeControl {
+        base: seconds("base"),
+        increment: seconds("increment"),
+        delay: seconds("delay"),
+    });
+    let network = match (args.get_one::<String>("serve"), args.get_one::<String>("connect")) {
+        (Some(address), _) => Some(serve(address, time_control)),
+        (None, Some(address)) => Some(connect(address)),
+        (None, None) => None,
+    };
+    if let Some(result) = network {
+        if let Err(err) = result {
+            eprintln!("Network error: {}", err);
+        }
+        return;
+    }
+    let player = Player {
+        engine: Engine::new(depth, time_limit),
+        engine_colors,
+        time_control,
+    };
     player.play_chess();
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(square: &str) -> ChessPosition {
+        ChessPosition::from_string(square).unwrap()
+    }
+
+    fn board_with(pieces: &[(PieceType, &str, Color)]) -> ChessBoard {
+        let mut board = ChessBoard::empty(CHESS_BOARD_SIZE);
+        for &(piece_type, square, color) in pieces {
+            board.add_piece(piece_type, position(square), color);
+        }
+        board
+    }
+
+    fn command(string: &str) -> MoveCommand {
+        MoveCommand::from_coordinates(string).unwrap()
+    }
+
+    fn board_from_fen(fen: &str) -> ChessBoard {
+        ChessBoard::from_fen(fen).unwrap()
+    }
+
+    #[test]
+    fn test_get_piece_by_position() {
+        let board = ChessBoard::new(CHESS_BOARD_SIZE);
+        assert!(matches!(board.get_piece(position("d1")), Some(Piece::Queen(_))));
+        assert!(matches!(board.get_piece(position("d8")), Some(Piece::Queen(_))));
+        assert_eq!(board.get_piece(position("g8")).unwrap().get_piece_color(), Color::BLACK);
+        assert!(board.get_piece(position("e4")).is_none());
+        assert_eq!(board.white_king_position(), Some(position("e1")));
+        assert_eq!(board.black_king_position(), Some(position("e8")));
+    }
+
+    #[test]
+    fn test_perft_start_position() {
+        let board = ChessBoard::new(CHESS_BOARD_SIZE);
+        assert_eq!(board.perft(Color::WHITE, 1), 20);
+        assert_eq!(board.perft(Color::WHITE, 2), 400);
+        assert_eq!(board.perft(Color::WHITE, 3), 8902);
+    }
+
+    // "Position 3" of the chessprogramming wiki perft suite: 8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - -
+    #[test]
+    fn test_perft_rook_endgame() {
+        let board = board_with(&[
+            (PieceType::King, "a5", Color::WHITE),
+            (PieceType::Pawn, "b5", Color::WHITE),
+            (PieceType::Rook, "b4", Color::WHITE),
+            (PieceType::Pawn, "e2", Color::WHITE),
+            (PieceType::Pawn, "g2", Color::WHITE),
+            (PieceType::Pawn, "c7", Color::BLACK),
+            (PieceType::Pawn, "d6", Color::BLACK),
+            (PieceType::Rook, "h5", Color::BLACK),
+            (PieceType::Pawn, "f4", Color::BLACK),
+            (PieceType::King, "h4", Color::BLACK),
+        ]);
+        assert_eq!(board.perft(Color::WHITE, 1), 14);
+        assert_eq!(board.perft(Color::WHITE, 2), 191);
+    }
+
+    #[test]
+    fn test_pinned_piece_cannot_move() {
+        let board = board_with(&[
+            (PieceType::King, "e1", Color::WHITE),
+            (PieceType::Knight, "e2", Color::WHITE),
+            (PieceType::Rook, "e8", Color::BLACK),
+            (PieceType::King, "a8", Color::BLACK),
+        ]);
+        let moves = board.legal_moves(Color::WHITE);
+        assert!(moves.iter().all(|command| command.src == position("e1")));
+        assert_eq!(moves.len(), 4);
+    }
+
+    #[test]
+    fn test_check_must_be_answered() {
+        let board = board_with(&[
+            (PieceType::King, "e1", Color::WHITE),
+            (PieceType::Rook, "a2", Color::WHITE),
+            (PieceType::Queen, "e5", Color::BLACK),
+            (PieceType::King, "e8", Color::BLACK),
+        ]);
+        assert!(board.is_in_check(Color::WHITE));
+        let mut moves = board.legal_moves(Color::WHITE);
+        moves.sort_by_key(|command| (command.src.x_coord, command.src.y_coord, command.dst.x_coord, command.dst.y_coord));
+        // block on e2 or step aside off the e-file
+        assert_eq!(moves, vec![
+            command("a2 e2"),
+            command("e1 d1"), command("e1 d2"), command("e1 f1"), command("e1 f2"),
+        ]);
+    }
+
+    #[test]
+    fn test_pawn_moves() {
+        let board = board_with(&[
+            (PieceType::King, "a1", Color::WHITE),
+            (PieceType::Pawn, "c2", Color::WHITE),
+            (PieceType::Pawn, "e2", Color::WHITE),
+            (PieceType::Knight, "e3", Color::BLACK),
+            (PieceType::Pawn, "d3", Color::BLACK),
+            (PieceType::King, "h8", Color::BLACK),
+        ]);
+        let pawn_moves = |src: &str| -> Vec<String> {
+            board.legal_moves(Color::WHITE).iter()
+                .filter(|command| command.src == position(src))
+                .map(|command| command.dst.to_string())
+                .collect()
+        };
+        assert_eq!(pawn_moves("c2"), vec!["c3", "c4", "d3"]);
+        // blocked pawns cannot jump but still capture
+        assert_eq!(pawn_moves("e2"), vec!["d3"]);
+    }
+
+    #[test]
+    fn test_game_rejects_illegal_moves() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        assert!(game.try_move(command("e2 e4")));
+        assert!(!game.try_move(command("e7 e5")));
+        assert!(!game.try_move(command("e2 e5")));
+        assert!(!game.try_move(command("g1 g3")));
+        assert!(game.try_move(command("g1 f3")));
+    }
+
+    #[test]
+    fn test_perft_kiwipete() {
+        let board = board_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        assert_eq!(board.perft(Color::WHITE, 1), 48);
+        assert_eq!(board.perft(Color::WHITE, 2), 2039);
+    }
+
+    #[test]
+    fn test_perft_with_en_passant() {
+        let board = board_from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1");
+        assert_eq!(board.perft(Color::WHITE, 3), 2812);
+    }
+
+    // Positions 4 and 5 of the perft suite, heavy on promotions
+    #[test]
+    fn test_perft_with_promotions() {
+        let mut board = board_from_fen("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1");
+        assert_eq!(board.perft(Color::WHITE, 1), 6);
+        assert_eq!(board.perft(Color::WHITE, 2), 264);
+        assert_eq!(board.perft(Color::WHITE, 3), 9467);
+
+        board = board_from_fen("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8");
+        assert_eq!(board.perft(Color::WHITE, 1), 44);
+        assert_eq!(board.perft(Color::WHITE, 2), 1486);
+    }
+
+    #[test]
+    fn test_parse_promotion_choice() {
+        let parsed = MoveCommand::from_coordinates("e7 e8 n").unwrap();
+        assert_eq!(parsed, MoveCommand::with_promotion(position("e7"), position("e8"), PieceType::Knight));
+        assert_eq!(MoveCommand::from_coordinates("e7 e8 Q").unwrap().promotion, Some(PieceType::Queen));
+        assert!(MoveCommand::from_coordinates("e7 e8 k").is_none());
+        assert!(MoveCommand::from_coordinates("e7 e8 qq").is_none());
+        assert!(MoveCommand::from_coordinates("e7 i8").is_none());
+    }
+
+    #[test]
+    fn test_castling() {
+        let mut board = board_from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        board.execute_move(&command("e1 g1"));
+        assert!(matches!(board.get_piece(position("f1")), Some(Piece::Rook(_))));
+        assert!(board.get_piece(position("h1")).is_none());
+        board.execute_move(&command("e8 c8"));
+        assert!(matches!(board.get_piece(position("d8")), Some(Piece::Rook(_))));
+        assert_eq!(board.history.len(), 2);
+
+        // no castling through an attacked square or after the rook moved
+        let mut board = board_from_fen("r3k2r/8/8/8/8/8/5q2/R3K2R w KQkq - 0 1");
+        let castles = |board: &ChessBoard| -> Vec<String> {
+            board.legal_moves(Color::WHITE).iter()
+                .filter(|command| command.src == position("e1") && (command.dst.x_coord - 4).abs() == 2)
+                .map(|command| command.dst.to_string())
+                .collect()
+        };
+        assert!(castles(&board).is_empty());
+        board.remove_piece(position("f2"));
+        assert_eq!(castles(&board), vec!["g1", "c1"]);
+        board.execute_move(&command("a1 a2"));
+        board.execute_move(&command("a2 a1"));
+        assert_eq!(castles(&board), vec!["g1"]);
+    }
+
+    #[test]
+    fn test_en_passant_only_right_after_double_push() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        for played in ["e2 e4", "a7 a6", "e4 e5", "d7 d5"] {
+            assert!(game.play_move(command(played)));
+        }
+        assert_eq!(game.board.en_passant_target(), Some(position("d6")));
+        assert!(game.try_move(command("e5 d6")));
+        game.board.execute_move(&command("e5 d6"));
+        assert!(game.board.get_piece(position("d5")).is_none());
+        let last = game.board.history.last().unwrap();
+        assert_eq!((last.piece_type, last.captured), (PieceType::Pawn, Some(PieceType::Pawn)));
+    }
+
+    #[test]
+    fn test_promotion() {
+        let mut board = board_from_fen("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1");
+        let promotions: Vec<Option<PieceType>> = board.legal_moves(Color::WHITE).iter()
+            .filter(|command| command.src == position("b7"))
+            .map(|command| command.promotion)
+            .collect();
+        assert_eq!(promotions, PROMOTION_CHOICES.map(Some));
+        board.execute_move(&command("b7 b8 n"));
+        assert!(matches!(board.get_piece(position("b8")), Some(Piece::Knight(_))));
+
+        // a promotion without a choice becomes a queen
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        game.board = board_from_fen("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(game.resolve_move(command("b7 b8")).unwrap().promotion, Some(PieceType::Queen));
+    }
+
+    struct RecordingRender {
+        lines: std::cell::RefCell<Vec<String>>,
+        renders: std::cell::Cell<usize>,
+    }
+
+    impl InputRender for RecordingRender {
+        fn render(&self, _game_state: ChessGameState) {
+            self.renders.set(self.renders.get() + 1);
+        }
+
+        fn print_line(&self, string: &str) {
+            self.lines.borrow_mut().push(string.to_string());
+        }
+
+        fn report_result(&self, status: &str, reason: &str) {
+            self.lines.borrow_mut().push(format!("{} {}", status, reason));
+        }
+    }
+
+    fn play(game: &mut ChessGame<ConsoleRender>, moves: &[&str]) {
+        for played in moves {
+            assert!(game.play_move(command(played)), "{} was rejected", played);
+        }
+    }
+
+    #[test]
+    fn test_checkmate_ends_the_game() {
+        let render = RecordingRender { lines: Default::default(), renders: Default::default() };
+        let mut game = ChessGame::new(Some(&render));
+        for played in ["f2 f3", "e7 e5", "g2 g4", "d8 h4"] {
+            assert!(game.play_move(command(played)));
+        }
+        assert!(game.finished);
+        assert_eq!(game.status, "black_victory");
+        assert_eq!(game.result_reason, Some("checkmate"));
+        assert!(!game.play_move(command("a2 a3")));
+        game.report_result();
+        assert_eq!(*render.lines.borrow(), vec!["black_victory checkmate"]);
+    }
+
+    #[test]
+    fn test_stalemate() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        game.board = board_from_fen("7k/4Q3/6K1/8/8/8/8/8 w - - 0 1");
+        play(&mut game, &["e7 f7"]);
+        assert_eq!((game.status, game.result_reason), ("draw", Some("stalemate")));
+        assert!(game.finished);
+    }
+
+    #[test]
+    fn test_threefold_repetition() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        let shuffle = ["g1 f3", "g8 f6", "f3 g1", "f6 g8"];
+        play(&mut game, &shuffle);
+        play(&mut game, &shuffle[..3]);
+        assert!(!game.finished);
+        play(&mut game, &shuffle[3..]);
+        assert_eq!((game.status, game.result_reason), ("draw", Some("threefold repetition")));
+    }
+
+    #[test]
+    fn test_fifty_move_rule() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        play(&mut game, &["e2 e4"]);
+        assert_eq!(game.board.halfmove_clock(), 0);
+        play(&mut game, &["g8 f6"]);
+        assert_eq!(game.board.halfmove_clock(), 1);
+        game.board.position.halfmove_clock = 98;
+        play(&mut game, &["g1 f3"]);
+        assert!(!game.finished);
+        play(&mut game, &["b8 c6"]);
+        assert_eq!((game.status, game.result_reason), ("draw", Some("fifty-move rule")));
+    }
+
+    #[test]
+    fn test_insufficient_material() {
+        for (placement, insufficient) in [
+            ("4k3/8/8/8/8/8/8/4K3", true),
+            ("4k3/8/8/8/8/8/8/2B1K3", true),
+            ("4k3/8/8/8/8/8/8/1N2K3", true),
+            ("2b1k3/8/8/8/8/8/8/2B1K3", false),
+            ("3bk3/8/8/8/8/8/8/2B1K3", true),
+            ("4k3/8/8/8/8/8/8/1NN1K3", false),
+            ("4k3/8/8/8/8/8/P7/4K3", false),
+        ] {
+            assert_eq!(board_from_fen(&format!("{} w - -", placement)).has_insufficient_material(), insufficient, "{}", placement);
+        }
+
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        game.board = board_from_fen("4k3/8/8/8/8/8/3r4/4K3 w - - 0 1");
+        play(&mut game, &["e1 d2"]);
+        assert_eq!((game.status, game.result_reason), ("draw", Some("insufficient material")));
+    }
+
+    const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    #[test]
+    fn test_fen_round_trip() {
+        assert_eq!(ChessBoard::new(CHESS_BOARD_SIZE).to_fen(), START_FEN);
+        assert_eq!(board_from_fen(START_FEN).legal_moves(Color::WHITE).len(), 20);
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w Kq d6 0 3",
+        ] {
+            assert_eq!(board_from_fen(fen).to_fen(), fen);
+        }
+        // clocks default when left out
+        assert_eq!(board_from_fen("4k3/8/8/8/8/8/8/4K3 b - -").to_fen(), "4k3/8/8/8/8/8/8/4K3 b - - 0 1");
+    }
+
+    #[test]
+    fn test_fen_tracks_played_moves() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        play(&mut game, &["e2 e4"]);
+        assert_eq!(game.to_fen(), "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1");
+        play(&mut game, &["e7 e5", "g1 f3", "e8 e7"]);
+        assert_eq!(game.get_game_state().fen, "rnbq1bnr/ppppkppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQ - 2 3");
+    }
+
+    #[test]
+    fn test_fen_castling_rights() {
+        let board = board_from_fen("r3k2r/8/8/8/8/8/8/R3K2R w Kq - 0 1");
+        let castles: Vec<String> = board.legal_moves(Color::WHITE).iter()
+            .filter(|command| command.src == position("e1") && (command.dst.x_coord - 4).abs() == 2)
+            .map(|command| command.dst.to_string())
+            .collect();
+        assert_eq!(castles, vec!["g1"]);
+        assert!(board.legal_moves(Color::BLACK).contains(&command("e8 c8")));
+        assert!(!board.legal_moves(Color::BLACK).contains(&command("e8 g8")));
+    }
+
+    #[test]
+    fn test_invalid_fen() {
+        for fen in [
+            "",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNX w KQkq - 0 1",
+            "rnbq1bnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQ - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KX - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e9 0 1",
+            // en passant off rank 3 or 6, or without the pawn that just moved two squares
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e4 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq e3 0 1",
+            "rnbqkbnr/pppp1ppp/8/4p3/8/8/PPPPPPPP/RNBQKBNR b KQkq e6 0 2",
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPPPPPP/RNBQKBNR b KQkq e3 0 1",
+            // pawns on the back ranks
+            "rnbqkbnP/pppppppp/8/8/8/8/PPPPPPP1/RNBQKBNR w KQq - 0 1",
+            "rnbqkbnr/ppppppp1/8/8/8/8/PPPPPPPP/RNBQKBNp w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - x 1",
+        ] {
+            assert!(ChessBoard::from_fen(fen).is_none(), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn test_game_from_fen() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::from_fen(None, "4k3/8/8/8/8/8/3r4/3K4 b - - 0 40").unwrap();
+        assert_eq!(game.status, "black_move");
+        assert!(!game.try_move(command("e1 d2")));
+        play(&mut game, &["e8 f7"]);
+        assert_eq!(game.to_fen(), "8/5k2/8/8/8/8/3r4/3K4 w - - 1 41");
+
+        // a finished position is recognised straight away
+        let game: ChessGame<ConsoleRender> = ChessGame::from_fen(None, "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap();
+        assert_eq!((game.status, game.result_reason), ("draw", Some("stalemate")));
+    }
+
+    #[test]
+    fn test_parse_san() {
+        let board = ChessBoard::new(CHESS_BOARD_SIZE);
+        assert_eq!(MoveCommand::from_string("Nf3", &board), Some(command("g1 f3")));
+        assert_eq!(MoveCommand::from_string("e4\n", &board), Some(command("e2 e4")));
+        assert_eq!(MoveCommand::from_string("g1 f3", &board), Some(command("g1 f3")));
+        assert!(MoveCommand::from_string("Nd2", &board).is_none());
+        assert!(MoveCommand::from_string("e5", &board).is_none());
+
+        let board = board_from_fen("r3k2r/8/8/3p4/4P3/8/8/R3K2R w KQkq - 0 1");
+        assert_eq!(MoveCommand::from_san("exd5", &board), Some(command("e4 d5")));
+        assert_eq!(MoveCommand::from_san("O-O", &board), Some(command("e1 g1")));
+        assert_eq!(MoveCommand::from_san("O-O-O+", &board), Some(command("e1 c1")));
+
+        // both rooks reach d1
+        let board = board_from_fen("4k3/8/8/8/8/8/4K3/R6R w - - 0 1");
+        assert!(MoveCommand::from_san("Rd1", &board).is_none());
+        assert_eq!(MoveCommand::from_san("Rad1", &board), Some(command("a1 d1")));
+        assert_eq!(MoveCommand::from_san("Rhd1", &board), Some(command("h1 d1")));
+
+        let board = board_from_fen("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(MoveCommand::from_san("b8=N", &board), Some(command("b7 b8 n")));
+        assert_eq!(MoveCommand::from_san("b8", &board), Some(command("b7 b8 q")));
+    }
+
+    #[test]
+    fn test_write_san() {
+        let san = |fen: &str, played: &str| command(played).to_san(&board_from_fen(fen));
+        assert_eq!(san(START_FEN, "g1 f3"), "Nf3");
+        assert_eq!(san(START_FEN, "e2 e4"), "e4");
+        assert_eq!(san("rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2", "e4 d5"), "exd5");
+        assert_eq!(san("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1 g1"), "O-O");
+        assert_eq!(san("3k4/8/8/8/8/8/8/R3K2R w KQ - 0 1", "e1 c1"), "O-O-O+");
+        assert_eq!(san("4k3/8/8/8/8/8/4K3/R6R w - - 0 1", "a1 d1"), "Rad1");
+        assert_eq!(san("4k3/8/8/8/R7/8/8/R3K3 w - - 0 1", "a1 a2"), "R1a2");
+        assert_eq!(san("4k3/8/8/8/Q1Q5/8/Q7/4K3 w - - 0 1", "a4 b3"), "Qa4b3");
+        assert_eq!(san("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7 b8 r"), "b8=R+");
+        assert_eq!(san("rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b KQkq - 0 2", "d8 h4"), "Qh4#");
+    }
+
+    #[test]
+    fn test_export_pgn() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        game.set_header("White", "Anna");
+        game.set_header("Date", "2024.01.02");
+        play(&mut game, &["f2 f3", "e7 e5", "g2 g4", "d8 h4"]);
+        assert_eq!(game.to_pgn(), [
+            "[Event \"?\"]",
+            "[Site \"?\"]",
+            "[Date \"2024.01.02\"]",
+            "[Round \"?\"]",
+            "[White \"Anna\"]",
+            "[Black \"?\"]",
+            "[Result \"0-1\"]",
+            "",
+            "1. f3 e5 2. g4 Qh4# 0-1",
+            "",
+        ].join("\n"));
+
+        let mut game: ChessGame<ConsoleRender> = ChessGame::from_fen(None, "4k3/8/8/8/8/8/3r4/3K4 b - - 0 40").unwrap();
+        play(&mut game, &["d2 a2", "d1 e1"]);
+        let pgn = game.to_pgn();
+        assert!(pgn.contains("[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/3r4/3K4 b - - 0 40\"]\n"));
+        assert!(pgn.ends_with("\n40... Ra2 41. Ke1 *\n"));
+    }
+
+    #[test]
+    fn test_replay_pgn() {
+        let pgn = "[Event \"Casual \\\"blitz\\\"\"]\n[Result \"1-0\"]\n\n\
+            1. e4 e5 {the open game} 2. Bc4 (2. Nf3 Nc6) Nc6 3. Qh5 $2 Nf6?? ; blunder\n\
+            4.Qxf7# 1-0\n";
+        let render = RecordingRender { lines: Default::default(), renders: Default::default() };
+        let game = ChessGame::from_pgn(Some(&render), pgn).unwrap();
+        assert_eq!(game.moves_san, vec!["e4", "e5", "Bc4", "Nc6", "Qh5", "Nf6", "Qxf7#"]);
+        assert_eq!((game.status, game.result_reason), ("white_victory", Some("checkmate")));
+        assert_eq!(game.headers, vec![("Event".to_string(), "Casual \"blitz\"".to_string())]);
+        assert!(game.to_pgn().starts_with("[Event \"Casual \\\"blitz\\\"\"]\n[Result \"1-0\"]\n"));
+        // the start position and one frame per move
+        assert_eq!(render.renders.get(), 8);
+
+        assert!(ChessGame::<ConsoleRender>::from_pgn(None, "1. e4 e4").is_none());
+        let from_fen = ChessGame::<ConsoleRender>::from_pgn(None, "[FEN \"4k3/8/8/8/8/8/3r4/3K4 b - - 0 40\"]\n\n40... Kf7").unwrap();
+        assert_eq!(from_fen.to_fen(), "8/5k2/8/8/8/8/3r4/3K4 w - - 1 41");
+    }
+
+    #[test]
+    fn test_evaluation() {
+        assert_eq!(Engine::evaluate(&ChessBoard::new(CHESS_BOARD_SIZE).position), 0);
+        // a centralised knight is worth more than one on the rim
+        let center = Engine::evaluate(&board_from_fen("4k3/8/8/8/3N4/8/8/4K3 w - - 0 1").position);
+        let rim = Engine::evaluate(&board_from_fen("4k3/8/8/8/N7/8/8/4K3 w - - 0 1").position);
+        assert!(center > rim && rim > 0);
+        assert_eq!(Engine::evaluate(&board_from_fen("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1").position), -Engine::evaluate(&board_from_fen("4kq2/8/8/8/8/8/8/4K3 w - - 0 1").position));
+    }
+
+    #[test]
+    fn test_engine_finds_mate() {
+        let engine = Engine::new(3, None);
+        let (best, score) = engine.search(&board_from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1")).unwrap();
+        assert_eq!(best, command("a1 a8"));
+        assert_eq!(score, Engine::MATE_SCORE - 1);
+        // nothing to play once mated
+        assert!(engine.best_move(&board_from_fen("R5k1/5ppp/8/8/8/8/8/6K1 b - - 1 1")).is_none());
+    }
+
+    #[test]
+    fn test_engine_looks_ahead() {
+        let board = board_from_fen("4k3/8/2p5/3p4/8/8/8/3QK3 w - - 0 1");
+        // the pawn on d5 is defended, only a shallow search grabs it
+        assert_eq!(Engine::new(1, None).best_move(&board), Some(command("d1 d5")));
+        assert_ne!(Engine::new(2, None).best_move(&board), Some(command("d1 d5")));
+        let board = board_from_fen("4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1");
+        assert_eq!(Engine::new(2, None).best_move(&board), Some(command("d2 d5")));
+    }
+
+    #[test]
+    fn test_engine_time_limit() {
+        let engine = Engine::new(Engine::MAX_DEPTH, Some(Duration::from_millis(100)));
+        let started = Instant::now();
+        let best = engine.best_move(&ChessBoard::new(CHESS_BOARD_SIZE)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(ChessBoard::new(CHESS_BOARD_SIZE).legal_moves(Color::WHITE).contains(&best));
+    }
+
+    #[test]
+    fn test_engine_plays_its_color() {
+        let mut game: ChessGame<ConsoleRender> = ChessGame::new(None);
+        assert!(game.play_engine_move().is_none());
+        game.set_engine(Engine::new(2, None), &[Color::BLACK]);
+        play(&mut game, &["e2 e4"]);
+        let reply = game.play_engine_move().unwrap();
+        assert_eq!(game.board.get_piece(reply.dst).unwrap().get_piece_color(), Color::BLACK);
+        assert_eq!(game.side_to_move(), Color::WHITE);
+        assert_eq!(game.moves_san.len(), 2);
+    }
+
+    #[test]
+    fn test_perft_deeper() {
+        assert_eq!(ChessBoard::new(CHESS_BOARD_SIZE).perft(Color::WHITE, 4), 197281);
+        let board = board_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        assert_eq!(board.perft(Color::WHITE, 3), 97862);
+        let board = board_from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1");
+        assert_eq!(board.perft(Color::WHITE, 4), 43238);
+        let board = board_from_fen("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8");
+        assert_eq!(board.perft(Color::WHITE, 3), 62379);
+    }
+
+    // The per-piece move patterns still agree with the bitboard move generator
+    #[test]
+    fn test_piece_moves_match_bitboards() {
+        for fen in [
+            START_FEN,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w Kq d6 0 3",
+        ] {
+            let board = board_from_fen(fen);
+            let mut from_pieces: Vec<String> = board.pieces().iter()
+                .filter(|piece| piece.get_piece_color() == Color::WHITE)
+                .flat_map(|piece| {
+                    let src = piece.get_piece_position();
+                    piece.get_movable_positions(&board).into_iter().map(move |dst| format!("{}{}", src, dst))
+                })
+                .collect();
+            let mut from_bitboards: Vec<String> = board.pseudo_legal_moves(Color::WHITE).iter()
+                .map(|command| format!("{}{}", command.src, command.dst))
+                .collect();
+            from_pieces.sort();
+            from_bitboards.sort();
+            from_bitboards.dedup();
+            assert_eq!(from_pieces, from_bitboards, "{}", fen);
+        }
+    }
+
+    // A clock that only moves when told to; clones share the same time
+    #[derive(Clone, Default)]
+    struct ManualClock {
+        now: std::rc::Rc<std::cell::Cell<Duration>>,
+    }
+
+    impl ManualClock {
+        fn advance(&self, seconds: u64) {
+            self.now.set(self.now.get() + Duration::from_secs(seconds));
+        }
+    }
+
+    impl ClockSource for ManualClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+    }
+
+    fn timed_game(fen: &str, control: TimeControl) -> (ChessGame<'static, ConsoleRender>, ManualClock) {
+        let mut game = ChessGame::from_fen(None, fen).unwrap();
+        let clock = ManualClock::default();
+        game.set_clock(control, Box::new(clock.clone()));
+        (game, clock)
+    }
+
+    fn time_remaining(game: &ChessGame<ConsoleRender>) -> [u64; 2] {
+        game.get_game_state().time_remaining.unwrap().map(|remaining| remaining.as_secs())
+    }
+
+    #[test]
+    fn test_clock_increment() {
+        let (mut game, clock) = timed_game(START_FEN, TimeControl::new(Duration::from_secs(60), Duration::from_secs(2)));
+        assert_eq!(game.headers.last().unwrap(), &("TimeControl".to_string(), "60+2".to_string()));
+        clock.advance(10);
+        assert_eq!(time_remaining(&game), [50, 60]);
+        play(&mut game, &["e2 e4"]);
+        assert_eq!(time_remaining(&game), [52, 60]);
+        clock.advance(30);
+        play(&mut game, &["e7 e5"]);
+        assert_eq!(time_remaining(&game), [52, 32]);
+    }
+
+    #[test]
+    fn test_clock_delay() {
+        let (mut game, clock) = timed_game(START_FEN, TimeControl::with_delay(Duration::from_secs(60), Duration::from_secs(5)));
+        clock.advance(3);
+        play(&mut game, &["e2 e4"]);
+        assert_eq!(time_remaining(&game), [60, 60]);
+        clock.advance(8);
+        assert_eq!(time_remaining(&game), [60, 57]);
+        play(&mut game, &["e7 e5"]);
+        assert_eq!(time_remaining(&game), [60, 57]);
+    }
+
+    #[test]
+    fn test_flag_fall_loses() {
+        let (mut game, clock) = timed_game(START_FEN, TimeControl::new(Duration::from_secs(10), Duration::ZERO));
+        play(&mut game, &["e2 e4"]);
+        clock.advance(10);
+        assert!(!game.play_move(command("e7 e5")));
+        assert_eq!((game.status, game.result_reason), ("white_victory", Some("time forfeit")));
+        assert!(game.to_pgn().contains("1. e4 1-0"));
+    }
+
+    // Moves on by a fixed step every time it is read
+    #[derive(Clone, Default)]
+    struct SteppingClock {
+        now: std::rc::Rc<std::cell::Cell<Duration>>,
+        step: Duration,
+    }
+
+    impl ClockSource for SteppingClock {
+        fn now(&self) -> Duration {
+            let now = self.now.get();
+            self.now.set(now + self.step);
+            now
+        }
+    }
+
+    #[test]
+    fn test_flag_falls_while_the_move_is_played() {
+        // 6 seconds pass between every read, so the flag is still up when the move is checked
+        // but has fallen by the time the clock is pressed
+        let clock = SteppingClock { step: Duration::from_secs(6), ..Default::default() };
+        let mut game: ChessGame<ConsoleRender> = ChessGame::from_fen(None, START_FEN).unwrap();
+        game.set_clock(TimeControl::new(Duration::from_secs(10), Duration::ZERO), Box::new(clock));
+        assert!(!game.play_move(command("e2 e4")));
+        assert_eq!((game.status, game.result_reason), ("black_victory", Some("time forfeit")));
+        assert!(game.moves_san.is_empty());
+
+        let clock = SteppingClock { step: Duration::from_secs(6), ..Default::default() };
+        let mut chess_clock = ChessClock::new(TimeControl::new(Duration::from_secs(10), Duration::ZERO), Box::new(clock), Color::WHITE);
+        assert!(chess_clock.press());
+        assert_eq!(chess_clock.remaining, [Duration::from_secs(4), Duration::from_secs(10)]);
+        assert!(chess_clock.press());
+        // white has 4 seconds left and uses 6
+        assert!(!chess_clock.press());
+    }
+
+    #[test]
+    fn test_flag_fall_without_mating_material_is_drawn() {
+        let (mut game, clock) = timed_game("4k3/8/8/8/8/8/8/R3K3 w - - 0 1", TimeControl::new(Duration::from_secs(5), Duration::ZERO));
+        clock.advance(6);
+        assert!(game.check_flag());
+        assert_eq!((game.status, game.result_reason), ("draw", Some("timeout vs insufficient material")));
+
+        let (mut game, clock) = timed_game("4k3/8/8/8/8/8/8/R3K3 b - - 0 1", TimeControl::new(Duration::from_secs(5), Duration::ZERO));
+        clock.advance(6);
+        assert!(game.check_flag());
+        assert_eq!((game.status, game.result_reason), ("white_victory", Some("time forfeit")));
+
+        for (fen, color, mating) in [
+            ("4k3/8/8/8/8/8/8/4KN2 w - - 0 1", Color::WHITE, false),
+            ("4k3/8/8/8/8/8/8/3BKN2 w - - 0 1", Color::WHITE, true),
+            ("4k3/p7/8/8/8/8/8/4K3 w - - 0 1", Color::BLACK, true),
+        ] {
+            assert_eq!(board_from_fen(fen).has_mating_material(color), mating, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn test_uci_handshake_and_position() {
+        let mut session = UciSession::new(Engine::new(1, None));
+        assert_eq!(session.handle("uci").unwrap().last().unwrap(), "uciok");
+        assert_eq!(session.handle("isready").unwrap(), vec!["readyok"]);
+
+        session.handle("position startpos moves e2e4 e7e5 g1f3");
+        assert_eq!(session.board.to_fen(), "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+        // moves stop at the first illegal one
+        session.handle("position startpos moves e2e4 e2e4 d7d5");
+        assert_eq!(session.board.to_fen(), "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1");
+        session.handle("position fen 8/4P1k1/8/8/8/8/8/4K3 w - - 0 1 moves e7e8n");
+        assert_eq!(session.board.to_fen(), "4N3/6k1/8/8/8/8/8/4K3 b - - 0 1");
+        // an invalid position leaves the board alone
+        session.handle("position fen not a position");
+        assert_eq!(session.board.to_fen(), "4N3/6k1/8/8/8/8/8/4K3 b - - 0 1");
+        session.handle("ucinewgame");
+        assert_eq!(session.board.to_fen(), START_FEN);
+        assert!(session.handle("quit").is_none());
+    }
+
+    #[test]
+    fn test_uci_go() {
+        let mut session = UciSession::new(Engine::new(1, None));
+        session.handle("position fen 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+        assert_eq!(session.handle("go depth 2").unwrap(), vec!["info score mate 1", "bestmove a1a8"]);
+        session.handle("position fen 7k/P7/8/8/8/8/8/K7 w - - 0 1");
+        assert_eq!(session.handle("go movetime 50").unwrap().last().unwrap(), "bestmove a7a8q");
+        session.handle("position fen 7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(session.handle("go wtime 1000 btime 1000").unwrap(), vec!["bestmove 0000"]);
+        assert_eq!(MoveCommand::from_uci("e7e8q"), Some(MoveCommand::with_promotion(position("e7"), position("e8"), PieceType::Queen)));
+        assert_eq!(MoveCommand::from_uci("e7e8k"), None);
+        assert_eq!(UciSession::score(-Engine::MATE_SCORE + 2), "mate -1");
+    }
+
+    // Joins a client to the server and returns what it receives
+    fn join(server: &mut ChessServer, id: usize) -> mpsc::Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        server.handle(ServerEvent::Joined(id, sender));
+        receiver
+    }
+
+    fn say(server: &mut ChessServer, id: usize, line: &str) {
+        server.handle(ServerEvent::Line(id, line.to_string()));
+    }
+
+    fn received(receiver: &mpsc::Receiver<String>) -> Vec<String> {
+        receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn test_server_pairs_players_and_validates_moves() {
+        let mut server = ChessServer::new(None);
+        let white = join(&mut server, 0);
+        assert_eq!(received(&white), vec!["welcome white"]);
+        say(&mut server, 0, "e2 e4");
+        assert_eq!(received(&white), vec!["error waiting for an opponent"]);
+
+        let black = join(&mut server, 1);
+        let black_lines = received(&black);
+        assert_eq!(black_lines[..2], ["welcome black", "start"]);
+        assert_eq!(black_lines[2], "8  rnbqkbnr");
+        assert!(black_lines.contains(&format!("fen {}", START_FEN)));
+        assert_eq!(received(&white).last().unwrap(), "turn white");
+
+        say(&mut server, 1, "e7 e5");
+        assert_eq!(received(&black), vec!["error not your turn"]);
+        say(&mut server, 0, "e2 e5");
+        assert_eq!(received(&white), vec!["error illegal move"]);
+        say(&mut server, 0, "e4");
+        let white_lines = received(&white);
+        assert_eq!(white_lines[0], "move e4");
+        assert_eq!(white_lines[5], "4  ....P...");
+        assert_eq!(received(&black), white_lines);
+
+        // spectators see the game but cannot play
+        let spectator = join(&mut server, 2);
+        let spectator_lines = received(&spectator);
+        assert_eq!(spectator_lines[0], "welcome spectator");
+        assert_eq!(spectator_lines.last().unwrap(), "turn black");
+        say(&mut server, 2, "e7 e5");
+        assert_eq!(received(&spectator), vec!["error spectators can only watch"]);
+        say(&mut server, 2, "fen");
+        assert_eq!(received(&spectator), vec!["fen rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"]);
+        assert!(received(&white).is_empty());
+    }
+
+    #[test]
+    fn test_server_resign_draw_and_abandonment() {
+        let mut server = ChessServer::new(None);
+        let white = join(&mut server, 0);
+        let _black = join(&mut server, 1);
+        let spectator = join(&mut server, 2);
+        received(&white);
+        received(&spectator);
+
+        say(&mut server, 0, "draw");
+        assert_eq!(received(&spectator), vec!["draw offered by white"]);
+        // a move declines the offer
+        say(&mut server, 0, "d4");
+        say(&mut server, 1, "draw");
+        say(&mut server, 0, "Nf3");
+        say(&mut server, 1, "d5");
+        say(&mut server, 0, "draw");
+        say(&mut server, 1, "draw");
+        assert_eq!(received(&spectator).last().unwrap(), "result 1/2-1/2 agreement");
+        say(&mut server, 0, "e4");
+        assert_eq!(received(&white).last().unwrap(), "error the game is over");
+
+        let mut server = ChessServer::new(None);
+        let _white = join(&mut server, 0);
+        let black = join(&mut server, 1);
+        say(&mut server, 1, "resign");
+        assert_eq!(received(&black).last().unwrap(), "result 1-0 resignation");
+        assert!(server.game.to_pgn().contains("1-0"));
+
+        let mut server = ChessServer::new(None);
+        let white = join(&mut server, 0);
+        let _black = join(&mut server, 1);
+        server.handle(ServerEvent::Left(1));
+        assert_eq!(received(&white).last().unwrap(), "result 1-0 abandonment");
+    }
+
+    #[test]
+    fn test_server_flag_falls_without_a_command() {
+        let control = TimeControl::new(Duration::from_secs(60), Duration::ZERO);
+        let mut server = ChessServer::new(Some(control));
+        let white = join(&mut server, 0);
+        let _black = join(&mut server, 1);
+        let spectator = join(&mut server, 2);
+        let clock = ManualClock::default();
+        server.game.set_clock(control, Box::new(clock.clone()));
+        received(&white);
+        received(&spectator);
+
+        clock.advance(59);
+        server.check_clock();
+        assert!(received(&spectator).is_empty());
+        clock.advance(1);
+        server.check_clock();
+        assert_eq!(received(&spectator), vec!["result 0-1 time forfeit"]);
+        assert_eq!(received(&white), vec!["result 0-1 time forfeit"]);
+        server.check_clock();
+        assert!(received(&white).is_empty());
+    }
+}
@@ -2,9 +2,16 @@
 
 use std::cmp::Eq;
 use std::fmt;
-use std::marker::PhantomData;
 use chrono::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum ReturnStatus {
@@ -13,9 +20,11 @@ enum ReturnStatus {
     InsufficientFunds,
     InsufficientQuantity,
     NoStockPosition,
+    MoneyTransferLimitExceeded,
+    AccountNotActive,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 enum OrderStatus {
     Open,
     Filled,
@@ -23,7 +32,7 @@ enum OrderStatus {
     Cancelled,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 enum TimeEnforcementType {
     GoodTillCancelled,
     FillOrKill,
@@ -32,9 +41,10 @@ enum TimeEnforcementType {
     OnTheClose,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 enum AccountStatus {
     Active,
+    Blocked,
     Closed,
     Cancelled,
     Blacklisted,
@@ -73,116 +83,291 @@ impl Constants {
     }
 }
 
-#[derive(Debug, Clone)]
+static NEXT_ORDER_ID: AtomicU32 = AtomicU32::new(1);
+static NEXT_TRANSFER_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_order_id() -> u32 {
+    NEXT_ORDER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct OrderI {
     order_id: u32,
+    member_id: u32,
+    symbol: String,
     quantity: i32,
     is_buy_order: bool,
     status: OrderStatus,
     time_enforcement: TimeEnforcementType,
-    parts: Vec<Part>,
+    parts: Vec<OrderPart>,
     creation_time: DateTime<Utc>,
 }
 
-trait Order {
-    fn set_status(&mut self, status: OrderStatus) -> bool;
-    fn save_in_db(&self) -> bool;
-    fn add_order_parts(&mut self, parts: Vec<Part>);
-    fn update_in_db(&mut self ) -> bool;
-    fn get_order_id(&self) -> u32;
+impl OrderI {
+    fn new(id: u32, symbol: String, quantity: i32, time_enforcement: TimeEnforcementType) -> Self {
+        OrderI {
+            order_id: id,
+            member_id: 0,
+            symbol,
+            quantity,
+            is_buy_order: true,
+            status: OrderStatus::Open,
+            time_enforcement,
+            parts: Vec::new(),
+            creation_time: Utc::now(),
+        }
+    }
+
+    fn filled_quantity(&self) -> i32 {
+        self.parts.iter().map(|part| part.quantity).sum()
+    }
+
+    fn remaining_quantity(&self) -> i32 {
+        self.quantity - self.filled_quantity()
+    }
+
+    // Record an execution and move the order to PartiallyFilled or Filled
+    fn fill(&mut self, part: OrderPart) {
+        self.parts.push(part);
+        self.status = if self.remaining_quantity() > 0 {
+            OrderStatus::PartiallyFilled
+        } else {
+            OrderStatus::Filled
+        };
+    }
 }
 
-#[derive(Debug, Clone)]
-struct Part {
-    // Define the Part struct as needed
+trait Order {
+    fn get_order(&self) -> &OrderI;
+    fn get_order_mut(&mut self) -> &mut OrderI;
+    // Worst price the order may trade at, None means any price
+    fn get_price_limit(&self) -> Option<f64>;
+
+    // Last trade price at which the order is released to the book, None for non-stop orders
+    fn get_stop_price(&self) -> Option<f64> {
+        None
+    }
+
+    fn get_order_id(&self) -> u32 {
+        self.get_order().order_id
+    }
+
+    fn set_status(&mut self, status: OrderStatus) -> bool {
+        self.get_order_mut().status = status;
+        true
+    }
+
+    fn add_order_parts(&mut self, parts: Vec<OrderPart>) {
+        for part in parts {
+            self.get_order_mut().fill(part);
+        }
+    }
+
+    // The order as the exchange stores it
+    fn get_record(&self) -> PendingOrder {
+        PendingOrder {
+            order: self.get_order().clone(),
+            price_limit: self.get_price_limit(),
+            stop_price: self.get_stop_price(),
+        }
+    }
+
+    fn save_in_db(&self, db: &mut dyn Database) -> bool {
+        db.append(&DbRecord::OrderSaved(self.get_record()))
+    }
+
+    fn update_in_db(&mut self, db: &mut dyn Database) -> bool {
+        db.append(&DbRecord::OrderUpdated(self.get_record()))
+    }
 }
 
+// Becomes a market order once the last trade reaches the stop price
 #[derive(Debug, Clone)]
 struct StopLossOrder {
     order: OrderI,
-    price_limit: f64,
+    stop_price: f64,
 }
 
+// Becomes a limit order once the last trade reaches the stop price
 #[derive(Debug, Clone)]
 struct StopLimitOrder {
     order: OrderI,
+    stop_price: f64,
     price_limit: f64,
 }
 
 #[derive(Debug, Clone)]
 struct MarketOrder {
     order: OrderI,
-    price_limit: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct LimitOrder {
     order: OrderI,
     price_limit: f64,
 }
 
 impl Order for LimitOrder {
-    fn get_order_id(&self) -> u32 {
-        unimplemented!()
-    }
-
-    fn set_status(&mut self, status: OrderStatus) -> bool {
-        self.order.status = status;
-        true
-    }
-
-    fn save_in_db(&self) -> bool {
-        // Implement the logic to save the order in the database
-        false
+    fn get_order(&self) -> &OrderI {
+        &self.order
     }
 
-    fn add_order_parts(&mut self, parts: Vec<Part>) {
-        self.order.parts.extend(parts);
+    fn get_order_mut(&mut self) -> &mut OrderI {
+        &mut self.order
     }
 
-    fn update_in_db(&mut self ) -> bool {
-        false
+    fn get_price_limit(&self) -> Option<f64> {
+        Some(self.price_limit)
     }
 }
 
 impl LimitOrder {
     fn new(
         id: u32, 
+        symbol: String,
         quantity: i32, 
         price_limit: f64,
         time_enforcement: TimeEnforcementType, 
     ) -> Self {
         LimitOrder {
-            order: OrderI {
-                order_id: id,
-                quantity,
-                is_buy_order: true,
-                status: OrderStatus::Open,
-                time_enforcement,
-                parts: Vec::new(),
-                creation_time: Utc::now(),
-            },
+            order: OrderI::new(id, symbol, quantity, time_enforcement),
             price_limit,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl Order for MarketOrder {
+    fn get_order(&self) -> &OrderI {
+        &self.order
+    }
+
+    fn get_order_mut(&mut self) -> &mut OrderI {
+        &mut self.order
+    }
+
+    fn get_price_limit(&self) -> Option<f64> {
+        None
+    }
+}
+
+impl MarketOrder {
+    fn new(id: u32, symbol: String, quantity: i32, time_enforcement: TimeEnforcementType) -> Self {
+        MarketOrder {
+            order: OrderI::new(id, symbol, quantity, time_enforcement),
+        }
+    }
+}
+
+impl Order for StopLossOrder {
+    fn get_order(&self) -> &OrderI {
+        &self.order
+    }
+
+    fn get_order_mut(&mut self) -> &mut OrderI {
+        &mut self.order
+    }
+
+    fn get_price_limit(&self) -> Option<f64> {
+        None
+    }
+
+    fn get_stop_price(&self) -> Option<f64> {
+        Some(self.stop_price)
+    }
+}
+
+impl StopLossOrder {
+    fn new(
+        id: u32,
+        symbol: String,
+        quantity: i32,
+        stop_price: f64,
+        time_enforcement: TimeEnforcementType,
+    ) -> Self {
+        StopLossOrder {
+            order: OrderI::new(id, symbol, quantity, time_enforcement),
+            stop_price,
+        }
+    }
+}
+
+impl Order for StopLimitOrder {
+    fn get_order(&self) -> &OrderI {
+        &self.order
+    }
+
+    fn get_order_mut(&mut self) -> &mut OrderI {
+        &mut self.order
+    }
+
+    fn get_price_limit(&self) -> Option<f64> {
+        Some(self.price_limit)
+    }
+
+    fn get_stop_price(&self) -> Option<f64> {
+        Some(self.stop_price)
+    }
+}
+
+impl StopLimitOrder {
+    fn new(
+        id: u32,
+        symbol: String,
+        quantity: i32,
+        stop_price: f64,
+        price_limit: f64,
+        time_enforcement: TimeEnforcementType,
+    ) -> Self {
+        StopLimitOrder {
+            order: OrderI::new(id, symbol, quantity, time_enforcement),
+            stop_price,
+            price_limit,
+        }
+    }
+}
+
+#[derive(Debug)]
 struct StockInventory {
     inventory_name: String,
-    last_updated_date: chrono::DateTime<Utc>
+    last_updated_date: chrono::DateTime<Utc>,
+    prices: HashMap<String, f64>,
+    trades: Option<mpsc::Receiver<MarketData>>,
 }
 
 impl StockInventory {
     fn new(name: String, last_updated_date: chrono::DateTime<Utc>) -> Self {
         StockInventory {
             inventory_name: name,
-            last_updated_date
+            last_updated_date,
+            prices: HashMap::new(),
+            trades: None,
+        }
+    }
+
+    // Follow the trade prints of every symbol listed on the exchange
+    fn connect(&mut self, stock_exchange: &mut StockExchange) {
+        for (symbol, price) in stock_exchange.get_last_prices() {
+            self.prices.insert(symbol, price);
+        }
+        self.trades = Some(stock_exchange.subscribe(&[], None));
+    }
+
+    fn refresh(&mut self) {
+        let Some(trades) = &self.trades else {
+            return;
+        };
+        for data in trades.try_iter() {
+            if let MarketData::Trade(trade) = data {
+                self.prices.insert(trade.symbol, trade.price);
+                self.last_updated_date = trade.executed_on;
+            }
         }
     }
 
-    fn search_symbol(&self, symbol: &str) -> Stock {
-        unimplemented!()
+    // The stock priced at its last trade, None if it never traded
+    fn search_symbol(&mut self, symbol: &str) -> Option<Stock> {
+        self.refresh();
+        self.prices.get(symbol).map(|price| Stock::new(symbol.to_string(), *price))
     }
 }
 
@@ -204,310 +389,3340 @@ impl Stock {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct OrderPart {
     price: f64,
     quantity: i32,
     execution_date: chrono::DateTime<Utc>,
 }
 
-#[derive(Debug, Clone)]
-struct StockExchange<O> {
-    instance: Option<Box<OnlyOne<O>>>,
+// Prices are kept as integer ticks inside the book so they can be used as map keys
+const PRICE_TICKS: f64 = 10_000.0;
+
+fn price_key(price: f64) -> i64 {
+    (price * PRICE_TICKS).round() as i64
 }
 
-#[derive(Debug, Clone)]
-struct OnlyOne<O> {
-    _marker: PhantomData<O>,
-    instance: StockExchange<O>
-    // Define the OnlyOne struct as needed
+// Whether a resting price level is acceptable to an order with the given side and limit
+fn crosses(is_buy_order: bool, key: i64, limit: Option<f64>) -> bool {
+    match limit {
+        None => true,
+        Some(limit) if is_buy_order => key <= price_key(limit),
+        Some(limit) => key >= price_key(limit),
+    }
 }
 
-impl<O: Order> StockExchange<O> {
-    fn new() -> Self {
-        StockExchange {
-            instance: None
+// Fill report for an order that is not in the caller's hands, i.e. a resting order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExecutionReport {
+    order_id: u32,
+    member_id: u32,
+    symbol: String,
+    parts: Vec<OrderPart>,
+    status: OrderStatus,
+}
+
+impl ExecutionReport {
+    fn new(order: &OrderI, parts: Vec<OrderPart>) -> Self {
+        ExecutionReport {
+            order_id: order.order_id,
+            member_id: order.member_id,
+            symbol: order.symbol.clone(),
+            parts,
+            status: order.status.clone(),
         }
     }
+}
 
-    fn get_instance(&self) -> &OnlyOne<O> {
-        self.instance.as_ref().unwrap()
-    }
+// An order held by the exchange outside the continuous book, i.e. queued for an auction
+// or waiting in the stop trigger table. This is also the form in which orders are persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingOrder {
+    order: OrderI,
+    price_limit: Option<f64>,
+    stop_price: Option<f64>,
+}
 
-    fn place_order(&self, order: &O) -> ReturnStatus {
-        let return_status = self.get_instance().submit_order(order);
-        return_status
+impl Order for PendingOrder {
+    fn get_order(&self) -> &OrderI {
+        &self.order
     }
-}
 
-impl<O: Order> Default for StockExchange<O> {
-    fn default() -> Self {
-        StockExchange::new()
+    fn get_order_mut(&mut self) -> &mut OrderI {
+        &mut self.order
     }
-}
 
-impl<O: Order> OnlyOne<O> {
-    fn new(instance: StockExchange<O>) -> Self {
-        Self {
-            instance,
-            _marker: PhantomData,
-            // Initialize the OnlyOne struct as needed
-        }
+    fn get_price_limit(&self) -> Option<f64> {
+        self.price_limit
     }
 
-    fn submit_order(&self, order: &O) -> ReturnStatus {
-        // Implement the logic to submit the order
-        ReturnStatus::Success
+    fn get_stop_price(&self) -> Option<f64> {
+        self.stop_price
     }
 }
 
-trait Account {
-    fn reset_password(&self);
+#[derive(Debug, Clone)]
+struct OrderBook {
+    symbol: String,
+    // Each price level is a FIFO queue, which gives time priority within the level
+    bids: BTreeMap<i64, VecDeque<LimitOrder>>,
+    asks: BTreeMap<i64, VecDeque<LimitOrder>>,
+    // Stop orders waiting for the last trade price to reach their stop, in arrival order
+    stop_orders: Vec<PendingOrder>,
+    last_trade_price: Option<f64>,
+    // Trades not yet published on the market data feed
+    trades: Vec<Trade>,
 }
 
-impl AccountStatus {
-    const NONE: u32 = 1;
-}
+impl OrderBook {
+    fn new(symbol: String) -> Self {
+        OrderBook {
+            symbol,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            stop_orders: Vec::new(),
+            last_trade_price: None,
+            trades: Vec::new(),
+        }
+    }
 
-struct Admin {
-    id: u32,
-    password: String,
-    name: String,
-    address: String,
-    email: String,
-    phone: String,
-    status: u32,
-    available_funds_for_trading: f64,
-    date_of_membership: chrono::DateTime<Utc>,
-    stock_positions: HashMap<u32, StockPosition>,
-    active_orders: HashMap<u32, LimitOrder>,
-}
+    fn best_bid(&self) -> Option<f64> {
+        self.bids.values().next_back().map(|level| level[0].price_limit)
+    }
 
-impl Account for Admin {
-    fn reset_password(&self) {
-        todo!()
+    fn best_ask(&self) -> Option<f64> {
+        self.asks.values().next().map(|level| level[0].price_limit)
     }
-}
 
-impl Admin {
-    fn block_member(&self) -> bool {
-        // Implement the logic to block the member
-        false
+    fn quote(&self) -> Quote {
+        let top = |level: &VecDeque<LimitOrder>| {
+            let quantity = level.iter().map(|order| order.order.remaining_quantity()).sum();
+            (level[0].price_limit, quantity)
+        };
+        Quote {
+            symbol: self.symbol.clone(),
+            best_bid: self.bids.values().next_back().map(top),
+            best_ask: self.asks.values().next().map(top),
+        }
     }
 
-    fn unblock_member(&self) -> bool {
-        // Implement the logic to unblock the member
-        false
+    fn record_trade(&mut self, part: &OrderPart) {
+        self.last_trade_price = Some(part.price);
+        self.trades.push(Trade {
+            symbol: self.symbol.clone(),
+            price: part.price,
+            quantity: part.quantity,
+            executed_on: part.execution_date,
+        });
     }
-}
 
-#[derive(Debug, Clone)]
-struct Member {
-    id: u32,
-    password: String,
-    name: String,
-    address: String,
-    email: String,
-    phone: String,
-    status: u32,
-    available_funds_for_trading: f64,
-    date_of_membership: chrono::DateTime<Utc>,
-    stock_positions: HashMap<u32, StockPosition>,
-    active_orders: HashMap<u32, LimitOrder>,
-}
+    fn add_resting(&mut self, order: LimitOrder) {
+        let side = if order.order.is_buy_order { &mut self.bids } else { &mut self.asks };
+        side.entry(price_key(order.price_limit)).or_default().push_back(order);
+    }
 
-impl Account for Member {
-    fn reset_password(&self) {
-        // Implement the logic to reset the password
+    // Quantity the book could fill right now for an order with the given side and limit
+    fn available_quantity(&self, is_buy_order: bool, limit: Option<f64>) -> i32 {
+        let side = if is_buy_order { &self.asks } else { &self.bids };
+        side.iter()
+            .filter(|(key, _)| crosses(is_buy_order, **key, limit))
+            .flat_map(|(_, level)| level.iter())
+            .map(|order| order.order.remaining_quantity())
+            .sum()
     }
-}
 
-impl Member {
-    fn new(id: u32, password: String, name: String, address: String, email: String, phone: String) -> Self {
-        Member {
-            id,
-            password,
-            name,
-            address,
-            email,
-            phone,
-            status: AccountStatus::NONE,
-            available_funds_for_trading: 0.0,
-            date_of_membership: chrono::Utc::now(),
-            stock_positions: HashMap::new(),
-            active_orders: HashMap::new(),
+    fn remove_resting(&mut self, order_id: u32) -> Option<LimitOrder> {
+        for side in [&mut self.bids, &mut self.asks] {
+            let mut found = None;
+            for (key, level) in side.iter_mut() {
+                if let Some(idx) = level.iter().position(|o| o.order.order_id == order_id) {
+                    found = Some((*key, idx));
+                    break;
+                }
+            }
+            if let Some((key, idx)) = found {
+                let level = side.get_mut(&key).unwrap();
+                let order = level.remove(idx);
+                if level.is_empty() {
+                    side.remove(&key);
+                }
+                return order;
+            }
         }
+        None
     }
 
-    fn place_sell_limit_order(&mut self, stock_id: u32, quantity: i32, limit_price: f64, enforcement_type: TimeEnforcementType) -> ReturnStatus {
-        // check if member has this stock position
-        if !self.stock_positions.contains_key(&stock_id) {
-            return ReturnStatus::NoStockPosition;
+    // Match the incoming order against the opposite side of the book. Trades happen at the
+    // resting order's price; best price first, then oldest order first within a price level.
+    fn match_order(&mut self, taker: &mut OrderI, limit: Option<f64>) -> Vec<ExecutionReport> {
+        let mut reports = Vec::new();
+        while taker.remaining_quantity() > 0 {
+            let side = if taker.is_buy_order { &mut self.asks } else { &mut self.bids };
+            let best_key = if taker.is_buy_order {
+                side.keys().next().copied()
+            } else {
+                side.keys().next_back().copied()
+            };
+            let Some(key) = best_key else {
+                break;
+            };
+            if !crosses(taker.is_buy_order, key, limit) {
+                break;
+            }
+
+            let level = side.get_mut(&key).unwrap();
+            let maker = level.front_mut().unwrap();
+            let part = OrderPart {
+                price: maker.price_limit,
+                quantity: taker.remaining_quantity().min(maker.order.remaining_quantity()),
+                execution_date: Utc::now(),
+            };
+            taker.fill(part.clone());
+            maker.order.fill(part.clone());
+            reports.push(ExecutionReport::new(&maker.order, vec![part.clone()]));
+            if maker.order.remaining_quantity() == 0 {
+                level.pop_front();
+                if level.is_empty() {
+                    side.remove(&key);
+                }
+            }
+            self.record_trade(&part);
         }
+        reports
+    }
 
-        let stock_position = self.stock_positions.get(&stock_id).unwrap();
-        // check if the member has enough quantity available to sell
-        if stock_position.get_quantity() < quantity {
-            return ReturnStatus::InsufficientQuantity;
+    // Continuous execution under the order's time in force. Whatever is left afterwards
+    // rests in the book for GTC limit orders and is cancelled otherwise.
+    fn execute(&mut self, order: &mut OrderI, limit: Option<f64>) -> (ReturnStatus, Vec<ExecutionReport>) {
+        if order.time_enforcement == TimeEnforcementType::FillOrKill
+            && self.available_quantity(order.is_buy_order, limit) < order.remaining_quantity()
+        {
+            order.status = OrderStatus::Cancelled;
+            return (ReturnStatus::Fail, Vec::new());
         }
 
-        let mut order = LimitOrder::new(stock_id, quantity, limit_price, enforcement_type);
-        order.order.is_buy_order = false;
-        order.save_in_db();
-        let stock_exchange = StockExchange::<LimitOrder>::new();
-        let success = stock_exchange.place_order(&order);
-        if success == ReturnStatus::Success {
-            order.set_status(OrderStatus::Filled);
-            order.save_in_db();
-        } else {
-            self.active_orders.insert(order.get_order_id(), order);
+        let reports = self.match_order(order, limit);
+        if order.remaining_quantity() > 0 {
+            match (&order.time_enforcement, limit) {
+                (TimeEnforcementType::GoodTillCancelled, Some(price_limit)) => {
+                    self.add_resting(LimitOrder {
+                        order: order.clone(),
+                        price_limit,
+                    });
+                }
+                _ => {
+                    order.status = OrderStatus::Cancelled;
+                }
+            }
         }
-        success
+        (ReturnStatus::Success, reports)
     }
 
-    fn place_buy_limit_order(
-        &mut self, 
-        stock_id: u32, quantity: i32, 
-        limit_price: f64, enforcement_type: TimeEnforcementType
-    ) -> ReturnStatus {
-        // check if the member has enough funds to buy this stock
-        if self.available_funds_for_trading < quantity as f64 * limit_price {
-            return ReturnStatus::InsufficientFunds;
+    // Buy stops fire when the market trades at or above the stop, sell stops at or below it
+    fn stop_triggered(&self, is_buy_order: bool, stop_price: f64) -> bool {
+        match self.last_trade_price {
+            None => false,
+            Some(last) if is_buy_order => price_key(last) >= price_key(stop_price),
+            Some(last) => price_key(last) <= price_key(stop_price),
         }
+    }
 
-        let mut order = LimitOrder::new(stock_id, quantity, limit_price, enforcement_type);
-        order.order.is_buy_order = true;
-        order.save_in_db();
-        let stock_exchange = StockExchange::new();
-        let success = stock_exchange.place_order(&order);
-        if success != ReturnStatus::Success {
-            order.set_status(OrderStatus::Filled);
-            order.save_in_db();
-        } else {
-            // self.active_orders.insert(order.get_order_id(), order);
+    // Release every stop order whose stop has been reached as a market or limit order.
+    // Their trades move the last price again, so keep going until no more stops fire.
+    fn trigger_stops(&mut self) -> Vec<ExecutionReport> {
+        let mut reports = Vec::new();
+        while let Some(idx) = self.stop_orders.iter().position(|pending| {
+            self.stop_triggered(pending.order.is_buy_order, pending.stop_price.unwrap())
+        }) {
+            let mut pending = self.stop_orders.remove(idx);
+            let filled_before = pending.order.parts.len();
+            let (_, fills) = self.execute(&mut pending.order, pending.price_limit);
+            reports.extend(fills);
+            let parts = pending.order.parts[filled_before..].to_vec();
+            if !parts.is_empty() || pending.order.status == OrderStatus::Cancelled {
+                reports.push(ExecutionReport::new(&pending.order, parts));
+            }
         }
-        success
+        reports
     }
 
-    fn callback_stock_exchange(&mut self, order_id: u32, order_parts: Vec<Part>, status: OrderStatus) {
-        let order = self.active_orders.get_mut(&order_id).unwrap();
-        order.add_order_parts(order_parts);
-        order.set_status(status.clone());
-        order.update_in_db();
+    // Price that maximizes the executable volume between the auction orders and the resting
+    // book. Ties go to the smallest imbalance, then to the price nearest the last trade.
+    fn clearing_price(&self, auction: &[PendingOrder]) -> Option<(f64, i32)> {
+        let resting = self.bids.values().chain(self.asks.values()).flatten()
+            .map(|order| (&order.order, Some(order.price_limit)));
+        let participants: Vec<(&OrderI, Option<f64>)> = auction.iter()
+            .map(|pending| (&pending.order, pending.price_limit))
+            .chain(resting)
+            .collect();
+
+        let mut candidates: Vec<f64> = participants.iter().filter_map(|(_, limit)| *limit).collect();
+        if candidates.is_empty() {
+            candidates.extend(self.last_trade_price);
+        }
+        let reference = self.last_trade_price.unwrap_or(0.0);
+
+        let mut best: Option<(f64, i32, i32)> = None;
+        for price in candidates {
+            let key = price_key(price);
+            let volume = |is_buy_order: bool| -> i32 {
+                participants.iter()
+                    .filter(|(order, limit)| {
+                        order.is_buy_order == is_buy_order
+                            && limit.is_none_or(|limit| crosses(is_buy_order, key, Some(limit)))
+                    })
+                    .map(|(order, _)| order.remaining_quantity())
+                    .sum()
+            };
+            let (buy_volume, sell_volume) = (volume(true), volume(false));
+            let executable = buy_volume.min(sell_volume);
+            let imbalance = (buy_volume - sell_volume).abs();
+            let better = match best {
+                None => true,
+                Some((best_price, best_volume, best_imbalance)) => {
+                    executable > best_volume
+                        || (executable == best_volume && imbalance < best_imbalance)
+                        || (executable == best_volume && imbalance == best_imbalance
+                            && (price - reference).abs() < (best_price - reference).abs())
+                }
+            };
+            if better {
+                best = Some((price, executable, imbalance));
+            }
+        }
+        best.filter(|(_, volume, _)| *volume > 0).map(|(price, volume, _)| (price, volume))
+    }
 
-        if status == OrderStatus::Filled || status == OrderStatus::Cancelled {
-            self.active_orders.remove(&order_id);
+    // Uncross the auction orders together with the resting book at a single clearing price.
+    // Auction orders that are not (fully) executed are cancelled. Resting orders are filled
+    // where they are queued, so a partial fill keeps its time priority.
+    fn run_auction(&mut self, mut auction: Vec<PendingOrder>) -> Vec<ExecutionReport> {
+        let mut reports = Vec::new();
+
+        if let Some((price, mut volume)) = self.clearing_price(&auction) {
+            let key = price_key(price);
+            let (mut buys, mut sells) = (Vec::new(), Vec::new());
+            for (idx, pending) in auction.iter().enumerate() {
+                let side = if pending.order.is_buy_order { &mut buys } else { &mut sells };
+                side.push(AuctionParticipant::Auction(idx));
+            }
+            // Every crossing price level of the book takes part in the auction
+            for (bid_key, level) in self.bids.range(key..) {
+                buys.extend((0..level.len()).map(|pos| AuctionParticipant::Resting(*bid_key, pos)));
+            }
+            for (ask_key, level) in self.asks.range(..=key) {
+                sells.extend((0..level.len()).map(|pos| AuctionParticipant::Resting(*ask_key, pos)));
+            }
+
+            // Market orders first, then best price, then resting orders ahead of auction orders
+            let priority = |participant: &AuctionParticipant, is_buy_order: bool| {
+                let (limit, from_book) = match *participant {
+                    AuctionParticipant::Auction(idx) => (auction[idx].price_limit.map(price_key), false),
+                    AuctionParticipant::Resting(level, _) => (Some(level), true),
+                };
+                let price = match limit {
+                    None => i64::MIN,
+                    Some(limit) if is_buy_order => -limit,
+                    Some(limit) => limit,
+                };
+                (price, !from_book)
+            };
+            buys.sort_by_key(|participant| priority(participant, true));
+            sells.sort_by_key(|participant| priority(participant, false));
+
+            let (mut i, mut j) = (0, 0);
+            while volume > 0 && i < buys.len() && j < sells.len() {
+                let buy_left = self.participant_order(&mut auction, buys[i], true).remaining_quantity();
+                let sell_left = self.participant_order(&mut auction, sells[j], false).remaining_quantity();
+                let quantity = volume.min(buy_left).min(sell_left);
+                let part = OrderPart {
+                    price,
+                    quantity,
+                    execution_date: Utc::now(),
+                };
+                for (participant, is_buy_order) in [(buys[i], true), (sells[j], false)] {
+                    let order = self.participant_order(&mut auction, participant, is_buy_order);
+                    order.fill(part.clone());
+                    reports.push(ExecutionReport::new(order, vec![part.clone()]));
+                }
+                self.record_trade(&part);
+                volume -= quantity;
+                if quantity == buy_left {
+                    i += 1;
+                }
+                if quantity == sell_left {
+                    j += 1;
+                }
+            }
+            // Filled resting orders leave the book, the rest stay where they were queued
+            for side in [&mut self.bids, &mut self.asks] {
+                side.retain(|_, level| {
+                    level.retain(|resting| resting.order.remaining_quantity() > 0);
+                    !level.is_empty()
+                });
+            }
+            self.last_trade_price = Some(price);
+        }
+
+        for mut pending in auction {
+            if pending.order.remaining_quantity() > 0 {
+                pending.order.status = OrderStatus::Cancelled;
+                reports.push(ExecutionReport::new(&pending.order, Vec::new()));
+            }
+        }
+        reports
+    }
+
+    fn participant_order<'a>(
+        &'a mut self,
+        auction: &'a mut [PendingOrder],
+        participant: AuctionParticipant,
+        is_buy_order: bool,
+    ) -> &'a mut OrderI {
+        match participant {
+            AuctionParticipant::Auction(idx) => &mut auction[idx].order,
+            AuctionParticipant::Resting(key, pos) => {
+                let side = if is_buy_order { &mut self.bids } else { &mut self.asks };
+                &mut side.get_mut(&key).unwrap()[pos].order
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct StockPosition {
-    // Define the StockPosition struct as needed
-    symbol: String,
-    quantity: f64,
+// An order taking part in an auction: one of the auction orders, or a resting order given
+// by its price level and position in the level
+#[derive(Debug, Clone, Copy)]
+enum AuctionParticipant {
+    Auction(usize),
+    Resting(i64, usize),
 }
 
-impl StockPosition {
-    fn get_quantity(&self) -> i32 {
-        unimplemented!()
-    }
+// Everything the exchange writes to its database. Order submissions, cancellations and
+// session changes are replayed on restart to rebuild the books; the other records are
+// kept for auditing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum DbRecord {
+    OrderSaved(PendingOrder),
+    OrderUpdated(PendingOrder),
+    OrderCancelled { symbol: String, order_id: u32 },
+    SessionOpened,
+    SessionClosed,
+    Execution(ExecutionReport),
+    MemberSaved(Box<Member>),
 }
 
-struct StockLot<O: Order> {
-    lot_number: String,
-    buying_order: O
+trait Database: fmt::Debug {
+    fn append(&mut self, record: &DbRecord) -> bool;
+
+    // All records in the order they were appended
+    fn records(&self) -> Vec<DbRecord>;
 }
 
-impl <O: Order> StockLot<O> {
-    fn new(lot_number: String, buying_order: O) -> Self {
-        StockLot {
-            lot_number, buying_order
-        }
+#[derive(Debug, Default)]
+struct InMemoryDatabase {
+    records: Vec<DbRecord>,
+}
+
+impl InMemoryDatabase {
+    fn new() -> Self {
+        Self::default()
     }
+}
 
-    fn get_buying_price(&self) -> f64 {
-        unimplemented!()
+impl Database for InMemoryDatabase {
+    fn append(&mut self, record: &DbRecord) -> bool {
+        self.records.push(record.clone());
+        true
+    }
+
+    fn records(&self) -> Vec<DbRecord> {
+        self.records.clone()
     }
 }
 
-#[derive(Debug, Clone)]
-struct WatchList {
-    name: String,
-    stocks: Vec<Stock>,
+// Append-only log with one JSON record per line
+#[derive(Debug)]
+struct FileDatabase {
+    path: PathBuf,
 }
 
-#[derive(Debug, Clone)]
-struct Statement {
-    name: String,
-    description: String
+impl FileDatabase {
+    fn new(path: PathBuf) -> Self {
+        FileDatabase { path }
+    }
 }
 
-impl Statement {
-    fn get_statement(&self) -> String {
-        unimplemented!()
+impl Database for FileDatabase {
+    fn append(&mut self, record: &DbRecord) -> bool {
+        let Ok(line) = serde_json::to_string(record) else {
+            return false;
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .is_ok()
+    }
+
+    fn records(&self) -> Vec<DbRecord> {
+        let Ok(file) = File::open(&self.path) else {
+            return Vec::new();
+        };
+        // a torn last line from a crash mid-write is skipped
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .collect()
     }
 }
 
-struct QuarterlyStatement {
-    statement: Statement,
-    quarter_no: u32,
-    year: u32,
+#[derive(Debug, Clone, PartialEq)]
+struct Trade {
+    symbol: String,
+    price: f64,
+    quantity: i32,
+    executed_on: chrono::DateTime<Utc>,
 }
 
-struct AnnualStatement {
-    statement: Statement,
-    year: u32,
+// Top of book: best price and the quantity resting at it on each side
+#[derive(Debug, Clone, PartialEq)]
+struct Quote {
+    symbol: String,
+    best_bid: Option<(f64, i32)>,
+    best_ask: Option<(f64, i32)>,
 }
 
-#[derive(Debug, Clone)]
-struct TransferMoney {
-    amount: f64,
-    from_account_id: u32,
-    to_account_id: u32,
-    created_on: chrono::DateTime<Utc>,
+#[derive(Debug, Clone, PartialEq)]
+struct Bar {
+    symbol: String,
+    start: chrono::DateTime<Utc>,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    volume: i32,
 }
 
-struct ElectronicWireTransfer {
-    transfer: TransferMoney,
-    bank_name: String,
-    wire_id: u64
+#[derive(Debug, Clone, PartialEq)]
+enum MarketData {
+    Trade(Trade),
+    Quote(Quote),
+    Bar(Bar),
 }
 
-struct CheckTransfer {
-    transfer: TransferMoney,
-    bank_name: String,
-    check_number: String,
+impl MarketData {
+    fn get_symbol(&self) -> &str {
+        match self {
+            MarketData::Trade(trade) => &trade.symbol,
+            MarketData::Quote(quote) => &quote.symbol,
+            MarketData::Bar(bar) => &bar.symbol,
+        }
+    }
 }
 
+// Aggregates trades into OHLCV bars aligned on multiples of the interval
 #[derive(Debug, Clone)]
-struct Notification {
+struct BarAggregator {
+    interval: chrono::Duration,
+    current: Option<Bar>,
+}
+
+impl BarAggregator {
+    fn new(interval: chrono::Duration) -> Self {
+        BarAggregator { interval, current: None }
+    }
+
+    fn bar_start(&self, time: chrono::DateTime<Utc>) -> chrono::DateTime<Utc> {
+        let interval = self.interval.num_milliseconds().max(1);
+        let millis = time.timestamp_millis();
+        Utc.timestamp_millis_opt(millis - millis.rem_euclid(interval)).unwrap()
+    }
+
+    // Returns the previous bar once a trade falls into a later interval
+    fn add(&mut self, trade: &Trade) -> Option<Bar> {
+        let start = self.bar_start(trade.executed_on);
+        if let Some(bar) = self.current.as_mut()
+            && bar.start == start
+        {
+            bar.high = bar.high.max(trade.price);
+            bar.low = bar.low.min(trade.price);
+            bar.close = trade.price;
+            bar.volume += trade.quantity;
+            return None;
+        }
+        self.current.replace(Bar {
+            symbol: trade.symbol.clone(),
+            start,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.quantity,
+        })
+    }
+
+    fn flush(&mut self) -> Option<Bar> {
+        self.current.take()
+    }
+}
+
+#[derive(Debug)]
+enum MarketDataSink {
+    Channel(mpsc::Sender<MarketData>),
+    Stream(tokio::sync::mpsc::UnboundedSender<MarketData>),
+}
+
+impl MarketDataSink {
+    // False once the receiving side is gone
+    fn send(&self, data: MarketData) -> bool {
+        match self {
+            MarketDataSink::Channel(sender) => sender.send(data).is_ok(),
+            MarketDataSink::Stream(sender) => sender.send(data).is_ok(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    // An empty list subscribes to every symbol
+    symbols: Vec<String>,
+    bar_interval: Option<chrono::Duration>,
+    bars: HashMap<String, BarAggregator>,
+    sink: MarketDataSink,
+}
+
+impl Subscription {
+    fn new(symbols: &[&str], bar_interval: Option<chrono::Duration>, sink: MarketDataSink) -> Self {
+        Subscription {
+            symbols: symbols.iter().map(|symbol| symbol.to_string()).collect(),
+            bar_interval,
+            bars: HashMap::new(),
+            sink,
+        }
+    }
+
+    fn wants(&self, symbol: &str) -> bool {
+        self.symbols.is_empty() || self.symbols.iter().any(|s| s == symbol)
+    }
+
+    fn publish(&mut self, data: &MarketData) -> bool {
+        if !self.wants(data.get_symbol()) {
+            return true;
+        }
+        let mut connected = self.sink.send(data.clone());
+        if let (MarketData::Trade(trade), Some(interval)) = (data, self.bar_interval) {
+            let bar = self.bars
+                .entry(trade.symbol.clone())
+                .or_insert_with(|| BarAggregator::new(interval))
+                .add(trade);
+            if let Some(bar) = bar {
+                connected &= self.sink.send(MarketData::Bar(bar));
+            }
+        }
+        connected
+    }
+
+    // Publish the bars still being built, e.g. at the end of the session
+    fn flush_bars(&mut self) -> bool {
+        let mut connected = true;
+        for aggregator in self.bars.values_mut() {
+            if let Some(bar) = aggregator.flush() {
+                connected &= self.sink.send(MarketData::Bar(bar));
+            }
+        }
+        connected
+    }
+}
+
+#[derive(Debug, Default)]
+struct MarketDataFeed {
+    subscriptions: Vec<Subscription>,
+    quotes: HashMap<String, Quote>,
+}
+
+impl MarketDataFeed {
+    fn subscribe(&mut self, subscription: Subscription) {
+        self.subscriptions.push(subscription);
+    }
+
+    // Subscribers whose receiver was dropped are removed
+    fn publish(&mut self, data: MarketData) {
+        self.subscriptions.retain_mut(|subscription| subscription.publish(&data));
+    }
+
+    fn publish_quote(&mut self, quote: Quote) {
+        if self.quotes.get(&quote.symbol) != Some(&quote) {
+            self.quotes.insert(quote.symbol.clone(), quote.clone());
+            self.publish(MarketData::Quote(quote));
+        }
+    }
+
+    fn flush_bars(&mut self) {
+        self.subscriptions.retain_mut(|subscription| subscription.flush_bars());
+    }
+}
+
+#[derive(Debug, Default)]
+struct StockExchange {
+    instance: Option<Box<OnlyOne>>,
+}
+
+#[derive(Debug, Default)]
+struct OnlyOne {
+    order_books: HashMap<String, OrderBook>,
+    execution_reports: Vec<ExecutionReport>,
+    session_open: bool,
+    opening_auction: Vec<PendingOrder>,
+    closing_auction: Vec<PendingOrder>,
+    database: Option<Box<dyn Database>>,
+    market_data: MarketDataFeed,
+}
+
+impl StockExchange {
+    fn new() -> Self {
+        StockExchange {
+            instance: None
+        }
+    }
+
+    // Start the exchange on top of a database, rebuilding the books from its records
+    fn with_database(database: Box<dyn Database>) -> Self {
+        let mut instance = OnlyOne::new();
+        for record in database.records() {
+            match record {
+                DbRecord::OrderSaved(mut order) => {
+                    NEXT_ORDER_ID.fetch_max(order.get_order_id() + 1, Ordering::Relaxed);
+                    instance.submit_order(&mut order);
+                }
+                DbRecord::OrderCancelled { symbol, order_id } => {
+                    instance.cancel_order(&symbol, order_id);
+                }
+                DbRecord::SessionOpened => instance.open_session(),
+                DbRecord::SessionClosed => instance.close_session(),
+                DbRecord::OrderUpdated(_) | DbRecord::Execution(_) | DbRecord::MemberSaved(_) => {}
+            }
+        }
+        // those were delivered before the restart
+        instance.execution_reports.clear();
+        instance.publish_market_data();
+        instance.database = Some(database);
+        StockExchange {
+            instance: Some(Box::new(instance)),
+        }
+    }
+
+    fn get_instance(&mut self) -> &mut OnlyOne {
+        self.instance.get_or_insert_with(|| Box::new(OnlyOne::new()))
+    }
+
+    // Detach the database, e.g. to hand it to a restarted exchange
+    fn take_database(&mut self) -> Option<Box<dyn Database>> {
+        self.get_instance().database.take()
+    }
+
+    fn place_order<O: Order>(&mut self, order: &mut O) -> ReturnStatus {
+        let instance = self.get_instance();
+        let reported = instance.execution_reports.len();
+        if let Some(db) = instance.database.as_deref_mut() {
+            order.save_in_db(db);
+        }
+        let status = instance.submit_order(order);
+        if let Some(db) = instance.database.as_deref_mut() {
+            order.update_in_db(db);
+        }
+        instance.save_execution_reports(reported);
+        instance.publish_market_data();
+        status
+    }
+
+    fn cancel_order(&mut self, symbol: &str, order_id: u32) -> ReturnStatus {
+        let instance = self.get_instance();
+        let reported = instance.execution_reports.len();
+        let status = instance.cancel_order(symbol, order_id);
+        if status == ReturnStatus::Success {
+            instance.save_record(&DbRecord::OrderCancelled { symbol: symbol.to_string(), order_id });
+        }
+        instance.save_execution_reports(reported);
+        instance.publish_market_data();
+        status
+    }
+
+    // Run the opening auction for every symbol with OnTheOpen orders queued
+    fn open_session(&mut self) {
+        let instance = self.get_instance();
+        let reported = instance.execution_reports.len();
+        instance.save_record(&DbRecord::SessionOpened);
+        instance.open_session();
+        instance.save_execution_reports(reported);
+        instance.publish_market_data();
+    }
+
+    // Run the closing auction for every symbol with OnTheClose orders queued
+    fn close_session(&mut self) {
+        let instance = self.get_instance();
+        let reported = instance.execution_reports.len();
+        instance.save_record(&DbRecord::SessionClosed);
+        instance.close_session();
+        instance.save_execution_reports(reported);
+        instance.publish_market_data();
+        instance.market_data.flush_bars();
+    }
+
+    // Trade prints, top of book changes and, given an interval, OHLCV bars for the
+    // symbols (all of them when empty)
+    fn subscribe(&mut self, symbols: &[&str], bar_interval: Option<chrono::Duration>) -> mpsc::Receiver<MarketData> {
+        let (sender, receiver) = mpsc::channel();
+        let subscription = Subscription::new(symbols, bar_interval, MarketDataSink::Channel(sender));
+        self.get_instance().market_data.subscribe(subscription);
+        receiver
+    }
+
+    // Same as `subscribe`, delivered as an async stream
+    fn subscribe_stream(
+        &mut self, symbols: &[&str], bar_interval: Option<chrono::Duration>,
+    ) -> UnboundedReceiverStream<MarketData> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let subscription = Subscription::new(symbols, bar_interval, MarketDataSink::Stream(sender));
+        self.get_instance().market_data.subscribe(subscription);
+        UnboundedReceiverStream::new(receiver)
+    }
+
+    fn get_last_prices(&mut self) -> HashMap<String, f64> {
+        self.get_instance().order_books.iter()
+            .filter_map(|(symbol, book)| Some((symbol.clone(), book.last_trade_price?)))
+            .collect()
+    }
+
+    fn save_member(&mut self, member: &Member) -> bool {
+        self.get_instance().save_record(&DbRecord::MemberSaved(Box::new(member.clone())))
+    }
+
+    // Latest saved state of a member
+    fn load_member(&mut self, member_id: u32) -> Option<Member> {
+        let db = self.get_instance().database.as_ref()?;
+        db.records().into_iter().rev().find_map(|record| match record {
+            DbRecord::MemberSaved(member) if member.id == member_id => Some(*member),
+            _ => None,
+        })
+    }
+
+    fn get_order_book(&mut self, symbol: &str) -> Option<&OrderBook> {
+        self.get_instance().order_books.get(symbol)
+    }
+
+    // Fills and cancellations of resting orders since the last call, to be routed to the
+    // owning members through `Member::callback_stock_exchange`
+    fn take_execution_reports(&mut self) -> Vec<ExecutionReport> {
+        std::mem::take(&mut self.get_instance().execution_reports)
+    }
+}
+
+impl OnlyOne {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn save_record(&mut self, record: &DbRecord) -> bool {
+        match self.database.as_deref_mut() {
+            Some(db) => db.append(record),
+            None => false,
+        }
+    }
+
+    // Publish the trades since the last call and any top of book change
+    fn publish_market_data(&mut self) {
+        let mut books: Vec<&mut OrderBook> = self.order_books.values_mut().collect();
+        books.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        for book in books {
+            for trade in std::mem::take(&mut book.trades) {
+                self.market_data.publish(MarketData::Trade(trade));
+            }
+            self.market_data.publish_quote(book.quote());
+        }
+    }
+
+    // Persist the execution reports produced since `from`
+    fn save_execution_reports(&mut self, from: usize) {
+        if let Some(db) = self.database.as_deref_mut() {
+            for report in &self.execution_reports[from..] {
+                db.append(&DbRecord::Execution(report.clone()));
+            }
+        }
+    }
+
+    fn submit_order<O: Order>(&mut self, order: &mut O) -> ReturnStatus {
+        let limit = order.get_price_limit();
+        let stop = order.get_stop_price();
+        if order.get_order().quantity <= 0
+            || limit.is_some_and(|price| price <= 0.0)
+            || stop.is_some_and(|price| price <= 0.0)
+        {
+            return ReturnStatus::Fail;
+        }
+
+        let symbol = order.get_order().symbol.clone();
+        let book = self.order_books
+            .entry(symbol.clone())
+            .or_insert_with(|| OrderBook::new(symbol));
+        let time_enforcement = order.get_order().time_enforcement.clone();
+        let is_auction_order = matches!(
+            time_enforcement,
+            TimeEnforcementType::OnTheOpen | TimeEnforcementType::OnTheClose
+        );
+
+        if let Some(stop_price) = stop {
+            if is_auction_order {
+                return ReturnStatus::Fail;
+            }
+            if !book.stop_triggered(order.get_order().is_buy_order, stop_price) {
+                book.stop_orders.push(PendingOrder {
+                    order: order.get_order().clone(),
+                    price_limit: limit,
+                    stop_price: Some(stop_price),
+                });
+                return ReturnStatus::Success;
+            }
+        }
+
+        if is_auction_order {
+            // the opening auction of this session has already run
+            if time_enforcement == TimeEnforcementType::OnTheOpen && self.session_open {
+                return ReturnStatus::Fail;
+            }
+            let pending = PendingOrder {
+                order: order.get_order().clone(),
+                price_limit: limit,
+                stop_price: None,
+            };
+            if time_enforcement == TimeEnforcementType::OnTheOpen {
+                self.opening_auction.push(pending);
+            } else {
+                self.closing_auction.push(pending);
+            }
+            return ReturnStatus::Success;
+        }
+
+        let (status, reports) = book.execute(order.get_order_mut(), limit);
+        self.execution_reports.extend(reports);
+        self.execution_reports.extend(book.trigger_stops());
+        status
+    }
+
+    fn open_session(&mut self) {
+        self.session_open = true;
+        let auction = std::mem::take(&mut self.opening_auction);
+        self.run_auctions(auction);
+    }
+
+    fn close_session(&mut self) {
+        self.session_open = false;
+        let auction = std::mem::take(&mut self.closing_auction);
+        self.run_auctions(auction);
+    }
+
+    fn run_auctions(&mut self, auction: Vec<PendingOrder>) {
+        let mut by_symbol: BTreeMap<String, Vec<PendingOrder>> = BTreeMap::new();
+        for pending in auction {
+            by_symbol.entry(pending.order.symbol.clone()).or_default().push(pending);
+        }
+        for (symbol, orders) in by_symbol {
+            let book = self.order_books
+                .entry(symbol.clone())
+                .or_insert_with(|| OrderBook::new(symbol));
+            let reports = book.run_auction(orders);
+            self.execution_reports.extend(reports);
+            self.execution_reports.extend(book.trigger_stops());
+        }
+    }
+
+    fn cancel_order(&mut self, symbol: &str, order_id: u32) -> ReturnStatus {
+        let mut cancelled = None;
+        if let Some(book) = self.order_books.get_mut(symbol) {
+            cancelled = book.remove_resting(order_id).map(|order| order.order);
+            if let Some(idx) = book.stop_orders.iter().position(|pending| pending.order.order_id == order_id) {
+                cancelled = Some(book.stop_orders.remove(idx).order);
+            }
+        }
+        for auction in [&mut self.opening_auction, &mut self.closing_auction] {
+            if let Some(idx) = auction.iter().position(|pending| pending.order.order_id == order_id) {
+                cancelled = Some(auction.remove(idx).order);
+            }
+        }
+        match cancelled {
+            Some(mut order) => {
+                order.status = OrderStatus::Cancelled;
+                self.execution_reports.push(ExecutionReport::new(&order, Vec::new()));
+                ReturnStatus::Success
+            }
+            None => ReturnStatus::Fail,
+        }
+    }
+}
+
+// Salted SHA-256, iterated so that every guess at a password costs as many hashes. This is
+// simple key stretching rather than a memory-hard KDF such as Argon2.
+const HASH_ROUNDS: u32 = 10_000;
+
+fn hash_secret(salt: &str, secret: &str) -> String {
+    let mut digest = Sha256::digest(format!("{}:{}", salt, secret).as_bytes());
+    for _ in 1..HASH_ROUNDS {
+        digest = Sha256::new().chain_update(salt.as_bytes()).chain_update(digest).finalize();
+    }
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Looks at every byte whatever the first difference, so the time taken does not tell how
+// much of a hash was guessed right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Only hashes are kept, for the password as well as for a pending reset token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Credentials {
+    salt: String,
+    password_hash: String,
+    reset_token_hash: Option<String>,
+    reset_token_expires_on: Option<chrono::DateTime<Utc>>,
+}
+
+impl Credentials {
+    const RESET_TOKEN_VALIDITY_MINUTES: i64 = 30;
+
+    fn new(password: &str) -> Self {
+        let salt = uuid::Uuid::new_v4().simple().to_string();
+        Credentials {
+            password_hash: hash_secret(&salt, password),
+            salt,
+            reset_token_hash: None,
+            reset_token_expires_on: None,
+        }
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        constant_time_eq(&hash_secret(&self.salt, password), &self.password_hash)
+    }
+}
+
+trait Account {
+    fn get_credentials(&self) -> &Credentials;
+    fn get_credentials_mut(&mut self) -> &mut Credentials;
+
+    fn check_password(&self, password: &str) -> bool {
+        self.get_credentials().check_password(password)
+    }
+
+    // Issue a single use token to be sent to the account holder, replacing any earlier one
+    fn request_password_reset(&mut self) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let credentials = self.get_credentials_mut();
+        credentials.reset_token_hash = Some(hash_secret(&credentials.salt, &token));
+        credentials.reset_token_expires_on =
+            Some(Utc::now() + chrono::Duration::minutes(Credentials::RESET_TOKEN_VALIDITY_MINUTES));
+        token
+    }
+
+    fn reset_password(&mut self, token: &str, new_password: &str) -> bool {
+        let credentials = self.get_credentials_mut();
+        let (Some(token_hash), Some(expires_on)) = (&credentials.reset_token_hash, credentials.reset_token_expires_on)
+        else {
+            return false;
+        };
+        if expires_on < Utc::now() || !constant_time_eq(token_hash, &hash_secret(&credentials.salt, token)) {
+            return false;
+        }
+        *credentials = Credentials::new(new_password);
+        true
+    }
+}
+
+impl AccountStatus {
+    fn can_trade(&self) -> bool {
+        *self == AccountStatus::Active
+    }
+}
+
+struct Admin {
+    id: u32,
+    credentials: Credentials,
+    name: String,
+    address: String,
+    email: String,
+    phone: String,
+    status: AccountStatus,
+    available_funds_for_trading: f64,
+    date_of_membership: chrono::DateTime<Utc>,
+    stock_positions: HashMap<String, StockPosition>,
+    active_orders: HashMap<u32, LimitOrder>,
+}
+
+impl Account for Admin {
+    fn get_credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    fn get_credentials_mut(&mut self) -> &mut Credentials {
+        &mut self.credentials
+    }
+}
+
+impl Admin {
+    fn new(id: u32, password: String, name: String, address: String, email: String, phone: String) -> Self {
+        Admin {
+            id,
+            credentials: Credentials::new(&password),
+            name,
+            address,
+            email,
+            phone,
+            status: AccountStatus::Active,
+            available_funds_for_trading: 0.0,
+            date_of_membership: chrono::Utc::now(),
+            stock_positions: HashMap::new(),
+            active_orders: HashMap::new(),
+        }
+    }
+
+    fn block_member(&self, stock_exchange: &mut StockExchange, member: &mut Member) -> bool {
+        if member.status != AccountStatus::Active {
+            return false;
+        }
+        self.set_member_status(stock_exchange, member, AccountStatus::Blocked)
+    }
+
+    fn unblock_member(&self, stock_exchange: &mut StockExchange, member: &mut Member) -> bool {
+        if member.status != AccountStatus::Blocked {
+            return false;
+        }
+        self.set_member_status(stock_exchange, member, AccountStatus::Active)
+    }
+
+    // Members that may no longer trade get their open orders cancelled
+    fn set_member_status(&self, stock_exchange: &mut StockExchange, member: &mut Member, status: AccountStatus) -> bool {
+        if !self.status.can_trade() {
+            return false;
+        }
+        member.status = status;
+        if !member.status.can_trade() {
+            member.cancel_open_orders(stock_exchange);
+        }
+        stock_exchange.save_member(member);
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Member {
+    id: u32,
+    credentials: Credentials,
+    name: String,
+    address: String,
+    email: String,
+    phone: String,
+    status: AccountStatus,
+    // Cash that is not tied up by open buy orders
+    available_funds_for_trading: f64,
+    reserved_funds: f64,
+    date_of_membership: chrono::DateTime<Utc>,
+    stock_positions: HashMap<String, StockPosition>,
+    active_orders: HashMap<u32, LimitOrder>,
+    // Sell orders that should consume a particular lot instead of the oldest ones
+    lot_selections: HashMap<u32, String>,
+    fills: Vec<Fill>,
+    realized_gains: Vec<RealizedGain>,
+    transfers: Vec<Transfer>,
+    notification_channels: Vec<NotificationChannel>,
+    // Not yet delivered
+    notifications: Vec<Notification>,
+}
+
+impl Account for Member {
+    fn get_credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    fn get_credentials_mut(&mut self) -> &mut Credentials {
+        &mut self.credentials
+    }
+}
+
+impl Member {
+    fn new(id: u32, password: String, name: String, address: String, email: String, phone: String) -> Self {
+        Member {
+            id,
+            credentials: Credentials::new(&password),
+            name,
+            address,
+            email,
+            phone,
+            status: AccountStatus::Active,
+            available_funds_for_trading: 0.0,
+            reserved_funds: 0.0,
+            date_of_membership: chrono::Utc::now(),
+            stock_positions: HashMap::new(),
+            active_orders: HashMap::new(),
+            lot_selections: HashMap::new(),
+            fills: Vec::new(),
+            realized_gains: Vec::new(),
+            transfers: Vec::new(),
+            notification_channels: vec![NotificationChannel::Email],
+            notifications: Vec::new(),
+        }
+    }
+
+    fn place_sell_limit_order(
+        &mut self,
+        stock_exchange: &mut StockExchange,
+        symbol: &str, quantity: i32,
+        limit_price: f64, enforcement_type: TimeEnforcementType
+    ) -> ReturnStatus {
+        self.place_sell_order(stock_exchange, symbol, None, quantity, limit_price, enforcement_type)
+    }
+
+    // Sell out of a specific lot; anything the lot cannot cover is taken from the oldest lots
+    fn place_sell_limit_order_from_lot(
+        &mut self,
+        stock_exchange: &mut StockExchange,
+        symbol: &str, lot_number: &str, quantity: i32,
+        limit_price: f64, enforcement_type: TimeEnforcementType
+    ) -> ReturnStatus {
+        self.place_sell_order(stock_exchange, symbol, Some(lot_number), quantity, limit_price, enforcement_type)
+    }
+
+    fn place_sell_order(
+        &mut self,
+        stock_exchange: &mut StockExchange,
+        symbol: &str, lot_number: Option<&str>, quantity: i32,
+        limit_price: f64, enforcement_type: TimeEnforcementType
+    ) -> ReturnStatus {
+        if !self.status.can_trade() {
+            return ReturnStatus::AccountNotActive;
+        }
+        // check if member has this stock position
+        let Some(stock_position) = self.stock_positions.get_mut(symbol) else {
+            self.notify(format!("Cannot sell {} {}: no position", quantity, symbol));
+            return ReturnStatus::NoStockPosition;
+        };
+
+        // check if the member has enough quantity available to sell
+        let available = stock_position.get_available_quantity();
+        if available < quantity {
+            self.notify(format!("Cannot sell {} {}: only {} available", quantity, symbol, available));
+            return ReturnStatus::InsufficientQuantity;
+        }
+        // a lot can only back what other open orders have not already claimed from it
+        if let Some(lot_number) = lot_number {
+            match stock_position.lots.iter_mut().find(|lot| lot.lot_number == lot_number) {
+                Some(lot) if lot.get_available_quantity() >= quantity => lot.reserved_quantity += quantity,
+                Some(lot) => {
+                    let available = lot.get_available_quantity();
+                    self.notify(format!("Cannot sell {} {} from lot {}: only {} available", quantity, symbol, lot_number, available));
+                    return ReturnStatus::InsufficientQuantity;
+                }
+                None => {
+                    self.notify(format!("Cannot sell {} {}: no lot {}", quantity, symbol, lot_number));
+                    return ReturnStatus::NoStockPosition;
+                }
+            }
+        }
+        stock_position.reserved_quantity += quantity;
+
+        let mut order = LimitOrder::new(next_order_id(), symbol.to_string(), quantity, limit_price, enforcement_type);
+        order.order.is_buy_order = false;
+        order.order.member_id = self.id;
+        if let Some(lot_number) = lot_number {
+            self.lot_selections.insert(order.get_order_id(), lot_number.to_string());
+        }
+        let success = stock_exchange.place_order(&mut order);
+        if success != ReturnStatus::Success {
+            order.set_status(OrderStatus::Cancelled);
+        }
+        self.track_order(order);
+        stock_exchange.save_member(self);
+        success
+    }
+
+    fn place_buy_limit_order(
+        &mut self, 
+        stock_exchange: &mut StockExchange,
+        symbol: &str, quantity: i32, 
+        limit_price: f64, enforcement_type: TimeEnforcementType
+    ) -> ReturnStatus {
+        if !self.status.can_trade() {
+            return ReturnStatus::AccountNotActive;
+        }
+        // check if the member has enough funds to buy this stock
+        let cost = quantity as f64 * limit_price;
+        if self.available_funds_for_trading < cost {
+            self.notify(format!(
+                "Cannot buy {} {} at {:.2}: needs {:.2}, available funds {:.2}",
+                quantity, symbol, limit_price, cost, self.available_funds_for_trading,
+            ));
+            return ReturnStatus::InsufficientFunds;
+        }
+        self.available_funds_for_trading -= cost;
+        self.reserved_funds += cost;
+
+        let mut order = LimitOrder::new(next_order_id(), symbol.to_string(), quantity, limit_price, enforcement_type);
+        order.order.is_buy_order = true;
+        order.order.member_id = self.id;
+        let success = stock_exchange.place_order(&mut order);
+        if success != ReturnStatus::Success {
+            order.set_status(OrderStatus::Cancelled);
+        }
+        self.track_order(order);
+        stock_exchange.save_member(self);
+        success
+    }
+
+    // Book the fills the order got on submission and keep it around until the exchange
+    // reports it as filled or cancelled
+    fn track_order(&mut self, order: LimitOrder) {
+        self.apply_fills(&order, &order.order.parts);
+        match order.order.status {
+            OrderStatus::Open | OrderStatus::PartiallyFilled => {
+                self.active_orders.insert(order.get_order_id(), order);
+            }
+            OrderStatus::Filled => self.release_reservation(&order),
+            OrderStatus::Cancelled => {
+                self.notify_cancelled(&order);
+                self.release_reservation(&order);
+            }
+        }
+    }
+
+    fn callback_stock_exchange(&mut self, order_id: u32, order_parts: Vec<OrderPart>, status: OrderStatus) {
+        let Some(mut order) = self.active_orders.remove(&order_id) else {
+            return;
+        };
+        order.add_order_parts(order_parts.clone());
+        order.set_status(status.clone());
+        self.apply_fills(&order, &order_parts);
+
+        if status == OrderStatus::Cancelled {
+            self.notify_cancelled(&order);
+        }
+        if status == OrderStatus::Filled || status == OrderStatus::Cancelled {
+            self.release_reservation(&order);
+        } else {
+            self.active_orders.insert(order_id, order);
+        }
+    }
+
+    fn cancel_open_orders(&mut self, stock_exchange: &mut StockExchange) {
+        let mut orders: Vec<(u32, String)> = self.active_orders.values()
+            .map(|order| (order.get_order_id(), order.order.symbol.clone()))
+            .collect();
+        orders.sort();
+        for (order_id, symbol) in orders {
+            if stock_exchange.cancel_order(&symbol, order_id) == ReturnStatus::Success {
+                self.callback_stock_exchange(order_id, Vec::new(), OrderStatus::Cancelled);
+            }
+        }
+    }
+
+    fn apply_fills(&mut self, order: &LimitOrder, parts: &[OrderPart]) {
+        for part in parts {
+            let symbol = &order.order.symbol;
+            self.fills.push(Fill {
+                order_id: order.get_order_id(),
+                symbol: symbol.clone(),
+                is_buy_order: order.order.is_buy_order,
+                price: part.price,
+                quantity: part.quantity,
+                execution_date: part.execution_date,
+            });
+            self.notify(format!(
+                "{} {} {} at {:.2} (order {})",
+                if order.order.is_buy_order { "Bought" } else { "Sold" },
+                part.quantity, symbol, part.price, order.get_order_id(),
+            ));
+
+            if order.order.is_buy_order {
+                // the reservation was made at the limit, refund any price improvement
+                let reserved = part.quantity as f64 * order.price_limit;
+                self.reserved_funds -= reserved;
+                self.available_funds_for_trading += reserved - part.quantity as f64 * part.price;
+                self.stock_positions
+                    .entry(symbol.clone())
+                    .or_insert_with(|| StockPosition::new(symbol.clone()))
+                    .add_lot(order.get_order_id(), part);
+            } else {
+                self.available_funds_for_trading += part.quantity as f64 * part.price;
+                let lot_number = self.lot_selections.get(&order.get_order_id()).cloned();
+                if let Some(position) = self.stock_positions.get_mut(symbol) {
+                    position.reserved_quantity -= part.quantity;
+                    let gains = position.sell(part, lot_number.as_deref());
+                    self.realized_gains.extend(gains);
+                    if position.get_quantity() == 0 && position.reserved_quantity == 0 {
+                        self.stock_positions.remove(symbol);
+                    }
+                }
+            }
+        }
+    }
+
+    // Give back whatever the unfilled part of a finished order was holding
+    fn release_reservation(&mut self, order: &LimitOrder) {
+        let remaining = order.order.remaining_quantity();
+        if order.order.is_buy_order {
+            let reserved = remaining as f64 * order.price_limit;
+            self.reserved_funds -= reserved;
+            self.available_funds_for_trading += reserved;
+        } else if let Some(position) = self.stock_positions.get_mut(&order.order.symbol) {
+            position.reserved_quantity -= remaining;
+            if let Some(lot_number) = self.lot_selections.get(&order.get_order_id()) {
+                position.release_lot(lot_number, remaining);
+            }
+        }
+        self.lot_selections.remove(&order.get_order_id());
+    }
+
+    // Money coming in from a bank account. It can only be traded once the transfer clears.
+    fn deposit_money(&mut self, transfer: Transfer) -> ReturnStatus {
+        if transfer.get_transfer().to_account_id != self.id {
+            return ReturnStatus::Fail;
+        }
+        self.submit_transfer(transfer)
+    }
+
+    // Money going out to a bank account. It is held back from trading while the transfer
+    // is pending and given back if it fails.
+    fn withdraw_money(&mut self, transfer: Transfer) -> ReturnStatus {
+        let amount = transfer.get_transfer().amount;
+        if transfer.get_transfer().from_account_id != self.id {
+            return ReturnStatus::Fail;
+        }
+        if amount > self.available_funds_for_trading {
+            self.notify(format!(
+                "Cannot withdraw {:.2}: available funds {:.2}",
+                amount, self.available_funds_for_trading,
+            ));
+            return ReturnStatus::InsufficientFunds;
+        }
+        let status = self.submit_transfer(transfer);
+        if status == ReturnStatus::Success {
+            self.available_funds_for_trading -= amount;
+        }
+        status
+    }
+
+    fn submit_transfer(&mut self, mut transfer: Transfer) -> ReturnStatus {
+        let amount = transfer.get_transfer().amount;
+        if amount <= 0.0 {
+            return ReturnStatus::Fail;
+        }
+        if amount > Constants::new().money_transfer_limit as f64 {
+            return ReturnStatus::MoneyTransferLimitExceeded;
+        }
+        transfer.get_transfer_mut().status = TransferStatus::Pending;
+        self.transfers.push(transfer);
+        ReturnStatus::Success
+    }
+
+    fn clear_transfer(&mut self, transfer_id: u32, cleared_on: chrono::DateTime<Utc>) -> bool {
+        self.settle_transfer(transfer_id, TransferStatus::Cleared, Some(cleared_on))
+    }
+
+    fn fail_transfer(&mut self, transfer_id: u32) -> bool {
+        self.settle_transfer(transfer_id, TransferStatus::Failed, None)
+    }
+
+    // Move a pending transfer to its final state
+    fn settle_transfer(
+        &mut self,
+        transfer_id: u32,
+        status: TransferStatus,
+        cleared_on: Option<chrono::DateTime<Utc>>,
+    ) -> bool {
+        let Some(transfer) = self.transfers.iter_mut()
+            .find(|transfer| transfer.get_transfer().id == transfer_id)
+        else {
+            return false;
+        };
+        if transfer.get_transfer().status != TransferStatus::Pending {
+            return false;
+        }
+        transfer.get_transfer_mut().status = status.clone();
+        transfer.get_transfer_mut().cleared_on = cleared_on;
+        let amount = transfer.get_signed_amount(self.id);
+        let message = format!(
+            "{} of {:.2} by {} {}",
+            if amount < 0.0 { "Withdrawal" } else { "Deposit" },
+            amount.abs(),
+            transfer.get_description(),
+            if status == TransferStatus::Cleared { "completed" } else { "failed" },
+        );
+        match status {
+            // withdrawals were taken out when they were submitted
+            TransferStatus::Cleared if amount > 0.0 => self.available_funds_for_trading += amount,
+            TransferStatus::Failed if amount < 0.0 => self.available_funds_for_trading -= amount,
+            _ => {}
+        }
+        self.notify(message);
+        true
+    }
+
+    fn notify(&mut self, message: String) {
+        self.notifications.push(Notification::new(message));
+    }
+
+    fn notify_cancelled(&mut self, order: &LimitOrder) {
+        self.notify(format!(
+            "Order {} cancelled with {} {} unfilled",
+            order.get_order_id(), order.order.remaining_quantity(), order.order.symbol,
+        ));
+    }
+
+    fn set_notification_channels(&mut self, channels: Vec<NotificationChannel>) {
+        self.notification_channels = channels;
+    }
+
+    fn get_notifiers(&self, notification: &Notification) -> Vec<Box<dyn Notifier>> {
+        self.notification_channels.iter()
+            .map(|channel| -> Box<dyn Notifier> {
+                let notification = notification.clone();
+                let phone_number = self.phone.clone();
+                match channel {
+                    NotificationChannel::Sms => Box::new(SMSNotification { notification, phone_number }),
+                    NotificationChannel::Push => Box::new(PushNotification { notification, phone_number }),
+                    NotificationChannel::Email => {
+                        Box::new(EmailNotification { notification, email_address: self.email.clone() })
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Send the pending notifications over every preferred channel. Those no channel
+    // accepted stay pending. Returns the number of deliveries.
+    fn dispatch_notifications(&mut self, sink: &mut dyn NotificationSink) -> usize {
+        let mut delivered = 0;
+        let mut pending = Vec::new();
+        for notification in std::mem::take(&mut self.notifications) {
+            let sent = self.get_notifiers(&notification).iter()
+                .filter(|notifier| notifier.send(sink))
+                .count();
+            if sent == 0 {
+                pending.push(notification);
+            }
+            delivered += sent;
+        }
+        self.notifications = pending;
+        delivered
+    }
+
+    fn get_realized_pnl(&self) -> f64 {
+        self.realized_gains.iter().map(|gain| gain.get_gain()).sum()
+    }
+
+    // Profit or loss of the open positions against the given quotes
+    fn get_unrealized_pnl(&self, stocks: &[Stock]) -> f64 {
+        stocks.iter()
+            .filter_map(|stock| {
+                self.stock_positions.get(&stock.symbol).map(|position| position.get_unrealized_pnl(stock))
+            })
+            .sum()
+    }
+}
+
+// A single execution as seen from the member's account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Fill {
+    order_id: u32,
+    symbol: String,
+    is_buy_order: bool,
+    price: f64,
+    quantity: i32,
+    execution_date: chrono::DateTime<Utc>,
+}
+
+impl Fill {
+    // Cash effect of the fill: buys cost money, sells bring it in
+    fn get_signed_amount(&self) -> f64 {
+        let amount = self.quantity as f64 * self.price;
+        if self.is_buy_order { -amount } else { amount }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RealizedGain {
+    symbol: String,
+    lot_number: String,
+    quantity: i32,
+    buying_price: f64,
+    selling_price: f64,
+    sold_on: chrono::DateTime<Utc>,
+}
+
+impl RealizedGain {
+    fn get_gain(&self) -> f64 {
+        self.quantity as f64 * (self.selling_price - self.buying_price)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StockPosition {
+    symbol: String,
+    // Oldest lot first
+    lots: Vec<StockLot>,
+    // Shares promised to open sell orders
+    reserved_quantity: i32,
+    lots_created: u32,
+}
+
+impl StockPosition {
+    fn new(symbol: String) -> Self {
+        StockPosition {
+            symbol,
+            lots: Vec::new(),
+            reserved_quantity: 0,
+            lots_created: 0,
+        }
+    }
+
+    fn get_quantity(&self) -> i32 {
+        self.lots.iter().map(|lot| lot.quantity).sum()
+    }
+
+    fn get_available_quantity(&self) -> i32 {
+        self.get_quantity() - self.reserved_quantity
+    }
+
+    fn get_cost_basis(&self) -> f64 {
+        self.lots.iter().map(|lot| lot.quantity as f64 * lot.get_buying_price()).sum()
+    }
+
+    fn get_average_cost(&self) -> f64 {
+        let quantity = self.get_quantity();
+        if quantity == 0 {
+            return 0.0;
+        }
+        self.get_cost_basis() / quantity as f64
+    }
+
+    fn get_unrealized_pnl(&self, stock: &Stock) -> f64 {
+        self.get_quantity() as f64 * stock.get_price() - self.get_cost_basis()
+    }
+
+    fn add_lot(&mut self, order_id: u32, part: &OrderPart) {
+        self.lots_created += 1;
+        let lot_number = format!("{}-{}", self.symbol, self.lots_created);
+        self.lots.push(StockLot::new(lot_number, order_id, part));
+    }
+
+    fn release_lot(&mut self, lot_number: &str, quantity: i32) {
+        if let Some(lot) = self.lots.iter_mut().find(|lot| lot.lot_number == lot_number) {
+            lot.reserved_quantity -= quantity;
+        }
+    }
+
+    // Take the sold shares out of the requested lot first, using up its reservation, then
+    // oldest lot first from the shares no other order has claimed
+    fn sell(&mut self, part: &OrderPart, lot_number: Option<&str>) -> Vec<RealizedGain> {
+        let mut order: Vec<usize> = (0..self.lots.len()).collect();
+        let selected = lot_number.and_then(|number| self.lots.iter().position(|lot| lot.lot_number == number));
+        if let Some(idx) = selected {
+            order.retain(|i| *i != idx);
+            order.insert(0, idx);
+        }
+
+        let mut gains = Vec::new();
+        let mut remaining = part.quantity;
+        for idx in order {
+            if remaining == 0 {
+                break;
+            }
+            let lot = &mut self.lots[idx];
+            let quantity = if selected == Some(idx) {
+                let quantity = remaining.min(lot.quantity);
+                lot.reserved_quantity -= quantity.min(lot.reserved_quantity);
+                quantity
+            } else {
+                remaining.min(lot.get_available_quantity())
+            };
+            if quantity == 0 {
+                continue;
+            }
+            lot.quantity -= quantity;
+            remaining -= quantity;
+            gains.push(RealizedGain {
+                symbol: self.symbol.clone(),
+                lot_number: lot.lot_number.clone(),
+                quantity,
+                buying_price: lot.get_buying_price(),
+                selling_price: part.price,
+                sold_on: part.execution_date,
+            });
+        }
+        self.lots.retain(|lot| lot.quantity > 0);
+        gains
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StockLot {
+    lot_number: String,
+    buying_order_id: u32,
+    quantity: i32,
+    buying_price: f64,
+    bought_on: chrono::DateTime<Utc>,
+    // Shares promised to open sell orders for this lot
+    #[serde(default)]
+    reserved_quantity: i32,
+}
+
+impl StockLot {
+    fn new(lot_number: String, buying_order_id: u32, part: &OrderPart) -> Self {
+        StockLot {
+            lot_number,
+            buying_order_id,
+            quantity: part.quantity,
+            buying_price: part.price,
+            bought_on: part.execution_date,
+            reserved_quantity: 0,
+        }
+    }
+
+    fn get_buying_price(&self) -> f64 {
+        self.buying_price
+    }
+
+    fn get_available_quantity(&self) -> i32 {
+        self.quantity - self.reserved_quantity
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WatchList {
+    name: String,
+    stocks: Vec<Stock>,
+}
+
+impl WatchList {
+    fn new(name: String, stocks: Vec<Stock>) -> Self {
+        WatchList { name, stocks }
+    }
+
+    fn subscribe(
+        &self, stock_exchange: &mut StockExchange, bar_interval: Option<chrono::Duration>,
+    ) -> mpsc::Receiver<MarketData> {
+        let symbols: Vec<&str> = self.stocks.iter().map(|stock| stock.symbol.as_str()).collect();
+        stock_exchange.subscribe(&symbols, bar_interval)
+    }
+
+    // Keep the listed prices at the last trade
+    fn update(&mut self, data: &MarketData) {
+        if let MarketData::Trade(trade) = data {
+            for stock in self.stocks.iter_mut().filter(|stock| stock.symbol == trade.symbol) {
+                stock.price = trade.price;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    name: String,
+    description: String,
+    member_id: u32,
+    member_name: String,
+    // The period is [period_start, period_end)
+    period_start: chrono::DateTime<Utc>,
+    period_end: chrono::DateTime<Utc>,
+    opening_balance: f64,
+    closing_balance: f64,
+    transfers: Vec<Transfer>,
+    trades: Vec<Fill>,
+    realized_gains: Vec<RealizedGain>,
+    // Shares held per symbol at the end of the period
+    positions: BTreeMap<String, i32>,
+}
+
+impl Statement {
+    // Balances are rebuilt from the member's recorded transfers and fills. A transfer counts
+    // from the day it cleared, as that is when the money moved.
+    fn new(
+        name: String,
+        description: String,
+        member: &Member,
+        period_start: chrono::DateTime<Utc>,
+        period_end: chrono::DateTime<Utc>,
+    ) -> Self {
+        let in_period = |date: &chrono::DateTime<Utc>| *date >= period_start && *date < period_end;
+
+        let mut opening_balance = 0.0;
+        let mut closing_balance = 0.0;
+        for transfer in &member.transfers {
+            let Some(cleared_on) = transfer.get_cleared_on() else {
+                continue;
+            };
+            if cleared_on < period_end {
+                closing_balance += transfer.get_signed_amount(member.id);
+                if cleared_on < period_start {
+                    opening_balance += transfer.get_signed_amount(member.id);
+                }
+            }
+        }
+
+        let mut positions = BTreeMap::new();
+        for fill in &member.fills {
+            if fill.execution_date < period_end {
+                closing_balance += fill.get_signed_amount();
+                if fill.execution_date < period_start {
+                    opening_balance += fill.get_signed_amount();
+                }
+                let quantity = if fill.is_buy_order { fill.quantity } else { -fill.quantity };
+                *positions.entry(fill.symbol.clone()).or_insert(0) += quantity;
+            }
+        }
+        positions.retain(|_, quantity| *quantity != 0);
+
+        Statement {
+            name,
+            description,
+            member_id: member.id,
+            member_name: member.name.clone(),
+            period_start,
+            period_end,
+            opening_balance,
+            closing_balance,
+            transfers: member.transfers.iter()
+                .filter(|transfer| transfer.get_cleared_on().is_some_and(|cleared_on| in_period(&cleared_on)))
+                .cloned()
+                .collect(),
+            trades: member.fills.iter()
+                .filter(|fill| in_period(&fill.execution_date))
+                .cloned()
+                .collect(),
+            realized_gains: member.realized_gains.iter()
+                .filter(|gain| in_period(&gain.sold_on))
+                .cloned()
+                .collect(),
+            positions,
+        }
+    }
+
+    fn get_realized_gain(&self) -> f64 {
+        self.realized_gains.iter().map(|gain| gain.get_gain()).sum()
+    }
+
+    fn last_day(&self) -> chrono::NaiveDate {
+        (self.period_end - chrono::Duration::days(1)).date_naive()
+    }
+
+    fn get_statement(&self) -> String {
+        let mut lines = vec![
+            self.name.clone(),
+            self.description.clone(),
+            format!("Member: {} ({})", self.member_name, self.member_id),
+            format!("Period: {} to {}", self.period_start.date_naive(), self.last_day()),
+            format!("Opening balance: {:.2}", self.opening_balance),
+            String::new(),
+            "Transfers".to_string(),
+        ];
+        for transfer in &self.transfers {
+            lines.push(format!(
+                "  {}  {:<30} {:>12.2}",
+                transfer.get_cleared_on().unwrap().date_naive(),
+                transfer.get_description(),
+                transfer.get_signed_amount(self.member_id),
+            ));
+        }
+        lines.push("Trades".to_string());
+        for fill in &self.trades {
+            lines.push(format!(
+                "  {}  {:<4} {:<6} {:>8} @ {:>10.2} {:>12.2}",
+                fill.execution_date.date_naive(),
+                if fill.is_buy_order { "BUY" } else { "SELL" },
+                fill.symbol,
+                fill.quantity,
+                fill.price,
+                fill.get_signed_amount(),
+            ));
+        }
+        lines.push("Realized gains".to_string());
+        for gain in &self.realized_gains {
+            lines.push(format!(
+                "  {}  {:<6} lot {:<12} {:>8} {:>10.2} -> {:>10.2} {:>12.2}",
+                gain.sold_on.date_naive(),
+                gain.symbol,
+                gain.lot_number,
+                gain.quantity,
+                gain.buying_price,
+                gain.selling_price,
+                gain.get_gain(),
+            ));
+        }
+        lines.push("Positions".to_string());
+        for (symbol, quantity) in &self.positions {
+            lines.push(format!("  {:<6} {:>8}", symbol, quantity));
+        }
+        lines.push(String::new());
+        lines.push(format!("Total realized gain: {:.2}", self.get_realized_gain()));
+        lines.push(format!("Closing balance: {:.2}", self.closing_balance));
+        lines.join("\n")
+    }
+
+    fn get_csv(&self) -> String {
+        let mut rows = vec!["section,date,description,quantity,price,amount".to_string()];
+        let mut push = |section: &str, date: String, description: &str, quantity: String, price: String, amount: String| {
+            rows.push([section, &date, &csv_field(description), &quantity, &price, &amount].join(","));
+        };
+
+        push("balance", self.period_start.date_naive().to_string(), "Opening balance",
+            String::new(), String::new(), format!("{:.2}", self.opening_balance));
+        for transfer in &self.transfers {
+            push("transfer", transfer.get_cleared_on().unwrap().date_naive().to_string(), &transfer.get_description(),
+                String::new(), String::new(), format!("{:.2}", transfer.get_signed_amount(self.member_id)));
+        }
+        for fill in &self.trades {
+            let side = if fill.is_buy_order { "BUY" } else { "SELL" };
+            push("trade", fill.execution_date.date_naive().to_string(), &format!("{} {}", side, fill.symbol),
+                fill.quantity.to_string(), format!("{:.2}", fill.price), format!("{:.2}", fill.get_signed_amount()));
+        }
+        for gain in &self.realized_gains {
+            push("realized_gain", gain.sold_on.date_naive().to_string(), &format!("{} lot {}", gain.symbol, gain.lot_number),
+                gain.quantity.to_string(), format!("{:.2}", gain.selling_price), format!("{:.2}", gain.get_gain()));
+        }
+        for (symbol, quantity) in &self.positions {
+            push("position", self.last_day().to_string(), symbol,
+                quantity.to_string(), String::new(), String::new());
+        }
+        push("balance", self.last_day().to_string(), "Closing balance",
+            String::new(), String::new(), format!("{:.2}", self.closing_balance));
+        rows.join("\n")
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn start_of_month(year: i32, month: u32) -> chrono::DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap()
+}
+
+struct QuarterlyStatement {
+    statement: Statement,
+    quarter_no: u32,
+    year: u32,
+}
+
+impl QuarterlyStatement {
+    fn new(member: &Member, quarter_no: u32, year: u32) -> Self {
+        assert!((1..=4).contains(&quarter_no), "quarter must be between 1 and 4");
+        let first_month = (quarter_no - 1) * 3 + 1;
+        let period_end = if quarter_no == 4 {
+            start_of_month(year as i32 + 1, 1)
+        } else {
+            start_of_month(year as i32, first_month + 3)
+        };
+        QuarterlyStatement {
+            statement: Statement::new(
+                format!("Quarterly statement Q{} {}", quarter_no, year),
+                "Account activity for the quarter".to_string(),
+                member,
+                start_of_month(year as i32, first_month),
+                period_end,
+            ),
+            quarter_no,
+            year,
+        }
+    }
+}
+
+struct AnnualStatement {
+    statement: Statement,
+    year: u32,
+}
+
+impl AnnualStatement {
+    fn new(member: &Member, year: u32) -> Self {
+        AnnualStatement {
+            statement: Statement::new(
+                format!("Annual statement {}", year),
+                "Yearly account activity and realized gains for tax purposes".to_string(),
+                member,
+                start_of_month(year as i32, 1),
+                start_of_month(year as i32 + 1, 1),
+            ),
+            year,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+enum TransferStatus {
+    Pending,
+    Cleared,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TransferMoney {
+    id: u32,
+    amount: f64,
+    from_account_id: u32,
+    to_account_id: u32,
+    created_on: chrono::DateTime<Utc>,
+    status: TransferStatus,
+    // When the bank reported the money as cleared
+    cleared_on: Option<chrono::DateTime<Utc>>,
+}
+
+impl TransferMoney {
+    fn new(amount: f64, from_account_id: u32, to_account_id: u32, created_on: chrono::DateTime<Utc>) -> Self {
+        TransferMoney {
+            id: NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed),
+            amount,
+            from_account_id,
+            to_account_id,
+            created_on,
+            status: TransferStatus::Pending,
+            cleared_on: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ElectronicWireTransfer {
+    transfer: TransferMoney,
+    bank_name: String,
+    wire_id: u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckTransfer {
+    transfer: TransferMoney,
+    bank_name: String,
+    check_number: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Transfer {
+    Wire(ElectronicWireTransfer),
+    Check(CheckTransfer),
+}
+
+impl Transfer {
+    fn get_transfer(&self) -> &TransferMoney {
+        match self {
+            Transfer::Wire(wire) => &wire.transfer,
+            Transfer::Check(check) => &check.transfer,
+        }
+    }
+
+    fn get_transfer_mut(&mut self) -> &mut TransferMoney {
+        match self {
+            Transfer::Wire(wire) => &mut wire.transfer,
+            Transfer::Check(check) => &mut check.transfer,
+        }
+    }
+
+    // None unless the transfer cleared
+    fn get_cleared_on(&self) -> Option<chrono::DateTime<Utc>> {
+        self.get_transfer().cleared_on
+    }
+
+    fn get_description(&self) -> String {
+        match self {
+            Transfer::Wire(wire) => format!("Wire {} ({})", wire.wire_id, wire.bank_name),
+            Transfer::Check(check) => format!("Check {} ({})", check.check_number, check.bank_name),
+        }
+    }
+
+    // Positive when money comes into the given account, negative when it leaves it
+    fn get_signed_amount(&self, account_id: u32) -> f64 {
+        let transfer = self.get_transfer();
+        if transfer.to_account_id == account_id {
+            transfer.amount
+        } else if transfer.from_account_id == account_id {
+            -transfer.amount
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+enum NotificationChannel {
+    Sms,
+    Email,
+    Push,
+}
+
+static NEXT_NOTIFICATION_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Notification {
     id: u32,
     created_on: chrono::DateTime<Utc>,
     message: String,
 }
 
 impl Notification {
-    fn send(&self) -> bool {
-        false
+    fn new(message: String) -> Self {
+        Notification {
+            id: NEXT_NOTIFICATION_ID.fetch_add(1, Ordering::Relaxed),
+            created_on: Utc::now(),
+            message,
+        }
     }
 }
 
+trait Notifier {
+    fn get_notification(&self) -> &Notification;
+    fn get_channel(&self) -> NotificationChannel;
+    // Phone number or email address, depending on the channel
+    fn get_recipient(&self) -> &str;
+
+    fn send(&self, sink: &mut dyn NotificationSink) -> bool {
+        sink.deliver(&SentNotification {
+            channel: self.get_channel(),
+            recipient: self.get_recipient().to_string(),
+            notification: self.get_notification().clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 struct SMSNotification {
-    _notification: Notification,
+    notification: Notification,
     phone_number: String,
 }
 
+#[derive(Debug, Clone)]
 struct EmailNotification {
-    _notification: Notification,
+    notification: Notification,
     email_address: String,
 }
 
+#[derive(Debug, Clone)]
 struct PushNotification {
-    _notification: Notification,
+    notification: Notification,
     phone_number: String,
 }
 
+impl Notifier for SMSNotification {
+    fn get_notification(&self) -> &Notification {
+        &self.notification
+    }
+
+    fn get_channel(&self) -> NotificationChannel {
+        NotificationChannel::Sms
+    }
+
+    fn get_recipient(&self) -> &str {
+        &self.phone_number
+    }
+}
+
+impl Notifier for EmailNotification {
+    fn get_notification(&self) -> &Notification {
+        &self.notification
+    }
+
+    fn get_channel(&self) -> NotificationChannel {
+        NotificationChannel::Email
+    }
+
+    fn get_recipient(&self) -> &str {
+        &self.email_address
+    }
+}
+
+impl Notifier for PushNotification {
+    fn get_notification(&self) -> &Notification {
+        &self.notification
+    }
+
+    fn get_channel(&self) -> NotificationChannel {
+        NotificationChannel::Push
+    }
+
+    fn get_recipient(&self) -> &str {
+        &self.phone_number
+    }
+}
+
+// What a sink is handed for delivery
+#[derive(Debug, Clone, PartialEq)]
+struct SentNotification {
+    channel: NotificationChannel,
+    recipient: String,
+    notification: Notification,
+}
+
+impl PartialEq for Notification {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+trait NotificationSink: fmt::Debug {
+    fn deliver(&mut self, sent: &SentNotification) -> bool;
+}
+
+// Hands notifications to another part of the process over a channel
+#[derive(Debug)]
+struct ChannelSink {
+    sender: mpsc::Sender<SentNotification>,
+}
+
+impl ChannelSink {
+    fn new() -> (Self, mpsc::Receiver<SentNotification>) {
+        let (sender, receiver) = mpsc::channel();
+        (ChannelSink { sender }, receiver)
+    }
+}
+
+impl NotificationSink for ChannelSink {
+    fn deliver(&mut self, sent: &SentNotification) -> bool {
+        self.sender.send(sent.clone()).is_ok()
+    }
+}
+
+// Keeps everything it is given; can be told to reject a channel
+#[derive(Debug, Default)]
+struct MockSink {
+    sent: Vec<SentNotification>,
+    failing_channels: Vec<NotificationChannel>,
+}
+
+impl NotificationSink for MockSink {
+    fn deliver(&mut self, sent: &SentNotification) -> bool {
+        if self.failing_channels.contains(&sent.channel) {
+            return false;
+        }
+        self.sent.push(sent.clone());
+        true
+    }
+}
+
+// Appends one tab separated line per notification
+#[derive(Debug)]
+struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    fn new(path: PathBuf) -> Self {
+        FileSink { path }
+    }
+}
+
+impl NotificationSink for FileSink {
+    fn deliver(&mut self, sent: &SentNotification) -> bool {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                writeln!(
+                    file, "{}\t{:?}\t{}\t{}",
+                    sent.notification.created_on.to_rfc3339(), sent.channel, sent.recipient, sent.notification.message,
+                )
+            })
+            .is_ok()
+    }
+}
+
 fn main() {
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_order(symbol: &str, is_buy_order: bool, quantity: i32, price: f64) -> LimitOrder {
+        enforced_order(symbol, is_buy_order, quantity, price, TimeEnforcementType::GoodTillCancelled)
+    }
+
+    fn enforced_order(
+        symbol: &str, is_buy_order: bool, quantity: i32, price: f64,
+        time_enforcement: TimeEnforcementType,
+    ) -> LimitOrder {
+        let mut order = LimitOrder::new(next_order_id(), symbol.to_string(), quantity, price, time_enforcement);
+        order.order.is_buy_order = is_buy_order;
+        order
+    }
+
+    // Asks: 100 @ 10.0, 100 @ 10.5. Bids: 100 @ 9.5, 100 @ 9.0
+    fn seeded_exchange() -> StockExchange {
+        let mut exchange = StockExchange::new();
+        for (is_buy_order, price) in [(false, 10.0), (false, 10.5), (true, 9.5), (true, 9.0)] {
+            exchange.place_order(&mut limit_order("IBM", is_buy_order, 100, price));
+        }
+        exchange.take_execution_reports();
+        exchange
+    }
+
+    #[test]
+    fn test_orders_rest_when_not_crossing() {
+        let mut exchange = StockExchange::new();
+        let mut bid = limit_order("AAPL", true, 100, 99.0);
+        let mut ask = limit_order("AAPL", false, 100, 101.0);
+        assert_eq!(exchange.place_order(&mut bid), ReturnStatus::Success);
+        assert_eq!(exchange.place_order(&mut ask), ReturnStatus::Success);
+
+        assert_eq!(bid.order.status, OrderStatus::Open);
+        assert_eq!(ask.order.status, OrderStatus::Open);
+        let book = exchange.get_order_book("AAPL").unwrap();
+        assert_eq!(book.best_bid(), Some(99.0));
+        assert_eq!(book.best_ask(), Some(101.0));
+        assert_eq!(book.last_trade_price, None);
+    }
+
+    #[test]
+    fn test_crossing_order_fills_at_resting_price() {
+        let mut exchange = StockExchange::new();
+        let mut ask = limit_order("AAPL", false, 100, 100.0);
+        exchange.place_order(&mut ask);
+        let mut bid = limit_order("AAPL", true, 100, 105.0);
+        exchange.place_order(&mut bid);
+
+        assert_eq!(bid.order.status, OrderStatus::Filled);
+        assert_eq!(bid.order.parts.len(), 1);
+        assert_eq!(bid.order.parts[0].price, 100.0);
+        assert_eq!(bid.order.parts[0].quantity, 100);
+
+        let reports = exchange.take_execution_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].order_id, ask.get_order_id());
+        assert_eq!(reports[0].status, OrderStatus::Filled);
+        assert!(exchange.get_order_book("AAPL").unwrap().best_ask().is_none());
+    }
+
+    #[test]
+    fn test_price_time_priority() {
+        let mut exchange = StockExchange::new();
+        let mut first_at_101 = limit_order("MSFT", false, 50, 101.0);
+        let mut second_at_101 = limit_order("MSFT", false, 50, 101.0);
+        let mut at_100 = limit_order("MSFT", false, 50, 100.0);
+        exchange.place_order(&mut first_at_101);
+        exchange.place_order(&mut second_at_101);
+        exchange.place_order(&mut at_100);
+
+        let mut bid = limit_order("MSFT", true, 120, 101.0);
+        exchange.place_order(&mut bid);
+
+        let fills: Vec<(f64, i32)> = bid.order.parts.iter().map(|p| (p.price, p.quantity)).collect();
+        assert_eq!(fills, vec![(100.0, 50), (101.0, 50), (101.0, 20)]);
+        assert_eq!(bid.order.status, OrderStatus::Filled);
+
+        let reports = exchange.take_execution_reports();
+        let ids: Vec<u32> = reports.iter().map(|r| r.order_id).collect();
+        assert_eq!(ids, vec![at_100.get_order_id(), first_at_101.get_order_id(), second_at_101.get_order_id()]);
+        assert_eq!(reports[2].status, OrderStatus::PartiallyFilled);
+        assert_eq!(exchange.get_order_book("MSFT").unwrap().last_trade_price, Some(101.0));
+    }
+
+    #[test]
+    fn test_partial_fill_rests_remainder() {
+        let mut exchange = StockExchange::new();
+        let mut ask = limit_order("GOOG", false, 30, 10.0);
+        exchange.place_order(&mut ask);
+        let mut bid = limit_order("GOOG", true, 100, 10.0);
+        exchange.place_order(&mut bid);
+
+        assert_eq!(bid.order.status, OrderStatus::PartiallyFilled);
+        assert_eq!(bid.order.remaining_quantity(), 70);
+        let book = exchange.get_order_book("GOOG").unwrap();
+        assert_eq!(book.best_bid(), Some(10.0));
+        assert_eq!(book.bids.values().next().unwrap()[0].order.remaining_quantity(), 70);
+    }
+
+    #[test]
+    fn test_books_are_per_symbol() {
+        let mut exchange = StockExchange::new();
+        let mut ask = limit_order("AAPL", false, 10, 10.0);
+        exchange.place_order(&mut ask);
+        let mut bid = limit_order("MSFT", true, 10, 10.0);
+        exchange.place_order(&mut bid);
+
+        assert_eq!(bid.order.status, OrderStatus::Open);
+        assert!(exchange.take_execution_reports().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_resting_order() {
+        let mut exchange = StockExchange::new();
+        let mut bid = limit_order("AAPL", true, 10, 10.0);
+        exchange.place_order(&mut bid);
+
+        assert_eq!(exchange.cancel_order("AAPL", bid.get_order_id()), ReturnStatus::Success);
+        assert_eq!(exchange.cancel_order("AAPL", bid.get_order_id()), ReturnStatus::Fail);
+        assert!(exchange.get_order_book("AAPL").unwrap().best_bid().is_none());
+        let reports = exchange.take_execution_reports();
+        assert_eq!(reports[0].status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_member_receives_fills_for_resting_order() {
+        let mut exchange = StockExchange::new();
+        let mut member = Member::new(
+            1, "secret".to_string(), "Alice".to_string(), "".to_string(),
+            "alice@example.com".to_string(), "".to_string(),
+        );
+        member.available_funds_for_trading = 10_000.0;
+        member.place_buy_limit_order(&mut exchange, "AAPL", 10, 50.0, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(member.active_orders.len(), 1);
+
+        let mut ask = limit_order("AAPL", false, 10, 50.0);
+        exchange.place_order(&mut ask);
+        for report in exchange.take_execution_reports() {
+            if report.member_id == member.id {
+                member.callback_stock_exchange(report.order_id, report.parts, report.status);
+            }
+        }
+        assert!(member.active_orders.is_empty());
+    }
+
+    #[test]
+    fn test_good_till_cancelled_rests_in_book() {
+        let mut exchange = seeded_exchange();
+        let mut bid = enforced_order("IBM", true, 150, 10.0, TimeEnforcementType::GoodTillCancelled);
+        exchange.place_order(&mut bid);
+
+        assert_eq!(bid.order.status, OrderStatus::PartiallyFilled);
+        let book = exchange.get_order_book("IBM").unwrap();
+        assert_eq!(book.best_bid(), Some(10.0));
+        assert_eq!(book.best_ask(), Some(10.5));
+    }
+
+    #[test]
+    fn test_fill_or_kill_rejects_unless_fully_fillable() {
+        let mut exchange = seeded_exchange();
+        let mut too_big = enforced_order("IBM", true, 250, 10.5, TimeEnforcementType::FillOrKill);
+        assert_eq!(exchange.place_order(&mut too_big), ReturnStatus::Fail);
+        assert_eq!(too_big.order.status, OrderStatus::Cancelled);
+        assert!(too_big.order.parts.is_empty());
+        assert_eq!(exchange.get_order_book("IBM").unwrap().available_quantity(true, Some(10.5)), 200);
+
+        let mut fits = enforced_order("IBM", true, 150, 10.5, TimeEnforcementType::FillOrKill);
+        assert_eq!(exchange.place_order(&mut fits), ReturnStatus::Success);
+        assert_eq!(fits.order.status, OrderStatus::Filled);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().available_quantity(true, None), 50);
+    }
+
+    #[test]
+    fn test_immediate_or_cancel_cancels_remainder() {
+        let mut exchange = seeded_exchange();
+        let mut ioc = enforced_order("IBM", true, 150, 10.0, TimeEnforcementType::ImmediateOrCancel);
+        assert_eq!(exchange.place_order(&mut ioc), ReturnStatus::Success);
+
+        assert_eq!(ioc.order.status, OrderStatus::Cancelled);
+        assert_eq!(ioc.order.filled_quantity(), 100);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(9.5));
+    }
+
+    #[test]
+    fn test_on_the_open_waits_for_opening_auction() {
+        let mut exchange = seeded_exchange();
+        let mut moo = enforced_order("IBM", true, 150, 10.5, TimeEnforcementType::OnTheOpen);
+        assert_eq!(exchange.place_order(&mut moo), ReturnStatus::Success);
+        assert_eq!(moo.order.status, OrderStatus::Open);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().available_quantity(true, None), 200);
+
+        exchange.open_session();
+        let reports = exchange.take_execution_reports();
+        let auction_fills: Vec<&ExecutionReport> = reports.iter()
+            .filter(|report| report.order_id == moo.get_order_id())
+            .collect();
+        assert_eq!(auction_fills.last().unwrap().status, OrderStatus::Filled);
+        // everything trades at the single clearing price
+        assert!(reports.iter().flat_map(|r| &r.parts).all(|part| part.price == 10.5));
+        let book = exchange.get_order_book("IBM").unwrap();
+        assert_eq!(book.last_trade_price, Some(10.5));
+        assert_eq!(book.available_quantity(true, None), 50);
+
+        let mut late = enforced_order("IBM", true, 10, 10.5, TimeEnforcementType::OnTheOpen);
+        assert_eq!(exchange.place_order(&mut late), ReturnStatus::Fail);
+    }
+
+    #[test]
+    fn test_on_the_close_uncrosses_at_closing_auction() {
+        let mut exchange = seeded_exchange();
+        exchange.open_session();
+        let mut moc = enforced_order("IBM", false, 100, 9.0, TimeEnforcementType::OnTheClose);
+        exchange.place_order(&mut moc);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(9.5));
+
+        exchange.close_session();
+        let reports = exchange.take_execution_reports();
+        let fills: Vec<&OrderPart> = reports.iter()
+            .filter(|report| report.order_id == moc.get_order_id())
+            .flat_map(|report| &report.parts)
+            .collect();
+        assert_eq!(fills.len(), 1);
+        assert_eq!((fills[0].price, fills[0].quantity), (9.5, 100));
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(9.0));
+    }
+
+    #[test]
+    fn test_partially_filled_resting_order_keeps_its_place() {
+        let mut exchange = seeded_exchange();
+        let mut later = limit_order("IBM", true, 100, 9.5);
+        exchange.place_order(&mut later);
+        let mut moo = enforced_order("IBM", false, 40, 9.5, TimeEnforcementType::OnTheOpen);
+        exchange.place_order(&mut moo);
+        exchange.open_session();
+        exchange.take_execution_reports();
+
+        // the first bid at 9.5 has 60 left and is still ahead of the later one
+        let mut sell = limit_order("IBM", false, 60, 9.5);
+        exchange.place_order(&mut sell);
+        let reports = exchange.take_execution_reports();
+        assert!(fills_for(&reports, later.get_order_id()).is_empty());
+        assert_eq!(exchange.get_order_book("IBM").unwrap().available_quantity(false, Some(9.5)), 100);
+    }
+
+    #[test]
+    fn test_unexecuted_auction_orders_are_cancelled() {
+        let mut exchange = seeded_exchange();
+        let mut moo = enforced_order("IBM", true, 100, 9.9, TimeEnforcementType::OnTheOpen);
+        exchange.place_order(&mut moo);
+        exchange.open_session();
+
+        let reports = exchange.take_execution_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].order_id, moo.get_order_id());
+        assert_eq!(reports[0].status, OrderStatus::Cancelled);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(9.5));
+    }
+
+    fn stop_loss(is_buy_order: bool, quantity: i32, stop_price: f64) -> StopLossOrder {
+        let mut order = StopLossOrder::new(
+            next_order_id(), "IBM".to_string(), quantity, stop_price, TimeEnforcementType::GoodTillCancelled,
+        );
+        order.order.is_buy_order = is_buy_order;
+        order
+    }
+
+    fn fills_for(reports: &[ExecutionReport], order_id: u32) -> Vec<(f64, i32)> {
+        reports.iter()
+            .filter(|report| report.order_id == order_id)
+            .flat_map(|report| &report.parts)
+            .map(|part| (part.price, part.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_market_order_sweeps_the_book() {
+        let mut exchange = seeded_exchange();
+        let mut buy = MarketOrder::new(next_order_id(), "IBM".to_string(), 150, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(exchange.place_order(&mut buy), ReturnStatus::Success);
+
+        let fills: Vec<(f64, i32)> = buy.order.parts.iter().map(|p| (p.price, p.quantity)).collect();
+        assert_eq!(fills, vec![(10.0, 100), (10.5, 50)]);
+        assert_eq!(buy.order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn test_market_order_never_rests() {
+        let mut exchange = seeded_exchange();
+        let mut sell = MarketOrder::new(next_order_id(), "IBM".to_string(), 300, TimeEnforcementType::GoodTillCancelled);
+        sell.order.is_buy_order = false;
+        exchange.place_order(&mut sell);
+
+        assert_eq!(sell.order.filled_quantity(), 200);
+        assert_eq!(sell.order.status, OrderStatus::Cancelled);
+        let book = exchange.get_order_book("IBM").unwrap();
+        assert!(book.best_bid().is_none());
+        assert_eq!(book.best_ask(), Some(10.0));
+    }
+
+    #[test]
+    fn test_stop_loss_triggers_on_last_trade() {
+        let mut exchange = seeded_exchange();
+        let mut stop = stop_loss(false, 50, 9.5);
+        exchange.place_order(&mut stop);
+        assert_eq!(stop.order.status, OrderStatus::Open);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().stop_orders.len(), 1);
+
+        exchange.place_order(&mut limit_order("IBM", false, 100, 9.5));
+        let reports = exchange.take_execution_reports();
+        assert_eq!(fills_for(&reports, stop.get_order_id()), vec![(9.0, 50)]);
+        let book = exchange.get_order_book("IBM").unwrap();
+        assert!(book.stop_orders.is_empty());
+        assert_eq!(book.last_trade_price, Some(9.0));
+    }
+
+    #[test]
+    fn test_stop_limit_becomes_resting_limit_order() {
+        let mut exchange = seeded_exchange();
+        let mut stop = StopLimitOrder::new(
+            next_order_id(), "IBM".to_string(), 150, 10.0, 10.2, TimeEnforcementType::GoodTillCancelled,
+        );
+        exchange.place_order(&mut stop);
+
+        let mut trigger = MarketOrder::new(next_order_id(), "IBM".to_string(), 10, TimeEnforcementType::GoodTillCancelled);
+        exchange.place_order(&mut trigger);
+        let reports = exchange.take_execution_reports();
+        assert_eq!(fills_for(&reports, stop.get_order_id()), vec![(10.0, 90)]);
+        let last = reports.iter().rfind(|report| report.order_id == stop.get_order_id()).unwrap();
+        assert_eq!(last.status, OrderStatus::PartiallyFilled);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(10.2));
+    }
+
+    #[test]
+    fn test_stops_cascade() {
+        let mut exchange = seeded_exchange();
+        let mut first = stop_loss(false, 100, 9.5);
+        let mut second = stop_loss(false, 50, 9.0);
+        exchange.place_order(&mut first);
+        exchange.place_order(&mut second);
+
+        exchange.place_order(&mut limit_order("IBM", false, 50, 9.5));
+        let reports = exchange.take_execution_reports();
+        assert_eq!(fills_for(&reports, first.get_order_id()), vec![(9.5, 50), (9.0, 50)]);
+        assert_eq!(fills_for(&reports, second.get_order_id()), vec![(9.0, 50)]);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().available_quantity(false, None), 0);
+    }
+
+    #[test]
+    fn test_stop_already_reached_executes_immediately() {
+        let mut exchange = seeded_exchange();
+        exchange.place_order(&mut limit_order("IBM", true, 10, 10.0));
+        let mut stop = stop_loss(true, 20, 9.8);
+        exchange.place_order(&mut stop);
+
+        assert_eq!(stop.order.status, OrderStatus::Filled);
+        assert_eq!(stop.order.parts[0].price, 10.0);
+    }
+
+    #[test]
+    fn test_cancel_stop_order() {
+        let mut exchange = seeded_exchange();
+        let mut stop = stop_loss(false, 50, 9.5);
+        exchange.place_order(&mut stop);
+
+        assert_eq!(exchange.cancel_order("IBM", stop.get_order_id()), ReturnStatus::Success);
+        assert!(exchange.get_order_book("IBM").unwrap().stop_orders.is_empty());
+    }
+
+    fn member_with_funds(funds: f64) -> Member {
+        let mut member = Member::new(
+            7, "secret".to_string(), "Bob".to_string(), "".to_string(),
+            "bob@example.com".to_string(), "".to_string(),
+        );
+        member.available_funds_for_trading = funds;
+        member
+    }
+
+    fn deliver(exchange: &mut StockExchange, member: &mut Member) {
+        for report in exchange.take_execution_reports() {
+            if report.member_id == member.id {
+                member.callback_stock_exchange(report.order_id, report.parts, report.status);
+            }
+        }
+    }
+
+    // Buys 100 AAPL at 10.0 and then 100 at 12.0
+    fn member_with_two_lots(exchange: &mut StockExchange) -> Member {
+        let mut member = member_with_funds(10_000.0);
+        exchange.place_order(&mut limit_order("AAPL", false, 100, 10.0));
+        exchange.place_order(&mut limit_order("AAPL", false, 100, 12.0));
+        member.place_buy_limit_order(exchange, "AAPL", 100, 10.0, TimeEnforcementType::GoodTillCancelled);
+        member.place_buy_limit_order(exchange, "AAPL", 100, 12.0, TimeEnforcementType::GoodTillCancelled);
+        exchange.take_execution_reports();
+        member
+    }
+
+    #[test]
+    fn test_buys_create_lots() {
+        let mut exchange = StockExchange::new();
+        let member = member_with_two_lots(&mut exchange);
+
+        let position = &member.stock_positions["AAPL"];
+        assert_eq!(position.get_quantity(), 200);
+        assert_eq!(position.lots.len(), 2);
+        assert_eq!(position.lots[0].get_buying_price(), 10.0);
+        assert_eq!(position.lots[1].get_buying_price(), 12.0);
+        assert_eq!(position.get_average_cost(), 11.0);
+        assert_eq!(member.available_funds_for_trading, 10_000.0 - 2_200.0);
+        assert_eq!(member.fills.len(), 2);
+    }
+
+    #[test]
+    fn test_fifo_sell_realizes_gain() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_two_lots(&mut exchange);
+        exchange.place_order(&mut limit_order("AAPL", true, 150, 13.0));
+        member.place_sell_limit_order(&mut exchange, "AAPL", 150, 13.0, TimeEnforcementType::GoodTillCancelled);
+
+        // 100 from the 10.0 lot and 50 from the 12.0 lot
+        assert_eq!(member.get_realized_pnl(), 100.0 * 3.0 + 50.0 * 1.0);
+        let position = &member.stock_positions["AAPL"];
+        assert_eq!(position.get_quantity(), 50);
+        assert_eq!(position.lots[0].get_buying_price(), 12.0);
+        assert_eq!(member.available_funds_for_trading, 10_000.0 - 2_200.0 + 1_950.0);
+    }
+
+    #[test]
+    fn test_sell_from_specific_lot() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_two_lots(&mut exchange);
+        let newest = member.stock_positions["AAPL"].lots[1].lot_number.clone();
+        exchange.place_order(&mut limit_order("AAPL", true, 50, 11.0));
+        member.place_sell_limit_order_from_lot(
+            &mut exchange, "AAPL", &newest, 50, 11.0, TimeEnforcementType::GoodTillCancelled,
+        );
+
+        assert_eq!(member.get_realized_pnl(), -50.0);
+        assert_eq!(member.realized_gains[0].lot_number, newest);
+        let position = &member.stock_positions["AAPL"];
+        assert_eq!(position.lots[0].quantity, 100);
+        assert_eq!(position.lots[1].quantity, 50);
+    }
+
+    #[test]
+    fn test_lot_is_not_sold_twice() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_two_lots(&mut exchange);
+        let oldest = member.stock_positions["AAPL"].lots[0].lot_number.clone();
+        let place = |member: &mut Member, exchange: &mut StockExchange, quantity| {
+            member.place_sell_limit_order_from_lot(
+                exchange, "AAPL", &oldest, quantity, 20.0, TimeEnforcementType::GoodTillCancelled,
+            )
+        };
+        assert_eq!(place(&mut member, &mut exchange, 70), ReturnStatus::Success);
+        assert_eq!(place(&mut member, &mut exchange, 40), ReturnStatus::InsufficientQuantity);
+        assert_eq!(
+            member.notifications.last().unwrap().message,
+            format!("Cannot sell 40 AAPL from lot {}: only 30 available", oldest),
+        );
+        assert_eq!(place(&mut member, &mut exchange, 30), ReturnStatus::Success);
+
+        // a plain sell skips the claimed lot and is filled from the newer one
+        member.place_sell_limit_order(&mut exchange, "AAPL", 50, 20.0, TimeEnforcementType::GoodTillCancelled);
+        exchange.place_order(&mut limit_order("AAPL", true, 150, 20.0));
+        for report in exchange.take_execution_reports() {
+            if report.member_id == member.id {
+                member.callback_stock_exchange(report.order_id, report.parts, report.status);
+            }
+        }
+        let sold: Vec<(&str, i32)> = member.realized_gains.iter()
+            .map(|gain| (gain.lot_number.as_str(), gain.quantity))
+            .collect();
+        assert_eq!(sold, [(oldest.as_str(), 70), (oldest.as_str(), 30), ("AAPL-2", 50)]);
+        let position = &member.stock_positions["AAPL"];
+        assert_eq!((position.get_quantity(), position.reserved_quantity), (50, 0));
+
+        assert_eq!(
+            member.place_sell_limit_order_from_lot(
+                &mut exchange, "AAPL", "AAPL-9", 10, 20.0, TimeEnforcementType::GoodTillCancelled,
+            ),
+            ReturnStatus::NoStockPosition,
+        );
+        assert_eq!(member.notifications.last().unwrap().message, "Cannot sell 10 AAPL: no lot AAPL-9");
+    }
+
+    #[test]
+    fn test_unrealized_pnl_against_quote() {
+        let mut exchange = StockExchange::new();
+        let member = member_with_two_lots(&mut exchange);
+        let quotes = vec![Stock::new("AAPL".to_string(), 11.5), Stock::new("MSFT".to_string(), 1.0)];
+
+        assert_eq!(member.get_unrealized_pnl(&quotes), 200.0 * 11.5 - 2_200.0);
+    }
+
+    #[test]
+    fn test_open_buy_orders_reserve_funds() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_funds(1_000.0);
+        let status = member.place_buy_limit_order(&mut exchange, "AAPL", 60, 10.0, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(status, ReturnStatus::Success);
+        assert_eq!(member.available_funds_for_trading, 400.0);
+        assert_eq!(member.reserved_funds, 600.0);
+
+        let status = member.place_buy_limit_order(&mut exchange, "AAPL", 60, 10.0, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(status, ReturnStatus::InsufficientFunds);
+
+        let order_id = *member.active_orders.keys().next().unwrap();
+        exchange.cancel_order("AAPL", order_id);
+        deliver(&mut exchange, &mut member);
+        assert_eq!(member.available_funds_for_trading, 1_000.0);
+        assert_eq!(member.reserved_funds, 0.0);
+    }
+
+    #[test]
+    fn test_price_improvement_is_refunded() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_funds(1_000.0);
+        exchange.place_order(&mut limit_order("AAPL", false, 50, 9.0));
+        member.place_buy_limit_order(&mut exchange, "AAPL", 50, 10.0, TimeEnforcementType::GoodTillCancelled);
+
+        assert_eq!(member.available_funds_for_trading, 550.0);
+        assert_eq!(member.reserved_funds, 0.0);
+        assert_eq!(member.stock_positions["AAPL"].get_average_cost(), 9.0);
+    }
+
+    #[test]
+    fn test_open_sell_orders_reserve_quantity() {
+        let mut exchange = StockExchange::new();
+        let mut member = member_with_two_lots(&mut exchange);
+        assert_eq!(
+            member.place_sell_limit_order(&mut exchange, "MSFT", 1, 20.0, TimeEnforcementType::GoodTillCancelled),
+            ReturnStatus::NoStockPosition
+        );
+        assert_eq!(
+            member.place_sell_limit_order(&mut exchange, "AAPL", 150, 20.0, TimeEnforcementType::GoodTillCancelled),
+            ReturnStatus::Success
+        );
+        assert_eq!(
+            member.place_sell_limit_order(&mut exchange, "AAPL", 100, 20.0, TimeEnforcementType::GoodTillCancelled),
+            ReturnStatus::InsufficientQuantity
+        );
+        assert_eq!(member.stock_positions["AAPL"].get_available_quantity(), 50);
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, 12, 0, 0).unwrap()
+    }
+
+    fn fill(is_buy_order: bool, quantity: i32, price: f64, execution_date: chrono::DateTime<Utc>) -> Fill {
+        Fill {
+            order_id: next_order_id(),
+            symbol: "AAPL".to_string(),
+            is_buy_order,
+            price,
+            quantity,
+            execution_date,
+        }
+    }
+
+    // Deposits 5000 in January, buys 100 AAPL at 10 in February, sells 50 at 13 in April
+    // and withdraws 500 in May 2024; the transfers clear a few days after they are made
+    fn member_with_history() -> Member {
+        let mut member = member_with_funds(0.0);
+        let wire = TransferMoney::new(5_000.0, 0, member.id, date(2024, 1, 5));
+        let wire_id = wire.id;
+        member.deposit_money(Transfer::Wire(ElectronicWireTransfer {
+            transfer: wire,
+            bank_name: "First Bank, N.A.".to_string(),
+            wire_id: 42,
+        }));
+        member.clear_transfer(wire_id, date(2024, 1, 8));
+        member.fills.push(fill(true, 100, 10.0, date(2024, 2, 1)));
+        member.fills.push(fill(false, 50, 13.0, date(2024, 4, 10)));
+        member.realized_gains.push(RealizedGain {
+            symbol: "AAPL".to_string(),
+            lot_number: "AAPL-1".to_string(),
+            quantity: 50,
+            buying_price: 10.0,
+            selling_price: 13.0,
+            sold_on: date(2024, 4, 10),
+        });
+        let check = TransferMoney::new(500.0, member.id, 0, date(2024, 5, 20));
+        let check_id = check.id;
+        member.withdraw_money(Transfer::Check(CheckTransfer {
+            transfer: check,
+            bank_name: "First Bank".to_string(),
+            check_number: "0042".to_string(),
+        }));
+        member.clear_transfer(check_id, date(2024, 5, 22));
+        member
+    }
+
+    #[test]
+    fn test_quarterly_statement_balances() {
+        let member = member_with_history();
+        let q1 = QuarterlyStatement::new(&member, 1, 2024).statement;
+        assert_eq!(q1.opening_balance, 0.0);
+        assert_eq!(q1.closing_balance, 4_000.0);
+        assert_eq!(q1.trades.len(), 1);
+        assert_eq!(q1.transfers.len(), 1);
+        assert_eq!(q1.positions["AAPL"], 100);
+        assert_eq!(q1.get_realized_gain(), 0.0);
+
+        let q2 = QuarterlyStatement::new(&member, 2, 2024).statement;
+        assert_eq!(q2.opening_balance, 4_000.0);
+        assert_eq!(q2.closing_balance, 4_150.0);
+        assert_eq!(q2.positions["AAPL"], 50);
+        assert_eq!(q2.get_realized_gain(), 150.0);
+    }
+
+    #[test]
+    fn test_transfer_counts_in_the_period_it_cleared() {
+        let mut member = member_with_funds(0.0);
+        let wire = TransferMoney::new(1_000.0, 0, member.id, date(2024, 3, 29));
+        let wire_id = wire.id;
+        member.deposit_money(Transfer::Wire(ElectronicWireTransfer {
+            transfer: wire,
+            bank_name: "First Bank".to_string(),
+            wire_id: 43,
+        }));
+        member.clear_transfer(wire_id, date(2024, 4, 2));
+
+        let q1 = QuarterlyStatement::new(&member, 1, 2024).statement;
+        assert!(q1.transfers.is_empty());
+        assert_eq!(q1.closing_balance, 0.0);
+        let q2 = QuarterlyStatement::new(&member, 2, 2024).statement;
+        assert_eq!(q2.transfers.len(), 1);
+        assert_eq!(q2.closing_balance, 1_000.0);
+        assert!(q2.get_statement().contains("2024-04-02  Wire 43 (First Bank)"));
+    }
+
+    #[test]
+    fn test_annual_statement_text() {
+        let member = member_with_history();
+        let annual = AnnualStatement::new(&member, 2024).statement;
+        assert_eq!(annual.opening_balance, 0.0);
+        assert_eq!(annual.closing_balance, 4_150.0);
+
+        let text = annual.get_statement();
+        assert!(text.starts_with("Annual statement 2024"));
+        assert!(text.contains("Period: 2024-01-01 to 2024-12-31"));
+        assert!(text.contains("Check 0042 (First Bank)"));
+        assert!(text.contains("Total realized gain: 150.00"));
+        assert!(text.ends_with("Closing balance: 4150.00"));
+    }
+
+    #[test]
+    fn test_statement_csv() {
+        let member = member_with_history();
+        let csv = QuarterlyStatement::new(&member, 1, 2024).statement.get_csv();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows, vec![
+            "section,date,description,quantity,price,amount",
+            "balance,2024-01-01,Opening balance,,,0.00",
+            "transfer,2024-01-08,\"Wire 42 (First Bank, N.A.)\",,,5000.00",
+            "trade,2024-02-01,BUY AAPL,100,10.00,-1000.00",
+            "position,2024-03-31,AAPL,100,,",
+            "balance,2024-03-31,Closing balance,,,4000.00",
+        ]);
+    }
+
+    fn restart(exchange: &mut StockExchange) -> StockExchange {
+        StockExchange::with_database(exchange.take_database().unwrap())
+    }
+
+    #[test]
+    fn test_restart_rebuilds_book_from_log() {
+        let mut exchange = StockExchange::with_database(Box::new(InMemoryDatabase::new()));
+        for (is_buy_order, price) in [(false, 10.0), (false, 10.5), (true, 9.5), (true, 9.0)] {
+            exchange.place_order(&mut limit_order("IBM", is_buy_order, 100, price));
+        }
+        // leaves 40 of the first ask resting
+        exchange.place_order(&mut limit_order("IBM", true, 60, 10.0));
+        let mut cancelled = limit_order("IBM", true, 100, 9.8);
+        exchange.place_order(&mut cancelled);
+        exchange.cancel_order("IBM", cancelled.get_order_id());
+        let mut stop = stop_loss(false, 50, 8.0);
+        exchange.place_order(&mut stop);
+
+        let mut restarted = restart(&mut exchange);
+        assert!(restarted.take_execution_reports().is_empty());
+        let book = restarted.get_order_book("IBM").unwrap();
+        assert_eq!(book.best_bid(), Some(9.5));
+        assert_eq!(book.best_ask(), Some(10.0));
+        assert_eq!(book.available_quantity(true, Some(10.0)), 40);
+        assert_eq!(book.last_trade_price, Some(10.0));
+        assert_eq!(book.stop_orders.len(), 1);
+        assert_eq!(book.stop_orders[0].get_order_id(), stop.get_order_id());
+
+        // the remainder keeps its time priority at 10.0
+        let mut buy = limit_order("IBM", true, 40, 10.0);
+        restarted.place_order(&mut buy);
+        assert_eq!(buy.get_order().status, OrderStatus::Filled);
+        assert!(next_order_id() > stop.get_order_id());
+    }
+
+    #[test]
+    fn test_log_records_orders_and_fills() {
+        let mut exchange = StockExchange::with_database(Box::new(InMemoryDatabase::new()));
+        exchange.place_order(&mut limit_order("IBM", false, 100, 10.0));
+        exchange.place_order(&mut limit_order("IBM", true, 100, 10.0));
+        let records = exchange.take_database().unwrap().records();
+        let saved = records.iter().filter(|r| matches!(r, DbRecord::OrderSaved(_))).count();
+        let updated: Vec<&PendingOrder> = records.iter()
+            .filter_map(|r| match r { DbRecord::OrderUpdated(order) => Some(order), _ => None })
+            .collect();
+        let executions = records.iter().filter(|r| matches!(r, DbRecord::Execution(_))).count();
+        assert_eq!(saved, 2);
+        assert_eq!(updated.len(), 2);
+        assert_eq!(updated[1].order.status, OrderStatus::Filled);
+        // one report for the resting maker
+        assert_eq!(executions, 1);
+    }
+
+    #[test]
+    fn test_file_database_survives_restart() {
+        let path = std::env::temp_dir().join(format!("stock_exchange_{}.jsonl", next_order_id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut exchange = StockExchange::with_database(Box::new(FileDatabase::new(path.clone())));
+        let mut member = member_with_funds(10_000.0);
+        exchange.place_order(&mut limit_order("IBM", false, 100, 10.0));
+        member.place_buy_limit_order(&mut exchange, "IBM", 30, 10.0, TimeEnforcementType::GoodTillCancelled);
+        member.place_buy_limit_order(&mut exchange, "IBM", 50, 9.0, TimeEnforcementType::GoodTillCancelled);
+        drop(exchange);
+
+        let mut restarted = StockExchange::with_database(Box::new(FileDatabase::new(path.clone())));
+        let book = restarted.get_order_book("IBM").unwrap();
+        assert_eq!(book.best_bid(), Some(9.0));
+        assert_eq!(book.available_quantity(true, Some(10.0)), 70);
+
+        let reloaded = restarted.load_member(member.id).unwrap();
+        assert_eq!(reloaded.available_funds_for_trading, member.available_funds_for_trading);
+        assert_eq!(reloaded.reserved_funds, 450.0);
+        assert_eq!(reloaded.stock_positions["IBM"].get_quantity(), 30);
+        assert_eq!(reloaded.active_orders.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn trade_at(price: f64, quantity: i32, minute: u32, second: u32) -> Trade {
+        Trade {
+            symbol: "IBM".to_string(),
+            price,
+            quantity,
+            executed_on: Utc.with_ymd_and_hms(2024, 3, 1, 10, minute, second).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_subscribers_receive_trades_and_quotes() {
+        let mut exchange = seeded_exchange();
+        let feed = exchange.subscribe(&["IBM"], None);
+        let other = exchange.subscribe(&["AAPL"], None);
+        exchange.place_order(&mut limit_order("IBM", true, 40, 10.0));
+        exchange.place_order(&mut limit_order("IBM", true, 10, 9.0));
+
+        let data: Vec<MarketData> = feed.try_iter().collect();
+        assert_eq!(data.len(), 2);
+        let MarketData::Trade(trade) = &data[0] else {
+            panic!("expected a trade, got {:?}", data[0]);
+        };
+        assert_eq!((trade.price, trade.quantity), (10.0, 40));
+        // the bid at 9.0 is below the best bid so only the ask size changed
+        assert_eq!(data[1], MarketData::Quote(Quote {
+            symbol: "IBM".to_string(),
+            best_bid: Some((9.5, 100)),
+            best_ask: Some((10.0, 60)),
+        }));
+        assert!(other.try_iter().next().is_none());
+    }
+
+    #[test]
+    fn test_bars_aggregate_trades_per_interval() {
+        let mut bars = BarAggregator::new(chrono::Duration::minutes(1));
+        assert_eq!(bars.add(&trade_at(10.0, 100, 0, 5)), None);
+        assert_eq!(bars.add(&trade_at(10.5, 50, 0, 30)), None);
+        assert_eq!(bars.add(&trade_at(9.5, 20, 0, 59)), None);
+        let bar = bars.add(&trade_at(9.8, 10, 1, 0)).unwrap();
+        assert_eq!(bar.start, Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, 0).unwrap());
+        assert_eq!((bar.open, bar.high, bar.low, bar.close, bar.volume), (10.0, 10.5, 9.5, 9.5, 170));
+        let bar = bars.flush().unwrap();
+        assert_eq!((bar.open, bar.close, bar.volume), (9.8, 9.8, 10));
+        assert_eq!(bars.flush(), None);
+    }
+
+    #[test]
+    fn test_bars_are_flushed_at_close() {
+        let mut exchange = seeded_exchange();
+        let feed = exchange.subscribe(&[], Some(chrono::Duration::hours(1)));
+        exchange.place_order(&mut limit_order("IBM", true, 150, 10.5));
+        exchange.place_order(&mut limit_order("IBM", false, 30, 9.5));
+        exchange.close_session();
+
+        let bars: Vec<Bar> = feed.try_iter()
+            .filter_map(|data| match data { MarketData::Bar(bar) => Some(bar), _ => None })
+            .collect();
+        assert_eq!(bars.iter().map(|bar| bar.volume).sum::<i32>(), 180);
+        let last = bars.last().unwrap();
+        assert_eq!(last.close, 9.5);
+        assert!(bars.iter().all(|bar| bar.high >= bar.low));
+    }
+
+    #[test]
+    fn test_stream_subscription() {
+        use tokio_stream::StreamExt;
+
+        let mut exchange = seeded_exchange();
+        let mut stream = exchange.subscribe_stream(&["IBM"], None);
+        exchange.place_order(&mut limit_order("IBM", false, 100, 9.5));
+        let data = futures::executor::block_on(stream.next()).unwrap();
+        assert!(matches!(data, MarketData::Trade(Trade { price: 9.5, quantity: 100, .. })));
+    }
+
+    #[test]
+    fn test_watch_list_and_inventory_follow_last_trade() {
+        let mut exchange = seeded_exchange();
+        let mut watch_list = WatchList::new("Tech".to_string(), vec![Stock::new("IBM".to_string(), 0.0)]);
+        let feed = watch_list.subscribe(&mut exchange, None);
+        let mut inventory = StockInventory::new("NYSE".to_string(), Utc::now());
+        inventory.connect(&mut exchange);
+        assert!(inventory.search_symbol("IBM").is_none());
+
+        exchange.place_order(&mut limit_order("IBM", true, 150, 10.5));
+        for data in feed.try_iter() {
+            watch_list.update(&data);
+        }
+        assert_eq!(watch_list.stocks[0].get_price(), 10.5);
+        assert_eq!(inventory.search_symbol("IBM").unwrap().get_price(), 10.5);
+        assert!(inventory.search_symbol("AAPL").is_none());
+    }
+
+    #[test]
+    fn test_dropped_subscribers_are_removed() {
+        let mut exchange = seeded_exchange();
+        drop(exchange.subscribe(&[], None));
+        exchange.place_order(&mut limit_order("IBM", true, 10, 10.0));
+        assert!(exchange.get_instance().market_data.subscriptions.is_empty());
+    }
+
+    fn messages(member: &Member) -> Vec<String> {
+        member.notifications.iter().map(|notification| notification.message.clone()).collect()
+    }
+
+    #[test]
+    fn test_member_notified_on_fills_and_cancellations() {
+        let mut exchange = seeded_exchange();
+        let mut member = member_with_funds(10_000.0);
+        member.place_buy_limit_order(&mut exchange, "IBM", 150, 10.5, TimeEnforcementType::GoodTillCancelled);
+        member.place_buy_limit_order(&mut exchange, "IBM", 10, 9.0, TimeEnforcementType::ImmediateOrCancel);
+        member.place_buy_limit_order(&mut exchange, "IBM", 20, 9.0, TimeEnforcementType::GoodTillCancelled);
+        let order_id = *member.active_orders.keys().next().unwrap();
+        exchange.cancel_order("IBM", order_id);
+        deliver(&mut exchange, &mut member);
+
+        let messages = messages(&member);
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].starts_with("Bought 100 IBM at 10.00"));
+        assert!(messages[1].starts_with("Bought 50 IBM at 10.50"));
+        assert!(messages[2].ends_with("cancelled with 10 IBM unfilled"));
+        assert_eq!(messages[3], format!("Order {} cancelled with 20 IBM unfilled", order_id));
+    }
+
+    #[test]
+    fn test_member_notified_on_shortfalls_and_transfers() {
+        let member = member_with_history();
+        assert_eq!(messages(&member), vec![
+            "Deposit of 5000.00 by Wire 42 (First Bank, N.A.) completed",
+            "Withdrawal of 500.00 by Check 0042 (First Bank) completed",
+        ]);
+
+        let mut exchange = seeded_exchange();
+        let mut member = member_with_funds(500.0);
+        member.place_buy_limit_order(&mut exchange, "IBM", 100, 10.0, TimeEnforcementType::GoodTillCancelled);
+        member.place_sell_limit_order(&mut exchange, "IBM", 10, 10.0, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(messages(&member), vec![
+            "Cannot buy 100 IBM at 10.00: needs 1000.00, available funds 500.00",
+            "Cannot sell 10 IBM: no position",
+        ]);
+    }
+
+    #[test]
+    fn test_notifications_follow_channel_preferences() {
+        let mut member = member_with_history();
+        member.phone = "555-0100".to_string();
+        member.set_notification_channels(vec![NotificationChannel::Sms, NotificationChannel::Email]);
+        let mut sink = MockSink::default();
+        assert_eq!(member.dispatch_notifications(&mut sink), 4);
+        assert!(member.notifications.is_empty());
+        let recipients: Vec<(NotificationChannel, &str)> = sink.sent.iter()
+            .map(|sent| (sent.channel, sent.recipient.as_str()))
+            .collect();
+        assert_eq!(recipients[..2], [
+            (NotificationChannel::Sms, "555-0100"),
+            (NotificationChannel::Email, "bob@example.com"),
+        ]);
+
+        // undelivered notifications are kept for the next dispatch
+        member.notify("Statement available".to_string());
+        member.set_notification_channels(vec![NotificationChannel::Push]);
+        sink.failing_channels.push(NotificationChannel::Push);
+        assert_eq!(member.dispatch_notifications(&mut sink), 0);
+        assert_eq!(member.notifications.len(), 1);
+
+        let (mut channel_sink, receiver) = ChannelSink::new();
+        assert_eq!(member.dispatch_notifications(&mut channel_sink), 1);
+        assert_eq!(receiver.try_recv().unwrap().channel, NotificationChannel::Push);
+    }
+
+    #[test]
+    fn test_file_sink_appends_lines() {
+        let path = std::env::temp_dir().join(format!("notifications_{}.log", next_order_id()));
+        let _ = std::fs::remove_file(&path);
+        let mut member = member_with_history();
+        member.dispatch_notifications(&mut FileSink::new(path.clone()));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("\tEmail\tbob@example.com\tDeposit of 5000.00 by Wire 42 (First Bank, N.A.) completed"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn wire_deposit(member: &Member, amount: f64) -> Transfer {
+        Transfer::Wire(ElectronicWireTransfer {
+            transfer: TransferMoney::new(amount, 0, member.id, Utc::now()),
+            bank_name: "First Bank".to_string(),
+            wire_id: 7,
+        })
+    }
+
+    fn check_withdrawal(member: &Member, amount: f64) -> Transfer {
+        Transfer::Check(CheckTransfer {
+            transfer: TransferMoney::new(amount, member.id, 0, Utc::now()),
+            bank_name: "First Bank".to_string(),
+            check_number: "0007".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_deposit_is_available_once_cleared() {
+        let mut member = member_with_funds(0.0);
+        let deposit = wire_deposit(&member, 2_000.0);
+        let transfer_id = deposit.get_transfer().id;
+        assert_eq!(member.deposit_money(deposit), ReturnStatus::Success);
+        assert_eq!(member.available_funds_for_trading, 0.0);
+        assert_eq!(member.transfers[0].get_transfer().status, TransferStatus::Pending);
+
+        assert!(member.clear_transfer(transfer_id, Utc::now()));
+        assert_eq!(member.available_funds_for_trading, 2_000.0);
+        // a transfer settles only once
+        assert!(!member.fail_transfer(transfer_id));
+        assert!(!member.clear_transfer(transfer_id, Utc::now()));
+        assert_eq!(member.available_funds_for_trading, 2_000.0);
+        assert_eq!(messages(&member), vec!["Deposit of 2000.00 by Wire 7 (First Bank) completed"]);
+    }
+
+    #[test]
+    fn test_failed_deposit_is_never_credited() {
+        let mut member = member_with_funds(0.0);
+        let deposit = wire_deposit(&member, 2_000.0);
+        let transfer_id = deposit.get_transfer().id;
+        member.deposit_money(deposit);
+        assert!(member.fail_transfer(transfer_id));
+        assert_eq!(member.available_funds_for_trading, 0.0);
+        assert_eq!(member.transfers[0].get_transfer().status, TransferStatus::Failed);
+        assert!(AnnualStatement::new(&member, Utc::now().year() as u32).statement.transfers.is_empty());
+    }
+
+    #[test]
+    fn test_withdrawal_is_held_until_settled() {
+        let mut member = member_with_funds(1_000.0);
+        let cleared = check_withdrawal(&member, 300.0);
+        let failed = check_withdrawal(&member, 400.0);
+        let (cleared_id, failed_id) = (cleared.get_transfer().id, failed.get_transfer().id);
+        assert_eq!(member.withdraw_money(cleared), ReturnStatus::Success);
+        assert_eq!(member.withdraw_money(failed), ReturnStatus::Success);
+        assert_eq!(member.available_funds_for_trading, 300.0);
+        assert_eq!(member.withdraw_money(check_withdrawal(&member, 301.0)), ReturnStatus::InsufficientFunds);
+
+        member.clear_transfer(cleared_id, Utc::now());
+        member.fail_transfer(failed_id);
+        assert_eq!(member.available_funds_for_trading, 700.0);
+        assert_eq!(messages(&member)[1..], [
+            "Withdrawal of 300.00 by Check 0007 (First Bank) completed",
+            "Withdrawal of 400.00 by Check 0007 (First Bank) failed",
+        ]);
+    }
+
+    #[test]
+    fn test_transfer_limit_and_direction_are_enforced() {
+        let mut member = member_with_funds(500_000.0);
+        let limit = Constants::new().money_transfer_limit as f64;
+        assert_eq!(member.deposit_money(wire_deposit(&member, limit)), ReturnStatus::Success);
+        assert_eq!(member.deposit_money(wire_deposit(&member, limit + 0.01)), ReturnStatus::MoneyTransferLimitExceeded);
+        assert_eq!(member.withdraw_money(check_withdrawal(&member, limit + 1.0)), ReturnStatus::MoneyTransferLimitExceeded);
+        assert_eq!(member.available_funds_for_trading, 500_000.0);
+        assert_eq!(member.deposit_money(wire_deposit(&member, -5.0)), ReturnStatus::Fail);
+        assert_eq!(member.deposit_money(check_withdrawal(&member, 5.0)), ReturnStatus::Fail);
+        assert_eq!(member.withdraw_money(wire_deposit(&member, 5.0)), ReturnStatus::Fail);
+        assert_eq!(member.transfers.len(), 1);
+    }
+
+    fn admin() -> Admin {
+        Admin::new(
+            1, "root".to_string(), "Alice".to_string(), "".to_string(),
+            "alice@example.com".to_string(), "".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_blocked_member_cannot_trade_and_loses_open_orders() {
+        let mut exchange = seeded_exchange();
+        let mut member = member_with_funds(10_000.0);
+        member.place_buy_limit_order(&mut exchange, "IBM", 100, 9.75, TimeEnforcementType::GoodTillCancelled);
+        assert_eq!(member.reserved_funds, 975.0);
+
+        assert!(admin().block_member(&mut exchange, &mut member));
+        assert_eq!(member.status, AccountStatus::Blocked);
+        assert!(member.active_orders.is_empty());
+        assert_eq!(member.reserved_funds, 0.0);
+        assert_eq!(member.available_funds_for_trading, 10_000.0);
+        assert_eq!(exchange.get_order_book("IBM").unwrap().best_bid(), Some(9.5));
+        assert_eq!(
+            member.place_buy_limit_order(&mut exchange, "IBM", 1, 10.0, TimeEnforcementType::GoodTillCancelled),
+            ReturnStatus::AccountNotActive,
+        );
+        assert!(!admin().block_member(&mut exchange, &mut member));
+
+        assert!(admin().unblock_member(&mut exchange, &mut member));
+        assert_eq!(
+            member.place_buy_limit_order(&mut exchange, "IBM", 1, 10.0, TimeEnforcementType::GoodTillCancelled),
+            ReturnStatus::Success,
+        );
+    }
+
+    #[test]
+    fn test_closed_and_blacklisted_members_are_rejected() {
+        let mut exchange = seeded_exchange();
+        for status in [AccountStatus::Closed, AccountStatus::Blacklisted] {
+            let mut member = member_with_two_lots(&mut exchange);
+            member.place_sell_limit_order(&mut exchange, "AAPL", 50, 20.0, TimeEnforcementType::GoodTillCancelled);
+            assert!(admin().set_member_status(&mut exchange, &mut member, status));
+            assert!(member.active_orders.is_empty());
+            assert_eq!(member.stock_positions["AAPL"].reserved_quantity, 0);
+            assert_eq!(
+                member.place_sell_limit_order(&mut exchange, "AAPL", 50, 20.0, TimeEnforcementType::GoodTillCancelled),
+                ReturnStatus::AccountNotActive,
+            );
+            // only blocked members can be unblocked
+            assert!(!admin().unblock_member(&mut exchange, &mut member));
+        }
+    }
+
+    #[test]
+    fn test_passwords_are_hashed() {
+        let member = member_with_funds(0.0);
+        assert!(member.check_password("secret"));
+        assert!(!member.check_password("Secret"));
+        assert!(!member.credentials.password_hash.contains("secret"));
+        assert_eq!(member.credentials.password_hash.len(), 64);
+        // salted per account
+        assert_ne!(member_with_funds(0.0).credentials.password_hash, member.credentials.password_hash);
+    }
+
+    #[test]
+    fn test_password_reset_token_flow() {
+        let mut member = member_with_funds(0.0);
+        assert!(!member.reset_password("anything", "new password"));
+
+        let stale = member.request_password_reset();
+        let token = member.request_password_reset();
+        assert!(!member.reset_password(&stale, "new password"));
+        assert!(member.reset_password(&token, "new password"));
+        assert!(member.check_password("new password"));
+        assert!(!member.check_password("secret"));
+        // tokens are single use
+        assert!(!member.reset_password(&token, "other"));
+
+        let mut admin = admin();
+        let token = admin.request_password_reset();
+        admin.credentials.reset_token_expires_on = Some(Utc::now() - chrono::Duration::minutes(1));
+        assert!(!admin.reset_password(&token, "other"));
+        assert!(admin.check_password("root"));
+    }
+}